@@ -1,27 +1,52 @@
-use std::time::Duration;
+use std::{convert::Infallible, time::Duration};
 
 use axum::{
     Json,
-    extract::{Query, State},
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::Stream;
 use serde::{Deserialize, Serialize};
-use tokio::time::timeout;
+use tokio::{sync::broadcast::error::RecvError, time::timeout};
 use tracing::info;
 use utoipa::ToSchema;
 use utoipa_axum::{router::OpenApiRouter, routes};
 
-use crate::{PlugCommand, PlugId, PlugTask, PowerState, SharedState};
+use crate::{
+    Availability, AvailabilityEvent, ChannelTimer, CrashEntry, DesiredState, FirmwareImage, JobId,
+    JobState, LogEntry, LogLevel, OtaProgress, PlugCommand, PlugConfig, PlugConfigPatch,
+    PlugHealth, PlugId, PlugTask, PowerOnBehavior, PowerState, Schedule, SharedState, cli::ARGS,
+};
+
+/// Largest firmware image accepted, the size of an OTA slot
+const MAX_FIRMWARE_SIZE: usize = 0x1e0000;
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
 pub struct QueryStatusParams {
     id: PlugId,
+    // Relay channel reported in `state`, 0 by default
+    #[serde(default)]
+    channel: u8,
 }
 
 #[derive(Debug, Clone, Serialize, utoipa::ToResponse, utoipa::ToSchema)]
 pub struct QueryStatusResponse {
+    /// State of the requested channel
     state: Option<PowerState>,
+    /// State of every channel
+    channels: Vec<PowerState>,
+    /// Timed command running on the requested channel
+    timer: Option<ChannelTimer>,
+    power_on: Option<PowerOnBehavior>,
+    ota: Option<OtaProgress>,
     lastseen: Option<chrono::DateTime<Utc>>,
+    availability: Option<Availability>,
 }
 
 #[utoipa::path(
@@ -37,49 +62,47 @@ pub async fn query_status(
     Query(params): Query<QueryStatusParams>,
 ) -> Json<QueryStatusResponse> {
     if let Some(plug) = s.plugs.get(&params.id) {
-        if plug.power_state == PowerState::Unknown {
-            let (task, rx) = PlugTask::new(PlugCommand::QueryState);
-            let _ = timeout(Duration::from_secs(10), async {
-                let sent = plug.task_tx.send(task).await.is_ok();
-                // avoids deadlocking
-                drop(plug);
-                if !sent {
-                    tracing::warn!("Sending task to plug failed");
-                    false
-                } else {
-                    match rx.await {
-                        Ok(b) => b,
-                        Err(_) => {
-                            tracing::warn!("task dropped by plug");
-                            false
-                        }
-                    }
-                }
-            })
-            .await
-            .is_ok_and(|i| i);
+        if plug.power_state(params.channel) == PowerState::Unknown && plug.online() {
+            // avoids deadlocking
+            drop(plug);
+            run_command(&s, &params.id, PlugCommand::QueryState).await;
         }
         match s.plugs.get(&params.id) {
             Some(status) => Json(QueryStatusResponse {
-                state: Some(status.power_state),
+                state: Some(status.power_state(params.channel)),
+                channels: status.channels.clone(),
+                timer: status.timer(params.channel),
+                power_on: status.power_on,
+                ota: status.ota,
                 lastseen: Some(status.last_seen),
+                availability: Some(status.availability().0),
             }),
             None => Json(QueryStatusResponse {
                 state: None,
+                channels: Vec::new(),
+                timer: None,
+                power_on: None,
+                ota: None,
                 lastseen: None,
+                availability: None,
             }),
         }
     } else {
         Json(QueryStatusResponse {
             state: None,
+            channels: Vec::new(),
+            timer: None,
+            power_on: None,
+            ota: None,
             lastseen: None,
+            availability: None,
         })
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
-enum PowerStateOption {
+pub enum PowerStateOption {
     On,
     Off,
 }
@@ -90,12 +113,19 @@ pub struct StateQuery {
     id: PlugId,
     // Desired plug power state
     state: PowerStateOption,
+    // Relay channel, 0 by default
+    #[serde(default)]
+    channel: u8,
+    // Seconds until the plug switches the channel back
+    duration: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, utoipa::ToResponse, utoipa::ToSchema)]
 pub struct SetStateResponse {
     present: bool,
     success: bool,
+    /// Not applied yet, the broker sends it again when the plug reconnects
+    queued: bool,
 }
 
 #[utoipa::path(
@@ -106,48 +136,1073 @@ pub struct SetStateResponse {
     ),
     responses(
         (status = 200, description = "Success", body = SetStateResponse),
+        (status = 404, description = "Plug never connected"),
     )
 )]
 pub async fn set_state(
     State(s): State<SharedState>,
     Query(query): Query<StateQuery>,
+) -> Result<Json<SetStateResponse>, StatusCode> {
+    info!(
+        "Turning {} channel {} {:?} for {:?}s",
+        *query.id, query.channel, &query.state, query.duration
+    );
+    if s.plugs
+        .get(&query.id)
+        .is_some_and(|plug| !plug.has_channel(query.channel))
+    {
+        return Ok(Json(SetStateResponse::from(Some(false))));
+    }
+    let channel = query.channel;
+    let command = match (query.state, query.duration) {
+        (PowerStateOption::On, None) => PlugCommand::TurnOn { channel },
+        (PowerStateOption::Off, None) => PlugCommand::TurnOff { channel },
+        (PowerStateOption::On, Some(seconds)) => PlugCommand::TurnOnFor { channel, seconds },
+        (PowerStateOption::Off, Some(seconds)) => PlugCommand::TurnOffFor { channel, seconds },
+    };
+    // timed commands make no sense once the plug is back, they aren't kept
+    let Some((_, state)) = command.switch().filter(|_| query.duration.is_none()) else {
+        return Ok(Json(SetStateResponse::from(
+            run_command(&s, &query.id, command).await,
+        )));
+    };
+    if !s.desire(query.id, channel, state) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !s.plugs.get(&query.id).is_some_and(|plug| plug.online()) {
+        info!(
+            "{} is offline, channel {channel} switches once it's back",
+            *query.id
+        );
+        return Ok(Json(SetStateResponse {
+            present: false,
+            success: false,
+            queued: true,
+        }));
+    }
+    let mut response = SetStateResponse::from(run_command(&s, &query.id, command).await);
+    response.queued = !response.success;
+    Ok(Json(response))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ShadowResponse {
+    /// Whether the plug is connected
+    online: bool,
+    /// Last state of each channel reported by the plug
+    reported: Vec<PowerState>,
+    /// States requested through `/api/setstate`
+    desired: Vec<DesiredState>,
+    /// Whether the plug confirmed every desired state
+    in_sync: bool,
+}
+
+/// Requested and reported state of a plug, requests made while it was
+/// offline are applied when it reconnects
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/shadow",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, body = ShadowResponse),
+    )
+)]
+pub async fn plug_shadow(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Json<ShadowResponse> {
+    let (online, reported) = s
+        .plugs
+        .get(&id)
+        .map(|plug| (plug.online(), plug.channels.clone()))
+        .unwrap_or_default();
+    let desired = s.desired.get(&id).map(|d| d.clone()).unwrap_or_default();
+    Json(ShadowResponse {
+        online,
+        reported,
+        in_sync: desired.iter().all(|d| d.synced.is_some()),
+        desired,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct PulseQuery {
+    // Plug ID
+    id: PlugId,
+    // Relay channel, 0 by default
+    #[serde(default)]
+    channel: u8,
+    // How long the channel stays on
+    millis: u32,
+}
+
+/// Turns a channel on for a moment, timed by the plug
+#[utoipa::path(
+    post,
+    path = "/api/pulse",
+    params(
+        PulseQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+    )
+)]
+pub async fn pulse(
+    State(s): State<SharedState>,
+    Query(query): Query<PulseQuery>,
 ) -> Json<SetStateResponse> {
-    info!("Turning {} {:?}", *query.id, &query.state);
-    if let Some(plug) = s.plugs.get(&query.id) {
-        let (task, rx) = PlugTask::new(match query.state {
-            PowerStateOption::On => PlugCommand::TurnOn,
-            PowerStateOption::Off => PlugCommand::TurnOff,
-        });
-        let success = timeout(Duration::from_secs(10), async {
-            let sent = plug.task_tx.send(task).await.is_ok();
-            // avoids deadlocking
-            drop(plug);
-            if !sent {
+    info!(
+        "Pulsing {} channel {} for {}ms",
+        *query.id, query.channel, query.millis
+    );
+    if s.plugs
+        .get(&query.id)
+        .is_some_and(|plug| !plug.has_channel(query.channel))
+    {
+        return Json(SetStateResponse::from(Some(false)));
+    }
+    let command = PlugCommand::Pulse {
+        channel: query.channel,
+        millis: query.millis,
+    };
+    Json(SetStateResponse::from(
+        run_command(&s, &query.id, command).await,
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct PowerOnQuery {
+    // Plug ID
+    id: PlugId,
+    // Relay state to apply after a power cut
+    behavior: PowerOnBehavior,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/poweron",
+    params(
+        PowerOnQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+    )
+)]
+pub async fn set_power_on(
+    State(s): State<SharedState>,
+    Query(query): Query<PowerOnQuery>,
+) -> Json<SetStateResponse> {
+    info!(
+        "Setting power-on behavior of {} to {:?}",
+        *query.id, &query.behavior
+    );
+    Json(SetStateResponse::from(
+        run_command(&s, &query.id, PlugCommand::SetPowerOn(query.behavior)).await,
+    ))
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToResponse, utoipa::ToSchema)]
+pub struct FirmwareUploadResponse {
+    /// Used to refer to the image in `/api/ota`
+    image_id: u32,
+    size: usize,
+    /// Hex encoded SHA-256 of the image
+    sha256: String,
+    uploaded: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct FirmwareUploadQuery {
+    /// Hex encoded DER ECDSA P-256 signature over the image's SHA-256, as
+    /// written by `openssl dgst -sha256 -sign`
+    signature: String,
+}
+
+/// Stores a firmware image for `/api/ota`
+///
+/// Needs `Authorization: Bearer <admin token>`. Plugs only install images
+/// signed with the key their firmware was built with, the broker doesn't
+/// check the signature itself.
+#[utoipa::path(
+    post,
+    path = "/api/firmware",
+    params(FirmwareUploadQuery),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Image stored", body = FirmwareUploadResponse),
+        (status = 400, description = "Empty image or malformed signature"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 403, description = "Admin routes are disabled"),
+    )
+)]
+pub async fn upload_firmware(
+    State(s): State<SharedState>,
+    Query(query): Query<FirmwareUploadQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<FirmwareUploadResponse>, StatusCode> {
+    check_admin(&headers)?;
+    let signature = decode_hex(&query.signature).ok_or(StatusCode::BAD_REQUEST)?;
+    if body.is_empty() || signature.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let image = FirmwareImage::new(body, signature);
+    let image_id = loop {
+        let id = rand::random::<u32>();
+        if !s.firmware.contains_key(&id) {
+            break id;
+        }
+    };
+    let response = FirmwareUploadResponse {
+        image_id,
+        size: image.data.len(),
+        sha256: image.sha256.iter().map(|b| format!("{b:02x}")).collect(),
+        uploaded: image.uploaded,
+    };
+    info!(
+        "Stored firmware image {image_id} ({} bytes, sha256 {})",
+        response.size, response.sha256
+    );
+    s.firmware.insert(image_id, image);
+    Ok(Json(response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/firmware/{image_id}",
+    params(
+        ("image_id" = u32, Path, description = "Firmware image ID")
+    ),
+    responses(
+        (status = 200, description = "Image contents", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 404, description = "No such image"),
+    )
+)]
+pub async fn download_firmware(
+    State(s): State<SharedState>,
+    Path(image_id): Path<u32>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let image = s.firmware.get(&image_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        image.data.clone(),
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct OtaQuery {
+    // Plug ID
+    id: PlugId,
+    // Firmware image ID returned by /api/firmware
+    image: u32,
+}
+
+/// Starts a firmware update, succeeds once the plug accepts the image
+///
+/// Progress is reported in the `ota` field of `/api/query`. Needs
+/// `Authorization: Bearer <admin token>`.
+#[utoipa::path(
+    post,
+    path = "/api/ota",
+    params(
+        OtaQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 403, description = "Admin routes are disabled"),
+        (status = 404, description = "No such image"),
+    )
+)]
+pub async fn start_ota(
+    State(s): State<SharedState>,
+    Query(query): Query<OtaQuery>,
+    headers: HeaderMap,
+) -> Result<Json<SetStateResponse>, StatusCode> {
+    check_admin(&headers)?;
+    if !s.firmware.contains_key(&query.image) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!("Updating {} to image {}", *query.id, query.image);
+    Ok(Json(SetStateResponse::from(
+        run_command(
+            &s,
+            &query.id,
+            PlugCommand::Ota {
+                image_id: query.image,
+            },
+        )
+        .await,
+    )))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct ScheduleQuery {
+    // Plug ID
+    id: PlugId,
+}
+
+/// Replaces the schedule stored on the plug
+#[utoipa::path(
+    put,
+    path = "/api/schedule",
+    params(
+        ScheduleQuery
+    ),
+    request_body = Schedule,
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+        (status = 400, description = "Too many entries or unknown channel"),
+    )
+)]
+pub async fn set_schedule(
+    State(s): State<SharedState>,
+    Query(query): Query<ScheduleQuery>,
+    Json(schedule): Json<Schedule>,
+) -> Result<Json<SetStateResponse>, StatusCode> {
+    let schedule_msg = schedule.to_common().ok_or(StatusCode::BAD_REQUEST)?;
+    if let Some(plug) = s.plugs.get(&query.id)
+        && !schedule.channels().all(|c| plug.has_channel(c))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    info!(
+        "Setting schedule of {} ({} entries)",
+        *query.id,
+        schedule_msg.entries.len()
+    );
+    Ok(Json(SetStateResponse::from(
+        run_command(&s, &query.id, PlugCommand::SetSchedule(schedule_msg)).await,
+    )))
+}
+
+/// Schedule stored on the plug
+#[utoipa::path(
+    get,
+    path = "/api/schedule",
+    params(
+        ScheduleQuery
+    ),
+    responses(
+        (status = 200, body = Schedule),
+        (status = 404, description = "Plug not connected or didn't answer"),
+    )
+)]
+pub async fn get_schedule(
+    State(s): State<SharedState>,
+    Query(query): Query<ScheduleQuery>,
+) -> Result<Json<Schedule>, StatusCode> {
+    run_command(&s, &query.id, PlugCommand::GetSchedule).await;
+    s.plugs
+        .get(&query.id)
+        .and_then(|plug| plug.schedule.clone())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Settings in use on a plug
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/config",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, body = PlugConfig),
+        (status = 404, description = "Plug not connected or didn't answer"),
+    )
+)]
+pub async fn get_config(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Result<Json<PlugConfig>, StatusCode> {
+    run_command(&s, &id, PlugCommand::GetConfig).await;
+    s.plugs
+        .get(&id)
+        .and_then(|plug| plug.config)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Changes some of a plug's settings, which it keeps across reboots
+#[utoipa::path(
+    patch,
+    path = "/api/plugs/{id}/config",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    request_body = PlugConfigPatch,
+    responses(
+        (status = 200, description = "Settings now in use", body = PlugConfig),
+        (status = 400, description = "Value out of range"),
+        (status = 404, description = "Plug not connected or didn't answer"),
+        (status = 502, description = "Plug didn't apply the settings"),
+    )
+)]
+pub async fn patch_config(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    Json(patch): Json<PlugConfigPatch>,
+) -> Result<Json<PlugConfig>, StatusCode> {
+    let current = s.plugs.get(&id).and_then(|plug| plug.config);
+    let current = match current {
+        Some(c) => c,
+        None => get_config(State(s.clone()), Path(id)).await?.0,
+    };
+    let config = patch.apply(current);
+    let config_msg = config.to_common().ok_or(StatusCode::BAD_REQUEST)?;
+    info!("Setting config of {} to {config:?}", *id);
+    match run_command(&s, &id, PlugCommand::SetConfig(config_msg)).await {
+        Some(true) => Ok(Json(config)),
+        Some(false) => Err(StatusCode::BAD_GATEWAY),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Last health report of a plug
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/health",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, body = PlugHealth),
+        (status = 404, description = "Unknown plug or no report yet"),
+    )
+)]
+pub async fn plug_health(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Result<Json<PlugHealth>, StatusCode> {
+    s.plugs
+        .get(&id)
+        .and_then(|plug| plug.health.clone())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Panics reported by a plug, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/crashes",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, body = Vec<CrashEntry>),
+    )
+)]
+pub async fn plug_crashes(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Json<Vec<CrashEntry>> {
+    Json(
+        s.crashes
+            .get(&id)
+            .map(|c| c.value().clone())
+            .unwrap_or_default(),
+    )
+}
+
+/// Recent log lines of a plug, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/logs",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, body = Vec<LogEntry>),
+    )
+)]
+pub async fn plug_logs(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Json<Vec<LogEntry>> {
+    Json(
+        s.logs
+            .get(&id)
+            .map(|l| l.lines.iter().cloned().collect())
+            .unwrap_or_default(),
+    )
+}
+
+/// Follows plugs going online and offline
+///
+/// Each event is an [AvailabilityEvent] named after the new availability, a
+/// `lagged` event tells how many were skipped because the client was too
+/// slow.
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    responses(
+        (status = 200, content_type = "text/event-stream", body = AvailabilityEvent),
+    )
+)]
+pub async fn stream_events(
+    State(s): State<SharedState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let rx = s.subscribe_availability();
+    let events = futures::stream::unfold(rx, async |mut rx| {
+        let event = match rx.recv().await {
+            Ok(event) => {
+                let name = match event.availability {
+                    Availability::Online => "online",
+                    Availability::Offline => "offline",
+                };
+                Event::default()
+                    .event(name)
+                    .json_data(event)
+                    .unwrap_or_else(|_| Event::default().comment("unserializable event"))
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                Event::default().event("lagged").data(skipped.to_string())
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Follows the log lines of a plug as they arrive
+///
+/// Each event holds a [LogEntry], a `lagged` event tells how many were
+/// skipped because the client was too slow.
+#[utoipa::path(
+    get,
+    path = "/api/plugs/{id}/logs/stream",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID")
+    ),
+    responses(
+        (status = 200, content_type = "text/event-stream", body = LogEntry),
+        (status = 404, description = "Plug never connected"),
+    )
+)]
+pub async fn stream_logs(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    // only plugs that connected get a channel, anything else would pile up
+    if !s.plugs.contains_key(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let rx = s.logs.entry(id).or_default().tx.subscribe();
+    let events = futures::stream::unfold(rx, async |mut rx| {
+        let event = match rx.recv().await {
+            Ok(entry) => Event::default()
+                .json_data(entry)
+                .unwrap_or_else(|_| Event::default().comment("unserializable line")),
+            Err(RecvError::Lagged(skipped)) => {
+                Event::default().event("lagged").data(skipped.to_string())
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct LogLevelQuery {
+    // Least important lines the plug sends
+    level: LogLevel,
+}
+
+/// Changes which lines a plug sends, until it reboots
+#[utoipa::path(
+    put,
+    path = "/api/plugs/{id}/loglevel",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID"),
+        LogLevelQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+    )
+)]
+pub async fn set_log_level(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    Query(query): Query<LogLevelQuery>,
+) -> Json<SetStateResponse> {
+    info!("Setting log level of {} to {:?}", *id, query.level);
+    Json(SetStateResponse::from(
+        run_command(&s, &id, PlugCommand::SetLogLevel(query.level)).await,
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct IdentifyQuery {
+    // How long the LED blinks, 30 seconds if not given
+    seconds: Option<u32>,
+}
+
+/// Blinks the plug LED, to tell it apart from the others
+#[utoipa::path(
+    post,
+    path = "/api/plugs/{id}/identify",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID"),
+        IdentifyQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetStateResponse),
+    )
+)]
+pub async fn identify(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    Query(query): Query<IdentifyQuery>,
+) -> Json<SetStateResponse> {
+    let seconds = query.seconds.unwrap_or(30);
+    info!("Identifying {} for {seconds}s", *id);
+    Json(SetStateResponse::from(
+        run_command(&s, &id, PlugCommand::Identify { seconds }).await,
+    ))
+}
+
+/// How long the restart routes wait for the plug to connect again
+const RESTART_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RestartResponse {
+    /// Whether the plug was connected
+    present: bool,
+    /// Whether the plug accepted the command
+    success: bool,
+    /// ID the plug connects with afterwards, a factory reset gives it a new one
+    id: Option<PlugId>,
+    /// Seconds until it connected again, `None` if it didn't within a minute
+    back_after: Option<f64>,
+}
+
+/// Restarts a plug and waits for it to connect again
+///
+/// Needs `Authorization: Bearer <admin token>`.
+#[utoipa::path(
+    post,
+    path = "/api/plugs/{id}/reboot",
+    params(("id" = PlugId, Path, description = "Plug ID")),
+    responses(
+        (status = 200, description = "Success", body = RestartResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 403, description = "Admin routes are disabled"),
+    )
+)]
+pub async fn reboot(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    headers: HeaderMap,
+) -> Result<Json<RestartResponse>, StatusCode> {
+    check_admin(&headers)?;
+    info!("Rebooting {}", *id);
+    let since = Utc::now();
+    let result = run_command(&s, &id, PlugCommand::Reboot).await;
+    let back_after = match result {
+        Some(true) => wait_reconnect(&s, &id, since).await,
+        _ => None,
+    };
+    Ok(Json(RestartResponse {
+        present: result.is_some(),
+        success: result == Some(true),
+        id: Some(id),
+        back_after,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct FactoryResetTokenResponse {
+    /// Whether the plug was connected
+    present: bool,
+    /// Confirms the factory reset, valid for 30 seconds
+    token: Option<u32>,
+}
+
+/// Asks a plug for the token that confirms a factory reset
+///
+/// Needs `Authorization: Bearer <admin token>`.
+#[utoipa::path(
+    post,
+    path = "/api/plugs/{id}/factory-reset/token",
+    params(("id" = PlugId, Path, description = "Plug ID")),
+    responses(
+        (status = 200, description = "Success", body = FactoryResetTokenResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 403, description = "Admin routes are disabled"),
+    )
+)]
+pub async fn factory_reset_token(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    headers: HeaderMap,
+) -> Result<Json<FactoryResetTokenResponse>, StatusCode> {
+    check_admin(&headers)?;
+    let result = run_command(&s, &id, PlugCommand::FactoryResetRequest).await;
+    let token = match result {
+        Some(true) => s.plugs.get(&id).and_then(|p| p.reset_token),
+        _ => None,
+    };
+    Ok(Json(FactoryResetTokenResponse {
+        present: result.is_some(),
+        token,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct FactoryResetQuery {
+    // From /api/plugs/{id}/factory-reset/token
+    token: u32,
+}
+
+/// Wipes a plug's settings, Wi-Fi credentials and identity, then waits for it
+/// to connect again with its new ID
+///
+/// Needs `Authorization: Bearer <admin token>`.
+#[utoipa::path(
+    post,
+    path = "/api/plugs/{id}/factory-reset",
+    params(
+        ("id" = PlugId, Path, description = "Plug ID"),
+        FactoryResetQuery
+    ),
+    responses(
+        (status = 200, description = "Success", body = RestartResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 403, description = "Admin routes are disabled"),
+    )
+)]
+pub async fn factory_reset(
+    State(s): State<SharedState>,
+    Path(id): Path<PlugId>,
+    Query(query): Query<FactoryResetQuery>,
+    headers: HeaderMap,
+) -> Result<Json<RestartResponse>, StatusCode> {
+    check_admin(&headers)?;
+    tracing::warn!("Factory resetting {}", *id);
+    let since = Utc::now();
+    let result = run_command(&s, &id, PlugCommand::FactoryReset { token: query.token }).await;
+    let new_id = match result {
+        Some(true) => s.plugs.get(&id).and_then(|p| p.replaced_by),
+        _ => None,
+    };
+    let back_after = match new_id {
+        Some(new_id) => wait_reconnect(&s, &new_id, since).await,
+        None => None,
+    };
+    Ok(Json(RestartResponse {
+        present: result.is_some(),
+        success: result == Some(true),
+        id: new_id,
+        back_after,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobCommand {
+    /// Same as `/api/setstate`
+    SetState {
+        #[serde(default)]
+        channel: u8,
+        state: PowerStateOption,
+        duration: Option<u32>,
+    },
+    /// Same as `/api/pulse`
+    Pulse {
+        #[serde(default)]
+        channel: u8,
+        millis: u32,
+    },
+    /// Refreshes the state returned by `/api/query`
+    Query,
+    Identify {
+        seconds: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+pub struct JobRequest {
+    id: PlugId,
+    command: JobCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, utoipa::IntoParams)]
+pub struct WaitQuery {
+    // Seconds to wait for the job to finish before answering, up to 30, 0 by
+    // default
+    #[serde(default)]
+    wait: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct JobResponse {
+    id: JobId,
+    plug: PlugId,
+    command: String,
+    state: JobState,
+    created: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+}
+
+impl JobResponse {
+    fn get(s: &SharedState, id: JobId) -> Option<Self> {
+        let job = s.jobs.get(&id)?;
+        Some(Self {
+            id,
+            plug: job.plug,
+            command: format!("{:?}", job.command),
+            state: *job.state.borrow(),
+            created: job.created,
+            finished: job.finished,
+        })
+    }
+
+    /// 202 until the job finishes
+    fn into_response(self) -> (StatusCode, Json<Self>) {
+        let status = if self.state.finished() {
+            StatusCode::OK
+        } else {
+            StatusCode::ACCEPTED
+        };
+        (status, Json(self))
+    }
+}
+
+/// Sends a command to a plug without waiting for it, unless asked to
+#[utoipa::path(
+    post,
+    path = "/api/jobs",
+    params(WaitQuery),
+    request_body = JobRequest,
+    responses(
+        (status = 200, description = "Job finished", body = JobResponse),
+        (status = 202, description = "Job still pending", body = JobResponse),
+        (status = 400, description = "Unknown channel"),
+        (status = 404, description = "Plug not connected, or never connected for queued commands"),
+    )
+)]
+pub async fn create_job(
+    State(s): State<SharedState>,
+    Query(query): Query<WaitQuery>,
+    Json(request): Json<JobRequest>,
+) -> Result<(StatusCode, Json<JobResponse>), StatusCode> {
+    let id = request.id;
+    let (command, channel) = match request.command {
+        JobCommand::SetState {
+            channel,
+            state,
+            duration,
+        } => {
+            let command = match (state, duration) {
+                (PowerStateOption::On, None) => PlugCommand::TurnOn { channel },
+                (PowerStateOption::Off, None) => PlugCommand::TurnOff { channel },
+                (PowerStateOption::On, Some(seconds)) => {
+                    PlugCommand::TurnOnFor { channel, seconds }
+                }
+                (PowerStateOption::Off, Some(seconds)) => {
+                    PlugCommand::TurnOffFor { channel, seconds }
+                }
+            };
+            (command, Some(channel))
+        }
+        JobCommand::Pulse { channel, millis } => {
+            (PlugCommand::Pulse { channel, millis }, Some(channel))
+        }
+        JobCommand::Query => (PlugCommand::QueryState, None),
+        JobCommand::Identify { seconds } => (PlugCommand::Identify { seconds }, None),
+    };
+    if let Some(channel) = channel
+        && s.plugs
+            .get(&id)
+            .is_some_and(|plug| !plug.has_channel(channel))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    info!("New job for {}: {command:?}", *id);
+
+    let online = s.plugs.get(&id).is_some_and(|plug| plug.online());
+    // kept in the shadow like /api/setstate does
+    let desired = command.switch().filter(|_| command.timer().is_none());
+    if let Some((channel, state)) = desired
+        && !s.desire(id, channel, state)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let job = match (online, desired) {
+        (true, _) => start_job(&s, &id, command).ok_or(StatusCode::NOT_FOUND)?,
+        (false, Some((channel, _))) => {
+            let job = s.add_job(id, command, JobState::Queued);
+            s.queue_job(id, channel, job);
+            job
+        }
+        (false, None) => return Err(StatusCode::NOT_FOUND),
+    };
+    wait_job(&s, job, Duration::from_secs(query.wait).min(MAX_JOB_WAIT)).await;
+    JobResponse::get(&s, job)
+        .map(JobResponse::into_response)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// State of a job, kept for 10 minutes after it finishes
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}",
+    params(
+        ("id" = JobId, Path, description = "Job ID"),
+        WaitQuery
+    ),
+    responses(
+        (status = 200, description = "Job finished", body = JobResponse),
+        (status = 202, description = "Job still pending", body = JobResponse),
+        (status = 404, description = "Unknown or expired job"),
+    )
+)]
+pub async fn get_job(
+    State(s): State<SharedState>,
+    Path(id): Path<JobId>,
+    Query(query): Query<WaitQuery>,
+) -> Result<(StatusCode, Json<JobResponse>), StatusCode> {
+    wait_job(&s, id, Duration::from_secs(query.wait).min(MAX_JOB_WAIT)).await;
+    JobResponse::get(&s, id)
+        .map(JobResponse::into_response)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Checks the bearer token against `--admin-token`
+fn check_admin(headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = &ARGS.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same_token(given, expected) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Constant time comparison, the length isn't a secret
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Bytes of an even length hex string, `None` if it isn't one or doesn't fit
+fn decode_hex<const N: usize>(hex: &str) -> Option<common::heapless::Vec<u8, N>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .and_then(|bytes| common::heapless::Vec::from_slice(&bytes).ok())
+}
+
+/// Waits for `id` to start a connection after `since`, returning how many
+/// seconds that took
+async fn wait_reconnect(s: &SharedState, id: &PlugId, since: DateTime<Utc>) -> Option<f64> {
+    timeout(RESTART_WAIT, async {
+        loop {
+            if let Some(connected) = s.plugs.get(id).map(|p| p.connected)
+                && connected > since
+            {
+                return (connected - since).as_seconds_f64();
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    })
+    .await
+    .ok()
+}
+
+impl From<Option<bool>> for SetStateResponse {
+    fn from(value: Option<bool>) -> Self {
+        Self {
+            present: value.is_some(),
+            success: value.unwrap_or(false),
+            queued: false,
+        }
+    }
+}
+
+/// How long a plug gets to acknowledge a command
+pub(crate) const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a client can wait on a job in one request
+const MAX_JOB_WAIT: Duration = Duration::from_secs(30);
+
+/// Sends `command` to a connected plug and waits up to 10 seconds for it to be
+/// acknowledged, without making a job only /api/jobs would ever look up
+///
+/// Returns `None` if the plug isn't connected
+async fn run_command(s: &SharedState, id: &PlugId, command: PlugCommand) -> Option<bool> {
+    // spawned so the command still goes out if the client hangs up
+    let state = tokio::spawn(send_command(s, id, command)?).await;
+    Some(matches!(state, Ok(JobState::Succeeded)))
+}
+
+/// Sends `command` to a connected plug without waiting, the job finishes
+/// once the plug acknowledges it or after 10 seconds
+///
+/// Returns `None` if the plug isn't connected
+fn start_job(s: &SharedState, id: &PlugId, command: PlugCommand) -> Option<JobId> {
+    let sent = send_command(s, id, command.clone())?;
+    let job = s.add_job(*id, command, JobState::Pending);
+    let s = s.clone();
+    tokio::spawn(async move { s.finish_job(job, sent.await) });
+    Some(job)
+}
+
+/// Hands `command` to a connected plug, the future settles once the plug
+/// acknowledges it or after 10 seconds
+///
+/// Returns `None` if the plug isn't connected
+fn send_command(
+    s: &SharedState,
+    id: &PlugId,
+    command: PlugCommand,
+) -> Option<impl Future<Output = JobState> + use<>> {
+    let task_tx = s.plugs.get(id)?.task_tx.clone();
+    let (task, rx) = PlugTask::new(command);
+    Some(async move {
+        let success = timeout(COMMAND_TIMEOUT, async {
+            if task_tx.send(task).await.is_err() {
                 tracing::warn!("Sending task to plug failed");
-                false
-            } else {
-                match rx.await {
-                    Ok(b) => b,
-                    Err(_) => {
-                        tracing::warn!("task dropped by plug");
-                        false
-                    }
+                return false;
+            }
+            match rx.await {
+                Ok(b) => b,
+                Err(_) => {
+                    tracing::warn!("task dropped by plug");
+                    false
                 }
             }
         })
-        .await
-        .is_ok_and(|i| i);
+        .await;
+        match success {
+            Ok(true) => JobState::Succeeded,
+            Ok(false) => JobState::Failed,
+            Err(_) => JobState::TimedOut,
+        }
+    })
+}
 
-        Json(SetStateResponse {
-            present: true,
-            success,
-        })
-    } else {
-        Json(SetStateResponse {
-            present: false,
-            success: false,
-        })
-    }
+/// Waits up to `wait` for a job to finish, returning its state by then
+async fn wait_job(s: &SharedState, job: JobId, wait: Duration) -> Option<JobState> {
+    let mut rx = s.jobs.get(&job)?.state.subscribe();
+    let _ = timeout(wait, rx.wait_for(|state| state.finished())).await;
+    Some(*rx.borrow())
 }
 
 #[derive(Serialize, ToSchema)]
@@ -158,8 +1213,16 @@ pub struct ListResponse {
 #[derive(Serialize, ToSchema)]
 pub struct PlugListInfo {
     id: PlugId,
+    /// State of channel 0
     state: PowerState,
+    channels: Vec<PowerState>,
+    power_on: Option<PowerOnBehavior>,
+    ota: Option<OtaProgress>,
     last_seen: chrono::DateTime<Utc>,
+    /// Channel states are the last ones reported while offline
+    availability: Availability,
+    /// When `availability` last changed
+    since: chrono::DateTime<Utc>,
 }
 
 #[utoipa::path(
@@ -176,8 +1239,13 @@ pub async fn list_plugs(State(s): State<SharedState>) -> Json<ListResponse> {
             .iter()
             .map(|k| PlugListInfo {
                 id: *k.key(),
-                state: k.value().power_state,
+                state: k.value().power_state(0),
+                channels: k.value().channels.clone(),
+                power_on: k.value().power_on,
+                ota: k.value().ota,
                 last_seen: k.value().last_seen,
+                availability: k.value().availability().0,
+                since: k.value().availability().1,
             })
             .collect(),
     })
@@ -186,6 +1254,27 @@ pub async fn list_plugs(State(s): State<SharedState>) -> Json<ListResponse> {
 pub fn router() -> OpenApiRouter<SharedState> {
     OpenApiRouter::new()
         .routes(routes!(set_state))
+        .routes(routes!(pulse))
+        .routes(routes!(set_power_on))
         .routes(routes!(query_status))
         .routes(routes!(list_plugs))
+        .routes(routes!(stream_events))
+        .routes(routes!(upload_firmware))
+        .routes(routes!(download_firmware))
+        .routes(routes!(start_ota))
+        .routes(routes!(set_schedule, get_schedule))
+        .routes(routes!(get_config, patch_config))
+        .routes(routes!(create_job))
+        .routes(routes!(get_job))
+        .routes(routes!(plug_shadow))
+        .routes(routes!(plug_health))
+        .routes(routes!(plug_crashes))
+        .routes(routes!(plug_logs))
+        .routes(routes!(stream_logs))
+        .routes(routes!(set_log_level))
+        .routes(routes!(identify))
+        .routes(routes!(reboot))
+        .routes(routes!(factory_reset_token))
+        .routes(routes!(factory_reset))
+        .layer(DefaultBodyLimit::max(MAX_FIRMWARE_SIZE))
 }
@@ -4,14 +4,14 @@ use std::{
 };
 
 use chrono::Utc;
-use common::{DisconnectReason, MessagePayload, PlugMessage};
+use common::{DisconnectReason, MessagePayload, OtaStatus, PlugMessage};
 use dashmap::mapref::one::RefMut;
 use futures::{
     SinkExt, StreamExt,
     stream::{SplitSink, SplitStream},
 };
 use tokio::{
-    net::ToSocketAddrs,
+    net::{TcpListener, ToSocketAddrs},
     select,
     sync::{
         Mutex,
@@ -19,15 +19,36 @@ use tokio::{
     },
     time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 use tokio_util::udp::UdpFramed;
 use tracing::{debug, info, warn};
 
 use crate::{
-    PlugCommand, PlugId, PlugTask, PowerState, SharedState, TaskRx,
-    broker::proto::{BrokerCodec, CodecError},
+    Availability, JobState, LogEntry, OtaProgress, PlugCommand, PlugId, PlugTask, PowerState,
+    SharedState, TaskRx, TaskTx, api::COMMAND_TIMEOUT, cli::ARGS,
 };
 
+mod handshake;
+mod ingress;
 mod proto;
+mod stream;
+
+use stream::Inbound;
+
+pub use handshake::{
+    COOKIE_LIFETIME, Cookies, GLOBAL_BURST, GLOBAL_RATE, Limited, PREFIX_BURST, PREFIX_RATE,
+    SessionLimits, prefix,
+};
+pub use ingress::{IGNORE_FOR, IngressFilter, MALFORMED_LIMIT, MALFORMED_WINDOW};
+pub use proto::{BrokerCodec, CodecError, MAX_DATAGRAM};
+pub use stream::{
+    FrameCodec, HANDSHAKE_TIMEOUT, MAX_STREAMS, MAX_STREAMS_PER_PREFIX, load_tls, tls_acceptor,
+};
+
+/// How often plugs that went silent are marked offline
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// How often sessions whose worker gave up are forgotten
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ConnectionState {
@@ -46,13 +67,77 @@ type BrokerStream = SplitStream<UdpFramed<BrokerCodec>>;
 pub struct Broker {
     stream: BrokerStream,
     sink: BrokerSink,
+    local_addr: SocketAddr,
     shared_state: SharedState,
-    sessions: HashMap<SocketAddr, MsgTx>,
+    ingress: IngressFilter,
+    cookies: Cookies,
+    limits: SessionLimits,
+    /// Messages from TCP connections, see [Broker::listen_stream]
+    inbound: Receiver<Inbound>,
+    inbound_tx: Sender<Inbound>,
+    /// Live sessions by where their messages come from
+    sessions: HashMap<Peer, Session>,
+    /// Where each plug's session lives, a plug has at most one
+    peers: HashMap<PlugId, Peer>,
+}
+
+/// Where a plug's messages come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Datagram(SocketAddr),
+    Stream(SocketAddr),
+}
+
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Peer::Datagram(addr) | Peer::Stream(addr) => *addr,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Datagram(addr) => write!(f, "{addr}/udp"),
+            Peer::Stream(addr) => write!(f, "{addr}/tcp"),
+        }
+    }
+}
+
+/// Where a session's answers go
+#[derive(Clone)]
+enum Outbox {
+    Datagram(BrokerSink, SocketAddr),
+    Stream(MsgTx),
+}
+
+impl Outbox {
+    async fn send(&self, msg: PlugMessage) -> Result<(), ConnectionError> {
+        match self {
+            Outbox::Datagram(sink, addr) => sink.lock().await.send((msg, *addr)).await?,
+            Outbox::Stream(tx) => tx.send(msg).await.map_err(|_| ConnectionError::Dead)?,
+        }
+        Ok(())
+    }
+}
+
+/// Handle to the worker serving one plug
+struct Session {
+    plug: PlugId,
+    tx: MsgTx,
+}
+
+impl Session {
+    /// Whether the worker is gone, dropping the session ends it otherwise
+    fn is_dead(&self) -> bool {
+        self.tx.is_closed()
+    }
 }
 
 struct BrokerConnection {
-    sink: BrokerSink,
-    addr: SocketAddr,
+    outbox: Outbox,
+    peer: Peer,
     /// Channel for protocol messages
     msg_rx: MsgRx,
     /// Channel for HTTP API tasks
@@ -60,6 +145,8 @@ struct BrokerConnection {
     /// HTTP API tasks
     tasks: Vec<PlugTask>,
     plug_id: Option<PlugId>,
+    /// Set once the plug connects, see [SharedState::set_availability]
+    session: u64,
     /// Holds shared state for plug power states and stuff
     shared_state: SharedState,
     /// Local message sequence number
@@ -84,52 +171,223 @@ impl From<CodecError> for ConnectionError {
 impl Broker {
     pub async fn new(addr: impl ToSocketAddrs, shared_state: SharedState) -> Self {
         let socket = tokio::net::UdpSocket::bind(addr).await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
         let framed = UdpFramed::new(socket, BrokerCodec);
         let (sink, stream) = framed.split();
+        let (inbound_tx, inbound) = channel(64);
 
         Self {
             sink: Arc::new(Mutex::new(sink)),
             stream,
+            local_addr,
             shared_state,
+            ingress: IngressFilter::new(),
+            cookies: Cookies::new(),
+            limits: SessionLimits::new(std::time::Instant::now()),
+            inbound,
+            inbound_tx,
             sessions: HashMap::new(),
+            peers: HashMap::new(),
         }
     }
 
+    /// Address the broker listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
     pub async fn run(&mut self) {
         tracing::info!("Broker initialized");
+        let shared_state = self.shared_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                shared_state.sweep();
+            }
+        });
 
+        let mut reap = tokio::time::interval(REAP_INTERVAL);
         loop {
-            let (msg, addr) = self.stream.next().await.unwrap().unwrap();
-            tracing::debug!("Received {msg:?} from {addr}");
-            if matches!(msg.payload, MessagePayload::Conn { id: _ }) {
-                let (tx, rx) = channel(16);
-                tx.send(msg).await.unwrap();
-                tracing::info!("New session for {addr}");
-                self.sessions.insert(addr, tx);
-                tokio::spawn(worker_task(
-                    rx,
-                    self.sink.clone(),
-                    addr,
-                    self.shared_state.clone(),
-                ));
-            } else if let Some(conn) = self.sessions.get(&addr)
-                && let Err(_e) = conn.send(msg).await
-            {
-                self.sessions.remove(&addr);
+            let (peer, outbox, msg) = select! {
+                next = self.stream.next() => match next {
+                    Some(Ok((datagram, addr))) => match self.admit(addr, datagram) {
+                        Some(msg) => (Peer::Datagram(addr), Outbox::Datagram(self.sink.clone(), addr), msg),
+                        None => continue,
+                    },
+                    Some(Err(e)) => {
+                        warn!("Failed to receive a datagram: {e}");
+                        continue;
+                    }
+                    None => {
+                        warn!("Broker socket closed");
+                        return;
+                    }
+                },
+                Some(Inbound { addr, msg, reply }) = self.inbound.recv() => match msg {
+                    Some(msg) => (Peer::Stream(addr), Outbox::Stream(reply), msg),
+                    None => {
+                        debug!("Connection from {addr} closed");
+                        self.close(Peer::Stream(addr));
+                        continue;
+                    }
+                },
+                _ = reap.tick() => {
+                    self.reap();
+                    continue;
+                }
+            };
+            tracing::debug!("Received {msg:?} from {peer}");
+            match msg.payload {
+                MessagePayload::Conn { id } => self.challenge(id, peer, outbox).await,
+                MessagePayload::ConnConfirm { id, cookie } => {
+                    let addr = peer.addr();
+                    let unix_now = Utc::now().timestamp().max(0) as u64;
+                    if !self.cookies.verify(addr, id, &cookie, unix_now) {
+                        debug!("Bad handshake cookie from {peer}");
+                    } else if let Err(e) = self.limits.admit(addr, std::time::Instant::now()) {
+                        warn!("Refusing session for {peer}: {e}");
+                    } else {
+                        self.open(id.into(), peer, outbox, msg).await;
+                    }
+                }
+                _ => self.forward(peer, msg).await,
             }
         }
     }
+
+    /// Also accepts plugs over TCP at `addr`, wrapped in TLS if `tls` is set.
+    /// Returns the address it listens on
+    pub async fn listen_stream(
+        &self,
+        addr: impl ToSocketAddrs,
+        tls: Option<TlsAcceptor>,
+    ) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!(
+            "Accepting {} connections on {local_addr}",
+            if tls.is_some() { "TLS" } else { "TCP" }
+        );
+        tokio::spawn(stream::accept(listener, tls, self.inbound_tx.clone()));
+        Ok(local_addr)
+    }
+
+    /// Decoded datagram from `addr`, unless it is ignored or malformed
+    ///
+    /// Addresses with a live session are never ignored, and their malformed
+    /// datagrams don't count against them: the source may be forged.
+    fn admit(
+        &mut self,
+        addr: SocketAddr,
+        datagram: Result<PlugMessage, CodecError>,
+    ) -> Option<PlugMessage> {
+        let now = std::time::Instant::now();
+        let live = self.sessions.contains_key(&Peer::Datagram(addr));
+        if !live && !self.ingress.admits(addr, now) {
+            tracing::trace!("Ignoring datagram from {addr}");
+            return None;
+        }
+        match datagram {
+            Ok(msg) => Some(msg),
+            Err(e) if live => {
+                warn!("Malformed datagram from {addr}, which has a session: {e}");
+                None
+            }
+            Err(e) => {
+                let errors = self.ingress.malformed(addr, now);
+                warn!("Malformed datagram from {addr} ({errors} recently): {e}");
+                if errors >= MALFORMED_LIMIT {
+                    warn!("Ignoring {addr} for {}s", IGNORE_FOR.as_secs());
+                }
+                None
+            }
+        }
+    }
+
+    /// Answers a `Conn` with a cookie, without setting anything up
+    async fn challenge(&mut self, id: uuid::Uuid, peer: Peer, outbox: Outbox) {
+        let cookie = self
+            .cookies
+            .issue(peer.addr(), id, Utc::now().timestamp().max(0) as u64);
+        // plugs don't check the sequence before the session opens
+        let msg = PlugMessage::new(0, MessagePayload::ConnCookie { cookie });
+        if let Err(e) = outbox.send(msg).await {
+            warn!("Failed to send a cookie to {peer}: {e}");
+        }
+    }
+
+    /// Hands `msg` to the session of `peer`, if there is one
+    async fn forward(&mut self, peer: Peer, msg: PlugMessage) {
+        if let Some(session) = self.sessions.get(&peer)
+            && let Err(_e) = session.tx.send(msg).await
+        {
+            self.close(peer);
+        }
+    }
+
+    /// Starts a worker for `plug` at `peer`, replacing the one it had before
+    ///
+    /// A plug whose address or transport changed keeps being the same plug:
+    /// its old session is dropped, which stops the old worker, and the new
+    /// worker takes over its state. Whatever `peer` had before is dropped too.
+    async fn open(&mut self, plug: PlugId, peer: Peer, outbox: Outbox, conn: PlugMessage) {
+        if let Some(old) = self.peers.get(&plug).copied() {
+            if old == peer {
+                info!("Plug {} reconnected from {peer}", plug.0);
+            } else {
+                info!("Plug {} moved from {old} to {peer}", plug.0);
+            }
+            self.close(old);
+        }
+        self.close(peer);
+
+        let (tx, rx) = channel(16);
+        tx.send(conn).await.unwrap();
+        info!("New session for {peer}");
+        self.sessions.insert(peer, Session { plug, tx });
+        self.peers.insert(plug, peer);
+        tokio::spawn(worker_task(rx, outbox, peer, self.shared_state.clone()));
+    }
+
+    /// Forgets the session of `peer`, which stops its worker
+    fn close(&mut self, peer: Peer) {
+        if let Some(session) = self.sessions.remove(&peer)
+            && self.peers.get(&session.plug) == Some(&peer)
+        {
+            self.peers.remove(&session.plug);
+        }
+    }
+
+    /// Forgets sessions whose worker already stopped, and offenders that
+    /// behaved for long enough
+    fn reap(&mut self) {
+        let now = std::time::Instant::now();
+        self.ingress.prune(now);
+        self.limits.prune(now);
+        let dead: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.is_dead())
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in dead {
+            debug!("Reaping session for {peer}");
+            self.close(peer);
+        }
+    }
 }
 
 impl BrokerConnection {
-    pub fn new(sink: BrokerSink, addr: SocketAddr, rx: MsgRx, shared_state: SharedState) -> Self {
+    fn new(outbox: Outbox, peer: Peer, rx: MsgRx, shared_state: SharedState) -> Self {
         Self {
-            sink,
-            addr,
+            outbox,
+            peer,
             msg_rx: rx,
             task_rx: None,
             tasks: Vec::default(),
             plug_id: None,
+            session: 0,
             shared_state,
             seq: Wrapping(rand::random()),
             client_seq: Wrapping(0),
@@ -139,12 +397,102 @@ impl BrokerConnection {
 
     pub async fn send(&mut self, payload: MessagePayload) -> Result<(), ConnectionError> {
         self.seq += 1;
-        self.sink
-            .lock()
+        self.outbox
+            .send(PlugMessage::new(self.seq.0, payload))
             .await
-            .send((PlugMessage::new(self.seq.0, payload), self.addr))
-            .await?;
-        Ok(())
+    }
+
+    /// Builds the announcement for a firmware image, failing the task if the
+    /// image doesn't exist
+    fn ota_begin(&mut self, image_id: u32) -> Option<MessagePayload> {
+        let Some(image) = self.shared_state.firmware.get(&image_id) else {
+            warn!("Firmware image {image_id} doesn't exist");
+            for t in self
+                .tasks
+                .extract_if(.., |t| *t.command() == PlugCommand::Ota { image_id })
+            {
+                t.complete(false);
+            }
+            return None;
+        };
+        Some(MessagePayload::OtaBegin {
+            image_id,
+            size: image.data.len() as u32,
+            sha256: image.sha256,
+            http_port: ARGS.ota_http_port.unwrap_or(ARGS.http_port),
+            signature: image.signature.clone(),
+        })
+    }
+
+    /// Completes the commands switching `channel` to `state`, starting the
+    /// timers of timed ones
+    fn switch_acked(&mut self, channel: u8, state: PowerState) {
+        let acked: Vec<_> = self
+            .tasks
+            .extract_if(.., |t| t.command().switch() == Some((channel, state)))
+            .collect();
+        if let Some(mut s) = self.get_state_mut() {
+            s.set_power_state(channel, state);
+            // a plain command cancels the timer on the plug too
+            for t in &acked {
+                s.set_timer(channel, t.command().timer());
+            }
+        }
+        if let Some(id) = self.plug_id {
+            for t in &acked {
+                if t.command().timer().is_some() {
+                    self.shared_state.undesire(id, channel);
+                } else {
+                    self.shared_state.desired_synced(id, channel, state);
+                }
+            }
+        }
+        for t in acked {
+            t.complete(true);
+        }
+    }
+
+    /// Sends the states requested while the plug was offline, they go out
+    /// after the [ConnAck](MessagePayload::ConnAck)
+    ///
+    /// Jobs queued for them finish with the plug's answer.
+    fn reconcile(&self, id: PlugId, tx: TaskTx) {
+        let pending = self.shared_state.pending(id);
+        if pending.is_empty() {
+            return;
+        }
+        info!(
+            "Applying {} pending channel state(s) to {}",
+            pending.len(),
+            id.0
+        );
+        let shared_state = self.shared_state.clone();
+        tokio::spawn(async move {
+            for (channel, state, job) in pending {
+                let command = if state == PowerState::On {
+                    PlugCommand::TurnOn { channel }
+                } else {
+                    PlugCommand::TurnOff { channel }
+                };
+                // completed by switch_acked like any other
+                let (task, rx) = PlugTask::new(command);
+                if tx.send(task).await.is_err() {
+                    // still queued for the next connection
+                    break;
+                }
+                if let Some(job) = job.filter(|job| shared_state.dequeue_job(*job)) {
+                    let shared_state = shared_state.clone();
+                    tokio::spawn(async move {
+                        let state = match timeout(COMMAND_TIMEOUT, rx).await {
+                            Ok(Ok(true)) => JobState::Succeeded,
+                            Ok(_) => JobState::Failed,
+                            Err(_) => JobState::TimedOut,
+                        };
+                        shared_state.finish_job(job, state);
+                    });
+                }
+            }
+        });
     }
 
     fn get_state_mut(&self) -> Option<RefMut<'_, PlugId, crate::PlugState>> {
@@ -176,8 +524,15 @@ impl BrokerConnection {
                 core::future::pending().await
             }
         };
+        let heartbeat = 1000
+            * crate::PlugConfig::heartbeat(
+                self.plug_id
+                    .and_then(|id| self.shared_state.plugs.get(&id)?.config),
+            );
         let next_msg = timeout(
-            Duration::from_millis(rand::random_range(29000..=31000)),
+            Duration::from_millis(rand::random_range(
+                heartbeat.saturating_sub(1000)..=heartbeat + 1000,
+            )),
             self.msg_rx.recv(),
         );
 
@@ -185,8 +540,8 @@ impl BrokerConnection {
             msg = next_msg => {
                 match msg {
                     Ok(Some(msg)) => {
-                        if let Some(mut s) = self.get_state_mut() {
-                            s.last_seen = Utc::now();
+                        if let Some(id) = self.plug_id {
+                            self.shared_state.seen(id, self.session);
                         }
                         self.feed_msg(Some(msg)).await
                     },
@@ -207,12 +562,26 @@ impl BrokerConnection {
             task = next_task => {
                 if let Some(task) = task {
                     tracing::debug!("Received new task {:?}", &task.command());
-                    let cmd = task.command();
+                    let cmd = task.command().clone();
                     self.tasks.push(task);
                     match cmd {
-                        PlugCommand::TurnOn => ControlFlow::Continue(Some(MessagePayload::TurnOn)),
-                        PlugCommand::TurnOff => ControlFlow::Continue(Some(MessagePayload::TurnOff)),
+                        PlugCommand::TurnOn { channel } => ControlFlow::Continue(Some(MessagePayload::TurnOn { channel })),
+                        PlugCommand::TurnOff { channel } => ControlFlow::Continue(Some(MessagePayload::TurnOff { channel })),
+                        PlugCommand::TurnOnFor { channel, seconds } => ControlFlow::Continue(Some(MessagePayload::TurnOnFor { channel, seconds })),
+                        PlugCommand::TurnOffFor { channel, seconds } => ControlFlow::Continue(Some(MessagePayload::TurnOffFor { channel, seconds })),
+                        PlugCommand::Pulse { channel, millis } => ControlFlow::Continue(Some(MessagePayload::Pulse { channel, millis })),
                         PlugCommand::QueryState => ControlFlow::Continue(Some(MessagePayload::QueryStatus)),
+                        PlugCommand::SetPowerOn(behavior) => ControlFlow::Continue(Some(MessagePayload::SetPowerOnBehavior { behavior: behavior.into() })),
+                        PlugCommand::Ota { image_id } => ControlFlow::Continue(self.ota_begin(image_id)),
+                        PlugCommand::SetSchedule(schedule) => ControlFlow::Continue(Some(MessagePayload::SetSchedule { schedule })),
+                        PlugCommand::GetSchedule => ControlFlow::Continue(Some(MessagePayload::GetSchedule)),
+                        PlugCommand::SetLogLevel(level) => ControlFlow::Continue(Some(MessagePayload::SetLogLevel { level: level.into() })),
+                        PlugCommand::Identify { seconds } => ControlFlow::Continue(Some(MessagePayload::Identify { seconds })),
+                        PlugCommand::SetConfig(config) => ControlFlow::Continue(Some(MessagePayload::SetConfig { config })),
+                        PlugCommand::GetConfig => ControlFlow::Continue(Some(MessagePayload::GetConfig)),
+                        PlugCommand::Reboot => ControlFlow::Continue(Some(MessagePayload::Reboot)),
+                        PlugCommand::FactoryResetRequest => ControlFlow::Continue(Some(MessagePayload::FactoryResetRequest)),
+                        PlugCommand::FactoryReset { token } => ControlFlow::Continue(Some(MessagePayload::FactoryReset { token })),
                     }
                 } else {
                     ControlFlow::Continue(None)
@@ -222,12 +591,12 @@ impl BrokerConnection {
 
         match next {
             ControlFlow::Continue(Some(msg)) => {
-                debug!("Sending {msg:?} to {}", self.addr);
+                debug!("Sending {msg:?} to {}", self.peer);
                 self.send(msg).await
             }
             ControlFlow::Continue(None) => Ok(()),
             ControlFlow::Break(reason) => {
-                info!("Disconnecting from {} ({reason:?})", self.addr);
+                info!("Disconnecting from {} ({reason:?})", self.peer);
                 self.disconnect(reason).await
             }
         }
@@ -274,25 +643,54 @@ impl BrokerConnection {
             }
         }
 
+        let seq = msg.as_ref().map(|m| m.seq);
         match (msg.map(|m| m.payload), self.state) {
-            (Some(Mp::Conn { id }), ConnectionState::Unknown) => {
+            (Some(Mp::ConnConfirm { id, cookie: _ }), ConnectionState::Unknown) => {
                 let (tx, rx) = tokio::sync::mpsc::channel(4);
-                self.shared_state.plugs.insert(
+                let now = Utc::now();
+                self.session = self.shared_state.new_session();
+                let previous = self.shared_state.plugs.insert(
                     id.into(),
                     crate::PlugState {
+                        session: self.session,
+                        availability: Availability::Online,
+                        since: now,
+                        connected: now,
                         last_seen: chrono::Utc::now(),
-                        power_state: crate::PowerState::Unknown,
-                        task_tx: tx,
+                        channels: Vec::new(),
+                        timers: Vec::new(),
+                        power_on: None,
+                        ota: None,
+                        schedule: None,
+                        health: None,
+                        log_level: None,
+                        config: None,
+                        reset_token: None,
+                        replaced_by: None,
+                        task_tx: tx.clone(),
                     },
                 );
                 self.plug_id = Some(id.into());
                 tracing::info!("New plug connected: {id}");
+                // a reconnection before going offline isn't news
+                match previous {
+                    Some(p) if p.availability == Availability::Online => {}
+                    _ => self
+                        .shared_state
+                        .announce(id.into(), Availability::Online, now),
+                }
+                self.reconcile(id.into(), tx);
                 self.task_rx = Some(rx);
 
-                self.client_seq = Wrapping(msg.unwrap().seq);
-                ok!(Mp::ConnAck, Cs::Working)
+                self.client_seq = Wrapping(seq.unwrap());
+                ok!(
+                    Mp::ConnAck {
+                        time: Utc::now().timestamp().max(0) as u64
+                    },
+                    Cs::Working
+                )
             }
-            (Some(Mp::Conn { id: _ }), _) => dc!(Dr::Closed),
+            (Some(Mp::Conn { .. } | Mp::ConnConfirm { .. }), _) => dc!(Dr::Closed),
             (Some(Mp::Disconnect { reason }), _) => {
                 warn!("Client requested disconnect: {reason:?}");
                 dc!(Dr::Closed)
@@ -306,40 +704,228 @@ impl BrokerConnection {
                 }
             }
             (Some(Mp::Pong { data: _ }), _) => dc!(Dr::ProtocolError),
-            (Some(Mp::TurnOffAck), _) | (Some(Mp::TurnOffNotify), _) => {
-                self.get_state_mut().unwrap().power_state = crate::PowerState::Off;
+            (Some(Mp::TurnOffAck { channel }), _) => {
+                self.switch_acked(channel, PowerState::Off);
+                ok!()
+            }
+            (Some(Mp::TurnOnAck { channel }), _) => {
+                self.switch_acked(channel, PowerState::On);
+                ok!()
+            }
+            (Some(Mp::TurnOffNotify { channel }), _) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    s.switched(channel, PowerState::Off);
+                }
+                ok!()
+            }
+            (Some(Mp::TurnOnNotify { channel }), _) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    s.switched(channel, PowerState::On);
+                }
+                ok!()
+            }
+            (
+                Some(Mp::StatusResp {
+                    channels,
+                    on,
+                    remaining,
+                }),
+                _,
+            ) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    s.set_channels(channels, on);
+                    s.set_remaining(&remaining);
+                }
+                for t in self.tasks.extract_if(.., |t| match t.command() {
+                    PlugCommand::QueryState => true,
+                    // the plug doesn't have this channel
+                    cmd => cmd.switch().is_some_and(|(channel, _)| channel >= channels),
+                }) {
+                    let success = *t.command() == PlugCommand::QueryState;
+                    t.complete(success);
+                }
+                ok!()
+            }
+            (
+                Some(Mp::PowerOnReport {
+                    behavior,
+                    channels,
+                    on,
+                }),
+                _,
+            ) => {
+                let behavior = behavior.into();
+                if let Some(mut s) = self.get_state_mut() {
+                    s.power_on = Some(behavior);
+                    s.set_channels(channels, on);
+                }
+                for t in self
+                    .tasks
+                    .extract_if(.., |t| *t.command() == PlugCommand::SetPowerOn(behavior))
+                {
+                    t.complete(true);
+                }
+                ok!()
+            }
+            (Some(Mp::TimeRequest), _) => ok!(Mp::TimeSync {
+                time: Utc::now().timestamp().max(0) as u64
+            }),
+            (Some(Mp::ScheduleReport { schedule }), _) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    s.schedule = Some((&schedule).into());
+                }
+                for t in self.tasks.extract_if(.., |t| {
+                    matches!(
+                        t.command(),
+                        PlugCommand::SetSchedule(_) | PlugCommand::GetSchedule
+                    )
+                }) {
+                    // the plug drops entries it can't run
+                    let success = match t.command() {
+                        PlugCommand::SetSchedule(sent) => *sent == schedule,
+                        _ => true,
+                    };
+                    t.complete(success);
+                }
+                ok!()
+            }
+            (Some(Mp::HealthReport { health }), _) => {
+                debug!("Plug {:?} health: {health:?}", self.plug_id);
+                if let Some(mut s) = self.get_state_mut() {
+                    s.health = Some((&health).into());
+                }
+                ok!()
+            }
+            (Some(Mp::Log { line, dropped }), _) => {
+                if let Some(id) = self.plug_id {
+                    self.shared_state.add_log(id, LogEntry::new(&line, dropped));
+                }
+                ok!()
+            }
+            (Some(Mp::LogLevelReport { level }), _) => {
+                let level = level.into();
+                if let Some(mut s) = self.get_state_mut() {
+                    s.log_level = Some(level);
+                }
                 for t in self
                     .tasks
-                    .extract_if(.., |t| t.command() == PlugCommand::TurnOff)
+                    .extract_if(.., |t| matches!(t.command(), PlugCommand::SetLogLevel(_)))
                 {
+                    let success = *t.command() == PlugCommand::SetLogLevel(level);
+                    t.complete(success);
+                }
+                ok!()
+            }
+            (Some(Mp::IdentifyAck { seconds }), _) => {
+                for t in self.tasks.extract_if(.., |t| {
+                    matches!(t.command(), PlugCommand::Identify { seconds: s } if *s == seconds)
+                }) {
                     t.complete(true);
                 }
                 ok!()
             }
-            (Some(Mp::TurnOnAck), _) | (Some(Mp::TurnOnNotify), _) => {
-                self.get_state_mut().unwrap().power_state = crate::PowerState::On;
+            (Some(Mp::ConfigReport { config }), _) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    // the timers below trust these to be in range
+                    s.config = Some(config.clamped().into());
+                }
+                for t in self.tasks.extract_if(.., |t| {
+                    matches!(
+                        t.command(),
+                        PlugCommand::SetConfig(_) | PlugCommand::GetConfig
+                    )
+                }) {
+                    let success = match t.command() {
+                        PlugCommand::SetConfig(c) => *c == config,
+                        _ => true,
+                    };
+                    t.complete(success);
+                }
+                ok!()
+            }
+            (Some(Mp::RebootAck), _) => {
+                info!("Plug {:?} is rebooting", self.plug_id);
                 for t in self
                     .tasks
-                    .extract_if(.., |t| t.command() == PlugCommand::TurnOn)
+                    .extract_if(.., |t| *t.command() == PlugCommand::Reboot)
                 {
                     t.complete(true);
                 }
                 ok!()
             }
-            (Some(Mp::StatusResp { is_on }), _) => {
-                self.get_state_mut().unwrap().power_state = if is_on {
-                    PowerState::On
-                } else {
-                    PowerState::Off
-                };
+            (Some(Mp::FactoryResetChallenge { token }), _) => {
+                if let Some(mut s) = self.get_state_mut() {
+                    s.reset_token = Some(token);
+                }
                 for t in self
                     .tasks
-                    .extract_if(.., |t| t.command() == PlugCommand::QueryState)
+                    .extract_if(.., |t| *t.command() == PlugCommand::FactoryResetRequest)
                 {
                     t.complete(true);
                 }
                 ok!()
             }
+            (Some(Mp::FactoryResetAck { id }), _) => {
+                warn!(
+                    "Plug {:?} is wiping itself, it will come back as {id}",
+                    self.plug_id
+                );
+                if let Some(mut s) = self.get_state_mut() {
+                    s.reset_token = None;
+                    s.replaced_by = Some(id.into());
+                }
+                for t in self.tasks.extract_if(.., |t| {
+                    matches!(t.command(), PlugCommand::FactoryReset { .. })
+                }) {
+                    t.complete(true);
+                }
+                ok!()
+            }
+            (Some(Mp::FactoryResetRefused), _) => {
+                warn!("Plug {:?} refused the factory reset token", self.plug_id);
+                if let Some(mut s) = self.get_state_mut() {
+                    s.reset_token = None;
+                }
+                for t in self.tasks.extract_if(.., |t| {
+                    matches!(t.command(), PlugCommand::FactoryReset { .. })
+                }) {
+                    t.complete(false);
+                }
+                ok!()
+            }
+            (Some(Mp::CrashReport { report }), _) => {
+                warn!(
+                    "Plug {:?} crashed at {}:{}: {}",
+                    self.plug_id, report.file, report.line, report.message
+                );
+                if let Some(id) = self.plug_id {
+                    self.shared_state.add_crash(id, (&report).into());
+                }
+                ok!(Mp::CrashAck)
+            }
+            (Some(Mp::OtaStatus { image_id, status }), _) => {
+                info!(
+                    "Plug {:?} update to image {image_id}: {status:?}",
+                    self.plug_id
+                );
+                if let Some(mut s) = self.get_state_mut() {
+                    s.ota = Some(OtaProgress {
+                        image_id,
+                        state: status.into(),
+                        updated: Utc::now(),
+                    });
+                }
+                // the plug only answers OtaBegin with Accepted or Busy
+                if matches!(status, OtaStatus::Accepted | OtaStatus::Busy) {
+                    for t in self
+                        .tasks
+                        .extract_if(.., |t| *t.command() == PlugCommand::Ota { image_id })
+                    {
+                        t.complete(status == OtaStatus::Accepted);
+                    }
+                }
+                ok!()
+            }
             (Some(m), Cs::Pinging(_d)) => {
                 warn!("Unhandled message: {m:?}");
                 ok!()
@@ -360,11 +946,11 @@ impl BrokerConnection {
     }
 }
 
-async fn worker_task(rx: MsgRx, sink: BrokerSink, addr: SocketAddr, shared_state: SharedState) {
-    let mut conn = BrokerConnection::new(sink, addr, rx, shared_state);
+async fn worker_task(rx: MsgRx, outbox: Outbox, peer: Peer, shared_state: SharedState) {
+    let mut conn = BrokerConnection::new(outbox, peer, rx, shared_state);
     loop {
         if let Err(e) = conn.recv().await {
-            warn!("Connection with {addr} errored: {e}");
+            warn!("Connection with {peer} errored: {e}");
             break;
         }
     }
@@ -383,9 +969,11 @@ impl std::error::Error for ConnectionError {}
 
 impl Drop for BrokerConnection {
     fn drop(&mut self) {
-        // race condition pinto cu bosta mijo
-        // if let Some(plug_id) = &self.plug_id {
-        //     self.shared_state.plugs.remove(plug_id);
-        // }
+        // the state stays for the API, and a newer connection for the same
+        // plug may own it already, which the session check takes care of
+        if let Some(plug_id) = self.plug_id {
+            self.shared_state
+                .set_availability(plug_id, self.session, Availability::Offline);
+        }
     }
 }
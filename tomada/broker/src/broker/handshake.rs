@@ -0,0 +1,190 @@
+//! Connection handshake and limits on new sessions
+//!
+//! A `Conn` is answered with a [Cookie] and nothing else: no channel, task or
+//! plug state exists until the plug echoes the cookie in `ConnConfirm`. The
+//! cookie is an HMAC over the source address, plug id and issue time, so the
+//! broker can check it without remembering it, and a spoofed source address
+//! never gets to see one.
+//!
+//! Confirmed handshakes still go through [SessionLimits], which caps how fast
+//! new sessions are opened overall and per network prefix.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use common::Cookie;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// How long a cookie can be echoed back, in seconds
+pub const COOKIE_LIFETIME: u64 = 30;
+
+/// New sessions per second, across all plugs
+pub const GLOBAL_RATE: f64 = 20.;
+pub const GLOBAL_BURST: f64 = 100.;
+/// New sessions per second from one prefix, see [prefix]
+pub const PREFIX_RATE: f64 = 1.;
+pub const PREFIX_BURST: f64 = 32.;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and checks handshake cookies with a secret that lives as long as
+/// the broker
+pub struct Cookies {
+    secret: [u8; 32],
+}
+
+impl Default for Cookies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cookies {
+    pub fn new() -> Self {
+        Self {
+            secret: rand::random(),
+        }
+    }
+
+    fn hmac(&self, addr: SocketAddr, id: Uuid, issued: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(id.as_bytes());
+        mac.update(&issued.to_be_bytes());
+        mac
+    }
+
+    /// Cookie for `id` connecting from `addr` at `now`, in Unix seconds
+    pub fn issue(&self, addr: SocketAddr, id: Uuid, now: u64) -> Cookie {
+        let tag = self.hmac(addr, id, now).finalize().into_bytes();
+        let mut mac = [0; 16];
+        mac.copy_from_slice(&tag[..16]);
+        Cookie { issued: now, mac }
+    }
+
+    /// Whether `cookie` was issued by this broker to `id` at `addr`, and is
+    /// still fresh at `now`
+    pub fn verify(&self, addr: SocketAddr, id: Uuid, cookie: &Cookie, now: u64) -> bool {
+        now.checked_sub(cookie.issued)
+            .is_some_and(|age| age <= COOKIE_LIFETIME)
+            && self
+                .hmac(addr, id, cookie.issued)
+                .verify_truncated_left(&cookie.mac)
+                .is_ok()
+    }
+}
+
+/// Network a plug connects from: the /24 for IPv4 and the /56 for IPv6,
+/// what a single home or small office usually gets
+pub fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[7..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+}
+
+/// Why a new session was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Global,
+    Prefix(IpAddr),
+}
+
+impl std::fmt::Display for Limited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limited::Global => f.write_str("too many new sessions"),
+            Limited::Prefix(prefix) => write!(f, "too many new sessions from {prefix}"),
+        }
+    }
+}
+
+/// Token buckets for new sessions, one shared and one per [prefix]
+#[derive(Debug)]
+pub struct SessionLimits {
+    global: Bucket,
+    prefixes: HashMap<IpAddr, Bucket>,
+}
+
+impl SessionLimits {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            global: Bucket::full(GLOBAL_BURST, now),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for a session from `addr`, unless a limit was reached
+    pub fn admit(&mut self, addr: SocketAddr, now: Instant) -> Result<(), Limited> {
+        let prefix = prefix(addr.ip());
+        self.global.refill(GLOBAL_RATE, GLOBAL_BURST, now);
+        let bucket = self
+            .prefixes
+            .entry(prefix)
+            .or_insert(Bucket::full(PREFIX_BURST, now));
+        bucket.refill(PREFIX_RATE, PREFIX_BURST, now);
+
+        if self.global.tokens < 1. {
+            Err(Limited::Global)
+        } else if bucket.tokens < 1. {
+            Err(Limited::Prefix(prefix))
+        } else {
+            self.global.tokens -= 1.;
+            bucket.tokens -= 1.;
+            Ok(())
+        }
+    }
+
+    /// Forgets prefixes whose bucket has refilled
+    pub fn prune(&mut self, now: Instant) {
+        let full = Duration::from_secs_f64(PREFIX_BURST / PREFIX_RATE);
+        self.prefixes
+            .retain(|_, b| now.saturating_duration_since(b.last) < full);
+    }
+
+    /// Prefixes being tracked
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+}
@@ -0,0 +1,83 @@
+//! Bookkeeping of addresses sending datagrams that don't decode
+//!
+//! Every malformed datagram counts against its source address. An address
+//! that sends [MALFORMED_LIMIT] of them within [MALFORMED_WINDOW] is ignored
+//! for [IGNORE_FOR], so a misbehaving host can't keep the broker busy logging
+//! its garbage and handling whatever it sends next. Datagrams are decoded
+//! before they get here, ignoring only skips what comes after.
+//!
+//! UDP sources are easy to spoof, so the broker never ignores an address with
+//! a live session, forging a plug's address can't cut it off.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+pub const MALFORMED_LIMIT: u32 = 5;
+pub const MALFORMED_WINDOW: Duration = Duration::from_secs(60);
+pub const IGNORE_FOR: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Default)]
+pub struct IngressFilter {
+    offenders: HashMap<SocketAddr, Offender>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Offender {
+    /// Malformed datagrams since `first`
+    errors: u32,
+    first: Instant,
+    ignored_until: Option<Instant>,
+}
+
+impl IngressFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether datagrams from `addr`, received at `now`, should be handled
+    pub fn admits(&self, addr: SocketAddr, now: Instant) -> bool {
+        self.offenders
+            .get(&addr)
+            .and_then(|o| o.ignored_until)
+            .is_none_or(|until| now >= until)
+    }
+
+    /// Counts a malformed datagram from `addr`, returning how many it sent in
+    /// the current window. Reaching [MALFORMED_LIMIT] gets it ignored.
+    pub fn malformed(&mut self, addr: SocketAddr, now: Instant) -> u32 {
+        let offender = self.offenders.entry(addr).or_insert(Offender {
+            errors: 0,
+            first: now,
+            ignored_until: None,
+        });
+        if now.duration_since(offender.first) >= MALFORMED_WINDOW {
+            offender.errors = 0;
+            offender.first = now;
+        }
+        offender.errors += 1;
+        if offender.errors >= MALFORMED_LIMIT {
+            offender.ignored_until = Some(now + IGNORE_FOR);
+        }
+        offender.errors
+    }
+
+    /// Forgets addresses that are neither ignored nor have a recent error
+    pub fn prune(&mut self, now: Instant) {
+        self.offenders.retain(|_, o| match o.ignored_until {
+            Some(until) => now < until,
+            None => now.duration_since(o.first) < MALFORMED_WINDOW,
+        });
+    }
+
+    /// Addresses being tracked, ignored or not
+    pub fn len(&self) -> usize {
+        self.offenders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offenders.is_empty()
+    }
+}
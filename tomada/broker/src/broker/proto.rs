@@ -6,11 +6,19 @@ use tokio_util::{
     codec::{Decoder, Encoder},
 };
 
+/// Largest datagram worth decoding, plugs serialize into a 256 byte buffer
+pub const MAX_DATAGRAM: usize = 512;
+
+/// Decodes one [PlugMessage] per datagram
+///
+/// A datagram that doesn't decode is still yielded, as an `Err` item, so the
+/// broker knows who sent it. Only socket errors end up as stream errors.
 pub struct BrokerCodec;
 
 #[derive(Debug)]
 pub enum CodecError {
     DecodeError(postcard::Error),
+    TooLarge(usize),
     Io(io::Error),
 }
 
@@ -19,6 +27,7 @@ impl Display for CodecError {
         match self {
             Self::Io(io) => write!(f, "{io}"),
             Self::DecodeError(e) => write!(f, "{e}"),
+            Self::TooLarge(len) => write!(f, "datagram too large ({len} bytes)"),
         }
     }
 }
@@ -38,7 +47,7 @@ impl From<postcard::Error> for CodecError {
 }
 
 impl Decoder for BrokerCodec {
-    type Item = PlugMessage;
+    type Item = Result<PlugMessage, CodecError>;
 
     type Error = CodecError;
 
@@ -49,13 +58,10 @@ impl Decoder for BrokerCodec {
         if src.is_empty() {
             return Ok(None);
         }
-        let msg = match postcard::from_bytes::<common::PlugMessage>(src) {
-            Ok(m) => m,
-            Err(e) => {
-                src.clear();
-                tracing::warn!("Deserialization error: {e}");
-                return Err(e.into());
-            }
+        let msg = if src.len() > MAX_DATAGRAM {
+            Err(CodecError::TooLarge(src.len()))
+        } else {
+            postcard::from_bytes::<common::PlugMessage>(src).map_err(CodecError::from)
         };
         src.clear();
         Ok(Some(msg))
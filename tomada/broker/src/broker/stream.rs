@@ -0,0 +1,288 @@
+//! TCP transport, optionally wrapped in TLS, for networks that drop UDP
+//!
+//! Messages are the same postcard [PlugMessage]s as over UDP, each prefixed
+//! with its length as a big endian u16. Every connection gets a task that
+//! hands its messages to [Broker::run](super::Broker::run) like datagrams, so
+//! a plug ends up with the same session whichever way it connects.
+//!
+//! Connections are held to the same limits as sessions: new ones go through
+//! [SessionLimits], no [prefix] keeps more than [MAX_STREAMS_PER_PREFIX] open,
+//! and one that doesn't get a `ConnAck` within [HANDSHAKE_TIMEOUT] is closed,
+//! so idle sockets can't tie up the fallback.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::{MessagePayload, PlugConfig, PlugMessage};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    select,
+    sync::{
+        Notify, Semaphore,
+        mpsc::{Sender, channel},
+    },
+    time::{Instant, timeout, timeout_at},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, Framed, LengthDelimitedCodec},
+};
+use tracing::{debug, warn};
+
+use super::{CodecError, MAX_DATAGRAM, PREFIX_BURST, SessionLimits, prefix};
+
+/// Connections open at once, past that new ones wait to be accepted
+pub const MAX_STREAMS: usize = 256;
+/// Connections open at once from one [prefix], as many as it can open in a
+/// burst
+pub const MAX_STREAMS_PER_PREFIX: usize = PREFIX_BURST as usize;
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection has to get through the plug handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the rate limits forget prefixes that went quiet
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// A connection with nothing to say for two of the longest heartbeats is gone
+const IDLE_TIMEOUT: Duration =
+    Duration::from_secs(2 * *PlugConfig::HEARTBEAT_SECS.end() as u64 + 10);
+
+/// Length prefixed [PlugMessage]s
+pub struct FrameCodec(LengthDelimitedCodec);
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self(
+            LengthDelimitedCodec::builder()
+                .length_field_type::<u16>()
+                .max_frame_length(MAX_DATAGRAM)
+                .new_codec(),
+        )
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = PlugMessage;
+
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.0.decode(src)? {
+            Some(frame) => Ok(Some(postcard::from_bytes(&frame)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<PlugMessage> for FrameCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: PlugMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let frame = postcard::to_stdvec(&item)?;
+        self.0.encode(frame.into(), dst)?;
+        Ok(())
+    }
+}
+
+/// What a connection task tells the broker
+pub(super) struct Inbound {
+    pub addr: SocketAddr,
+    /// `None` once the connection closed
+    pub msg: Option<PlugMessage>,
+    /// Goes back out on the same connection
+    pub reply: Sender<PlugMessage>,
+}
+
+/// Loads a PEM certificate chain and private key
+pub fn load_tls(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok(tls_acceptor(chain, key)?)
+}
+
+pub fn tls_acceptor(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<TlsAcceptor, tokio_rustls::rustls::Error> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Connections open per [prefix]
+#[derive(Debug, Default, Clone)]
+struct OpenStreams(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+impl OpenStreams {
+    /// Counts a connection from `addr` until the returned guard is dropped,
+    /// unless its prefix is at [MAX_STREAMS_PER_PREFIX]
+    fn open(&self, addr: SocketAddr) -> Option<OpenStream> {
+        let prefix = prefix(addr.ip());
+        let mut open = self.0.lock().unwrap();
+        let count = open.entry(prefix).or_default();
+        if *count >= MAX_STREAMS_PER_PREFIX {
+            return None;
+        }
+        *count += 1;
+        Some(OpenStream {
+            streams: self.clone(),
+            prefix,
+        })
+    }
+}
+
+struct OpenStream {
+    streams: OpenStreams,
+    prefix: IpAddr,
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        let mut open = self.streams.0.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.prefix) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.prefix);
+            }
+        }
+    }
+}
+
+pub(super) async fn accept(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    inbound: Sender<Inbound>,
+) {
+    let permits = Arc::new(Semaphore::new(MAX_STREAMS));
+    let mut limits = SessionLimits::new(std::time::Instant::now());
+    let open = OpenStreams::default();
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let accepted = select! {
+            accepted = listener.accept() => accepted,
+            _ = prune.tick() => {
+                limits.prune(std::time::Instant::now());
+                continue;
+            }
+        };
+        let (tcp, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept a connection: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if let Err(e) = limits.admit(addr, std::time::Instant::now()) {
+            debug!("Refusing a connection from {addr}: {e}");
+            continue;
+        }
+        let Some(guard) = open.open(addr) else {
+            debug!("Refusing a connection from {addr}: too many open from its network");
+            continue;
+        };
+        let _ = tcp.set_nodelay(true);
+        let tls = tls.clone();
+        let inbound = inbound.clone();
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => match timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(tcp)).await {
+                    Ok(Ok(stream)) => serve(stream, addr, inbound).await,
+                    Ok(Err(e)) => debug!("TLS handshake with {addr} failed: {e}"),
+                    Err(_) => debug!("TLS handshake with {addr} timed out"),
+                },
+                None => serve(tcp, addr, inbound).await,
+            }
+            drop(guard);
+            drop(permit);
+        });
+    }
+}
+
+async fn serve<S>(io: S, addr: SocketAddr, inbound: Sender<Inbound>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    debug!("Stream connection from {addr}");
+    let (mut sink, mut stream) = Framed::new(io, FrameCodec::default()).split();
+    let (reply, mut outgoing) = channel::<PlugMessage>(16);
+    let opened = Arc::new(Notify::new());
+    let writer = tokio::spawn({
+        let opened = opened.clone();
+        async move {
+            while let Some(msg) = outgoing.recv().await {
+                if matches!(msg.payload, MessagePayload::ConnAck { .. }) {
+                    opened.notify_one();
+                }
+                if let Err(e) = sink.send(msg).await {
+                    debug!("Failed to write to {addr}: {e}");
+                    break;
+                }
+            }
+        }
+    });
+
+    let handshake = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut open = false;
+    loop {
+        // until the broker opens a session the connection gets a few seconds
+        let deadline = if open {
+            Instant::now() + IDLE_TIMEOUT
+        } else {
+            handshake
+        };
+        let next = select! {
+            next = timeout_at(deadline, stream.next()) => next,
+            () = opened.notified(), if !open => {
+                open = true;
+                continue;
+            }
+        };
+        let msg = match next {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(Some(Err(e))) => {
+                warn!("Malformed frame from {addr}: {e}");
+                break;
+            }
+            Ok(None) => break,
+            Err(_) if open => {
+                debug!("Closing idle connection from {addr}");
+                break;
+            }
+            Err(_) => {
+                debug!("Closing connection from {addr}, no handshake in time");
+                break;
+            }
+        };
+        let inbound_msg = Inbound {
+            addr,
+            msg: Some(msg),
+            reply: reply.clone(),
+        };
+        if inbound.send(inbound_msg).await.is_err() {
+            break;
+        }
+    }
+
+    writer.abort();
+    let closed = Inbound {
+        addr,
+        msg: None,
+        reply,
+    };
+    let _ = inbound.send(closed).await;
+}
@@ -1,4 +1,4 @@
-use std::sync::LazyLock;
+use std::{path::PathBuf, sync::LazyLock};
 
 use clap::Parser;
 
@@ -8,6 +8,23 @@ pub static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
 pub struct Args {
     #[arg(long, default_value_t = 8080)]
     pub broker_port: u16,
+    /// Also accept plugs over TCP on --broker-port, for networks that drop UDP
+    #[arg(long)]
+    pub tcp: bool,
+    /// PEM certificate chain, wraps the TCP transport in TLS
+    #[arg(long, requires_all = ["tcp", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
     #[arg(long, default_value_t = 8081)]
     pub http_port: u16,
+    /// HTTP port plugs download firmware images from, if it differs from
+    /// --http-port because of port forwarding
+    #[arg(long)]
+    pub ota_http_port: Option<u16>,
+    /// Bearer token for the admin routes (reboot, factory reset), which are
+    /// disabled without it
+    #[arg(long)]
+    pub admin_token: Option<String>,
 }
@@ -2,15 +2,27 @@ pub mod api;
 mod broker;
 pub mod cli;
 
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::VecDeque,
+    ops::Deref,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
+use axum::body::Bytes;
 pub use broker::*;
-use chrono::Utc;
+use chrono::{Local, NaiveTime, TimeDelta, Timelike, Utc};
+use common::{ChannelMask, MAX_SIGNATURE, heapless};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::sync::{
+    broadcast,
     mpsc::{Receiver as MpscReceiver, Sender as MpscSender},
     oneshot::{Receiver, Sender as OneshotSender},
+    watch,
 };
 use utoipa::ToSchema;
 use uuid::Uuid;
@@ -27,11 +39,286 @@ pub enum PowerState {
     Unknown,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What the plug does with its relay after a power cut
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOnBehavior {
+    AlwaysOff,
+    AlwaysOn,
+    RestoreLast,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PlugCommand {
-    TurnOn,
-    TurnOff,
+    TurnOn { channel: u8 },
+    TurnOff { channel: u8 },
+    TurnOnFor { channel: u8, seconds: u32 },
+    TurnOffFor { channel: u8, seconds: u32 },
+    Pulse { channel: u8, millis: u32 },
     QueryState,
+    SetPowerOn(PowerOnBehavior),
+    Ota { image_id: u32 },
+    SetSchedule(common::Schedule),
+    GetSchedule,
+    SetLogLevel(LogLevel),
+    Identify { seconds: u32 },
+    Reboot,
+    FactoryResetRequest,
+    FactoryReset { token: u32 },
+    SetConfig(common::PlugConfig),
+    GetConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// Weekly schedule run by the plug itself, so it keeps working without the
+/// broker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Schedule {
+    /// Minutes added to UTC to get the local time of the entries, the broker's
+    /// current offset if missing. Daylight saving changes aren't followed.
+    #[serde(default)]
+    utc_offset: Option<i16>,
+    entries: Vec<ScheduleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleEntry {
+    days: Vec<Weekday>,
+    /// Local time, minutes precision
+    #[schema(value_type = String, example = "07:30")]
+    time: NaiveTime,
+    #[serde(default)]
+    channel: u8,
+    /// Turns the channel on if set, off otherwise
+    on: bool,
+}
+
+/// Pending revert of a timed command, kept by the plug so it happens even
+/// without the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ChannelTimer {
+    reverts_at: chrono::DateTime<Utc>,
+    reverts_to: PowerState,
+}
+
+/// Whether a plug is reachable, tracked apart from its channel states which
+/// keep their last reported value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    Online,
+    Offline,
+}
+
+/// A plug went online or offline, streamed by `/api/events`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AvailabilityEvent {
+    plug: PlugId,
+    availability: Availability,
+    since: chrono::DateTime<Utc>,
+}
+
+/// Feed of [AvailabilityEvent]s, nobody listening is fine
+#[derive(Debug, Clone)]
+pub struct AvailabilityFeed(broadcast::Sender<AvailabilityEvent>);
+
+/// Heartbeats a plug can miss before it counts as offline, the broker pings
+/// after one and gives up after another
+pub const OFFLINE_AFTER_HEARTBEATS: u64 = 2;
+
+/// Channel state requested through the API, kept apart from the state the
+/// plug reports so it can be applied once an offline plug comes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DesiredState {
+    channel: u8,
+    state: PowerState,
+    requested: chrono::DateTime<Utc>,
+    /// When the plug confirmed it, `None` while pending
+    synced: Option<chrono::DateTime<Utc>>,
+    /// Queued job that finishes once the plug is back and applies it
+    #[serde(skip)]
+    job: Option<JobId>,
+}
+
+/// Progress of a firmware update, as reported by the plug
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OtaState {
+    Accepted,
+    Busy,
+    DownloadFailed,
+    HashMismatch,
+    FlashFailed,
+    Installed,
+    Confirmed,
+    RolledBack,
+    BadSignature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct OtaProgress {
+    image_id: u32,
+    state: OtaState,
+    updated: chrono::DateTime<Utc>,
+}
+
+/// Why the plug last booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetReason {
+    PowerOn,
+    Software,
+    Watchdog,
+    Brownout,
+    DeepSleep,
+    Panic,
+    Other,
+}
+
+/// Last health report sent by the plug
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PlugHealth {
+    /// Seconds since the plug booted, as of `reported`
+    uptime: u32,
+    /// Wi-Fi signal in dBm
+    rssi: i8,
+    wifi_channel: u8,
+    /// Free heap in bytes
+    free_heap: u32,
+    reset_reason: ResetReason,
+    /// Wi-Fi reconnections since boot
+    reconnects: u32,
+    firmware: String,
+    reported: chrono::DateTime<Utc>,
+}
+
+/// Settings the plug keeps across reboots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PlugConfig {
+    /// Seconds without hearing from the other side before pinging it, 10 to
+    /// 300
+    heartbeat_secs: u16,
+    /// Highest Wi-Fi transmit power in 0.25dBm steps, 8 to 84
+    tx_power: u8,
+    led: LedBehavior,
+    /// Whether a click toggles the relays
+    button_enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LedBehavior {
+    /// Connection status, and the relay state once connected
+    Status,
+    RelayOnly,
+    /// Off except when identifying the plug
+    Off,
+}
+
+/// Changes to a [PlugConfig], missing fields are left alone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PlugConfigPatch {
+    heartbeat_secs: Option<u16>,
+    tx_power: Option<u8>,
+    led: Option<LedBehavior>,
+    button_enabled: Option<bool>,
+}
+
+/// Panic reported by a plug after rebooting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct CrashEntry {
+    message: String,
+    file: String,
+    line: u32,
+    /// Return addresses, innermost first, for `addr2line` against the
+    /// firmware ELF
+    backtrace: Vec<String>,
+    /// Seconds between boot and the panic
+    uptime: u32,
+    received: chrono::DateTime<Utc>,
+}
+
+/// Which lines a plug sends, from the least to the most verbose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct LogEntry {
+    level: LogLevel,
+    /// Milliseconds since the plug booted
+    uptime: u64,
+    message: String,
+    /// Lines the plug couldn't send before this one
+    dropped: u16,
+    received: chrono::DateTime<Utc>,
+}
+
+/// Log lines kept per plug, older ones are dropped
+pub const MAX_LOG_LINES: usize = 500;
+
+/// Recent log lines of a plug, and a feed of new ones for tailing
+#[derive(Debug)]
+pub struct PlugLogs {
+    lines: VecDeque<LogEntry>,
+    tx: broadcast::Sender<LogEntry>,
+}
+
+/// Crash reports kept per plug, older ones are dropped
+pub const MAX_CRASHES: usize = 16;
+
+/// Finished jobs are forgotten after this long
+pub const JOB_RETENTION: TimeDelta = TimeDelta::minutes(10);
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for the plug to acknowledge
+    Pending,
+    /// The plug is offline, the desired state is applied when it reconnects
+    Queued,
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+/// Command sent through the API, tracked until the plug acknowledges it
+#[derive(Debug)]
+pub struct Job {
+    plug: PlugId,
+    command: PlugCommand,
+    created: chrono::DateTime<Utc>,
+    finished: Option<chrono::DateTime<Utc>>,
+    state: watch::Sender<JobState>,
+}
+
+/// Firmware image uploaded through the API, served to plugs over HTTP
+#[derive(Debug, Clone)]
+pub struct FirmwareImage {
+    data: Bytes,
+    sha256: [u8; 32],
+    /// Made by whoever built the image, the broker only passes it on
+    signature: heapless::Vec<u8, MAX_SIGNATURE>,
+    uploaded: chrono::DateTime<Utc>,
 }
 
 #[derive(Debug)]
@@ -45,14 +332,51 @@ pub type TaskRx = MpscReceiver<PlugTask>;
 
 #[derive(Debug, Clone)]
 pub struct PlugState {
+    /// Connection that owns this state, older ones can't change its
+    /// availability anymore
+    session: u64,
+    availability: Availability,
+    /// When `availability` last changed
+    since: chrono::DateTime<Utc>,
+    /// When the current connection started
+    connected: chrono::DateTime<Utc>,
     last_seen: chrono::DateTime<Utc>,
-    power_state: PowerState,
+    /// One entry per relay, empty until the plug reports how many it has
+    channels: Vec<PowerState>,
+    /// Timed commands running on each channel
+    timers: Vec<Option<ChannelTimer>>,
+    /// Reported by the plug when it connects
+    power_on: Option<PowerOnBehavior>,
+    /// Last firmware update status
+    ota: Option<OtaProgress>,
+    /// Last schedule reported by the plug
+    schedule: Option<Schedule>,
+    health: Option<PlugHealth>,
+    /// Confirmed by the plug, it boots with `warn`
+    log_level: Option<LogLevel>,
+    /// Reported by the plug when it connects
+    config: Option<PlugConfig>,
+    /// Last factory reset token handed out by the plug
+    reset_token: Option<u32>,
+    /// ID the plug comes back with after a factory reset
+    replaced_by: Option<PlugId>,
     task_tx: TaskTx,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct SharedState {
     plugs: Arc<DashMap<PlugId, PlugState>>,
+    firmware: Arc<DashMap<u32, FirmwareImage>>,
+    /// Kept apart from [PlugState] so they outlive reconnections
+    crashes: Arc<DashMap<PlugId, Vec<CrashEntry>>>,
+    logs: Arc<DashMap<PlugId, PlugLogs>>,
+    /// One entry per channel, sorted, only for channels switched through
+    /// the API
+    desired: Arc<DashMap<PlugId, Vec<DesiredState>>>,
+    jobs: Arc<DashMap<JobId, Job>>,
+    next_job: Arc<AtomicU64>,
+    next_session: Arc<AtomicU64>,
+    availability: AvailabilityFeed,
 }
 
 impl From<Uuid> for PlugId {
@@ -61,6 +385,480 @@ impl From<Uuid> for PlugId {
     }
 }
 
+impl From<common::PowerOnBehavior> for PowerOnBehavior {
+    fn from(value: common::PowerOnBehavior) -> Self {
+        match value {
+            common::PowerOnBehavior::AlwaysOff => Self::AlwaysOff,
+            common::PowerOnBehavior::AlwaysOn => Self::AlwaysOn,
+            common::PowerOnBehavior::RestoreLast => Self::RestoreLast,
+        }
+    }
+}
+
+impl From<PowerOnBehavior> for common::PowerOnBehavior {
+    fn from(value: PowerOnBehavior) -> Self {
+        match value {
+            PowerOnBehavior::AlwaysOff => Self::AlwaysOff,
+            PowerOnBehavior::AlwaysOn => Self::AlwaysOn,
+            PowerOnBehavior::RestoreLast => Self::RestoreLast,
+        }
+    }
+}
+
+impl From<common::OtaStatus> for OtaState {
+    fn from(value: common::OtaStatus) -> Self {
+        match value {
+            common::OtaStatus::Accepted => Self::Accepted,
+            common::OtaStatus::Busy => Self::Busy,
+            common::OtaStatus::DownloadFailed => Self::DownloadFailed,
+            common::OtaStatus::HashMismatch => Self::HashMismatch,
+            common::OtaStatus::FlashFailed => Self::FlashFailed,
+            common::OtaStatus::Installed => Self::Installed,
+            common::OtaStatus::Confirmed => Self::Confirmed,
+            common::OtaStatus::RolledBack => Self::RolledBack,
+            common::OtaStatus::BadSignature => Self::BadSignature,
+        }
+    }
+}
+
+impl From<common::ResetReason> for ResetReason {
+    fn from(value: common::ResetReason) -> Self {
+        match value {
+            common::ResetReason::PowerOn => Self::PowerOn,
+            common::ResetReason::Software => Self::Software,
+            common::ResetReason::Watchdog => Self::Watchdog,
+            common::ResetReason::Brownout => Self::Brownout,
+            common::ResetReason::DeepSleep => Self::DeepSleep,
+            common::ResetReason::Panic => Self::Panic,
+            common::ResetReason::Other => Self::Other,
+        }
+    }
+}
+
+impl From<&common::Health> for PlugHealth {
+    fn from(value: &common::Health) -> Self {
+        Self {
+            uptime: value.uptime,
+            rssi: value.rssi,
+            wifi_channel: value.wifi_channel,
+            free_heap: value.free_heap,
+            reset_reason: value.reset_reason.into(),
+            reconnects: value.reconnects,
+            firmware: value.firmware.to_string(),
+            reported: Utc::now(),
+        }
+    }
+}
+
+impl From<&common::CrashReport> for CrashEntry {
+    fn from(value: &common::CrashReport) -> Self {
+        Self {
+            message: value.message.to_string(),
+            file: value.file.to_string(),
+            line: value.line,
+            backtrace: value
+                .backtrace
+                .iter()
+                .map(|a| format!("{a:#010x}"))
+                .collect(),
+            uptime: value.uptime,
+            received: Utc::now(),
+        }
+    }
+}
+
+impl From<common::PlugConfig> for PlugConfig {
+    fn from(value: common::PlugConfig) -> Self {
+        Self {
+            heartbeat_secs: value.heartbeat_secs,
+            tx_power: value.tx_power,
+            led: value.led.into(),
+            button_enabled: value.button_enabled,
+        }
+    }
+}
+
+impl From<common::LedBehavior> for LedBehavior {
+    fn from(value: common::LedBehavior) -> Self {
+        match value {
+            common::LedBehavior::Status => Self::Status,
+            common::LedBehavior::RelayOnly => Self::RelayOnly,
+            common::LedBehavior::Off => Self::Off,
+        }
+    }
+}
+
+impl From<LedBehavior> for common::LedBehavior {
+    fn from(value: LedBehavior) -> Self {
+        match value {
+            LedBehavior::Status => Self::Status,
+            LedBehavior::RelayOnly => Self::RelayOnly,
+            LedBehavior::Off => Self::Off,
+        }
+    }
+}
+
+impl From<common::LogLevel> for LogLevel {
+    fn from(value: common::LogLevel) -> Self {
+        match value {
+            common::LogLevel::Error => Self::Error,
+            common::LogLevel::Warn => Self::Warn,
+            common::LogLevel::Info => Self::Info,
+            common::LogLevel::Debug => Self::Debug,
+            common::LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for common::LogLevel {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn new(line: &common::LogLine, dropped: u16) -> Self {
+        Self {
+            level: line.level.into(),
+            uptime: line.uptime,
+            message: line.message.to_string(),
+            dropped,
+            received: Utc::now(),
+        }
+    }
+}
+
+impl Default for PlugLogs {
+    fn default() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            tx: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl Default for AvailabilityFeed {
+    fn default() -> Self {
+        Self(broadcast::channel(64).0)
+    }
+}
+
+impl SharedState {
+    fn new_session(&self) -> u64 {
+        self.next_session.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn subscribe_availability(&self) -> broadcast::Receiver<AvailabilityEvent> {
+        self.availability.0.subscribe()
+    }
+
+    /// Tells the listeners about a change already made to the plug state
+    fn announce(&self, plug: PlugId, availability: Availability, since: chrono::DateTime<Utc>) {
+        tracing::info!("Plug {} is {availability:?}", plug.0);
+        let _ = self.availability.0.send(AvailabilityEvent {
+            plug,
+            availability,
+            since,
+        });
+    }
+
+    /// Changes the availability of `id`, as long as `session` still owns it
+    fn set_availability(&self, id: PlugId, session: u64, availability: Availability) {
+        let since = Utc::now();
+        {
+            let Some(mut plug) = self.plugs.get_mut(&id) else {
+                return;
+            };
+            if plug.session != session || plug.availability == availability {
+                return;
+            }
+            plug.availability = availability;
+            plug.since = since;
+        }
+        self.announce(id, availability, since);
+    }
+
+    /// Records a message from `id`, which brings it back online if the
+    /// sweeper gave up on it too early
+    fn seen(&self, id: PlugId, session: u64) {
+        if let Some(mut plug) = self.plugs.get_mut(&id)
+            && plug.session == session
+        {
+            plug.last_seen = Utc::now();
+        }
+        self.set_availability(id, session, Availability::Online);
+    }
+
+    /// Marks plugs offline once they stayed silent for too long, in case
+    /// their connection didn't notice, and forgets jobs that finished more
+    /// than [JOB_RETENTION] ago
+    pub fn sweep(&self) {
+        let now = Utc::now();
+        self.jobs
+            .retain(|_, job| job.finished.is_none_or(|f| now - f < JOB_RETENTION));
+        let silent: Vec<_> = self
+            .plugs
+            .iter()
+            .filter(|plug| {
+                let heartbeat = PlugConfig::heartbeat(plug.config);
+                let limit = TimeDelta::seconds((OFFLINE_AFTER_HEARTBEATS * heartbeat + 5) as i64);
+                plug.availability == Availability::Online && now - plug.last_seen > limit
+            })
+            .map(|plug| (*plug.key(), plug.session))
+            .collect();
+        for (id, session) in silent {
+            self.set_availability(id, session, Availability::Offline);
+        }
+    }
+
+    fn add_log(&self, id: PlugId, entry: LogEntry) {
+        let mut logs = self.logs.entry(id).or_default();
+        if logs.lines.len() >= MAX_LOG_LINES {
+            logs.lines.pop_front();
+        }
+        logs.lines.push_back(entry.clone());
+        // nobody tailing is fine
+        let _ = logs.tx.send(entry);
+    }
+
+    /// Records `state` as the one `channel` should have, pending until the
+    /// plug confirms it
+    ///
+    /// Refused for plugs that never connected, made up ids would pile up.
+    #[must_use]
+    fn desire(&self, id: PlugId, channel: u8, state: PowerState) -> bool {
+        if !self.plugs.contains_key(&id) {
+            return false;
+        }
+        let mut desired = self.desired.entry(id).or_default();
+        let entry = DesiredState {
+            channel,
+            state,
+            requested: Utc::now(),
+            synced: None,
+            job: None,
+        };
+        match desired.binary_search_by_key(&channel, |d| d.channel) {
+            Ok(i) => {
+                if let Some(job) = desired[i].job {
+                    self.finish_job(job, JobState::Failed);
+                }
+                desired[i] = entry;
+            }
+            Err(i) => desired.insert(i, entry),
+        }
+        true
+    }
+
+    /// Ties a queued job to the desired state of `channel`
+    fn queue_job(&self, id: PlugId, channel: u8, job: JobId) {
+        if let Some(mut desired) = self.desired.get_mut(&id)
+            && let Some(d) = desired.iter_mut().find(|d| d.channel == channel)
+        {
+            d.job = Some(job);
+        }
+    }
+
+    /// Marks the desired state of `channel` as applied if it's `state`
+    fn desired_synced(&self, id: PlugId, channel: u8, state: PowerState) {
+        if let Some(mut desired) = self.desired.get_mut(&id)
+            && let Some(d) = desired
+                .iter_mut()
+                .find(|d| d.channel == channel && d.state == state && d.synced.is_none())
+        {
+            d.synced = Some(Utc::now());
+        }
+    }
+
+    /// Forgets the desired state of `channel`, replaced by a timed command
+    fn undesire(&self, id: PlugId, channel: u8) {
+        if let Some(mut desired) = self.desired.get_mut(&id) {
+            desired.retain(|d| {
+                if d.channel == channel
+                    && let Some(job) = d.job
+                {
+                    self.finish_job(job, JobState::Failed);
+                }
+                d.channel != channel
+            });
+        }
+    }
+
+    /// Desired states the plug hasn't confirmed yet, with their queued jobs
+    fn pending(&self, id: PlugId) -> Vec<(u8, PowerState, Option<JobId>)> {
+        self.desired
+            .get(&id)
+            .map(|desired| {
+                desired
+                    .iter()
+                    .filter(|d| d.synced.is_none())
+                    .map(|d| (d.channel, d.state, d.job))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn add_job(&self, plug: PlugId, command: PlugCommand, state: JobState) -> JobId {
+        let now = Utc::now();
+        let id = self.next_job.fetch_add(1, Ordering::Relaxed);
+        self.jobs.insert(
+            id,
+            Job {
+                plug,
+                command,
+                created: now,
+                finished: state.finished().then_some(now),
+                state: watch::Sender::new(state),
+            },
+        );
+        id
+    }
+
+    /// Settles a job, unless it already was
+    fn finish_job(&self, id: JobId, state: JobState) {
+        if let Some(mut job) = self.jobs.get_mut(&id)
+            && !job.state.borrow().finished()
+        {
+            job.finished = Some(Utc::now());
+            job.state.send_replace(state);
+        }
+    }
+
+    /// Moves a queued job to pending once its command is on the way, `false`
+    /// if it wasn't queued anymore
+    fn dequeue_job(&self, id: JobId) -> bool {
+        self.jobs.get(&id).is_some_and(|job| {
+            job.state.send_if_modified(|state| {
+                let queued = *state == JobState::Queued;
+                if queued {
+                    *state = JobState::Pending;
+                }
+                queued
+            })
+        })
+    }
+
+    fn add_crash(&self, id: PlugId, crash: CrashEntry) {
+        let mut crashes = self.crashes.entry(id).or_default();
+        if crashes.len() >= MAX_CRASHES {
+            crashes.remove(0);
+        }
+        crashes.push(crash);
+    }
+}
+
+impl PlugConfig {
+    /// Converts to the plug's format, `None` if a value is out of range
+    pub fn to_common(self) -> Option<common::PlugConfig> {
+        let config = common::PlugConfig {
+            heartbeat_secs: self.heartbeat_secs,
+            tx_power: self.tx_power,
+            led: self.led.into(),
+            button_enabled: self.button_enabled,
+        };
+        (config.clamped() == config).then_some(config)
+    }
+
+    /// Seconds the broker waits before pinging the plug
+    pub fn heartbeat(config: Option<Self>) -> u64 {
+        let range = common::PlugConfig::HEARTBEAT_SECS;
+        config
+            .map(|c| c.heartbeat_secs.clamp(*range.start(), *range.end()))
+            .unwrap_or(common::PlugConfig::default().heartbeat_secs)
+            .into()
+    }
+}
+
+impl PlugConfigPatch {
+    pub fn apply(self, config: PlugConfig) -> PlugConfig {
+        PlugConfig {
+            heartbeat_secs: self.heartbeat_secs.unwrap_or(config.heartbeat_secs),
+            tx_power: self.tx_power.unwrap_or(config.tx_power),
+            led: self.led.unwrap_or(config.led),
+            button_enabled: self.button_enabled.unwrap_or(config.button_enabled),
+        }
+    }
+}
+
+impl Schedule {
+    /// Converts to the plug's format, `None` if it has too many entries
+    pub fn to_common(&self) -> Option<common::Schedule> {
+        let utc_offset = self
+            .utc_offset
+            .unwrap_or_else(|| (Local::now().offset().local_minus_utc() / 60) as i16);
+        let entries = self
+            .entries
+            .iter()
+            .map(|e| common::ScheduleEntry {
+                days: e.days.iter().fold(0, |days, d| days | 1 << *d as u8),
+                minute: (e.time.hour() * 60 + e.time.minute()) as u16,
+                channel: e.channel,
+                on: e.on,
+            })
+            .collect::<Vec<_>>();
+        Some(common::Schedule {
+            utc_offset,
+            entries: common::heapless::Vec::from_slice(&entries).ok()?,
+        })
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = u8> {
+        self.entries.iter().map(|e| e.channel)
+    }
+}
+
+impl From<&common::Schedule> for Schedule {
+    fn from(value: &common::Schedule) -> Self {
+        const DAYS: [Weekday; 7] = [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ];
+        Self {
+            utc_offset: Some(value.utc_offset),
+            entries: value
+                .entries
+                .iter()
+                .map(|e| ScheduleEntry {
+                    days: DAYS
+                        .into_iter()
+                        .filter(|d| e.days & 1 << *d as u8 != 0)
+                        .collect(),
+                    time: NaiveTime::from_hms_opt(
+                        (e.minute / 60).into(),
+                        (e.minute % 60).into(),
+                        0,
+                    )
+                    .unwrap_or_default(),
+                    channel: e.channel,
+                    on: e.on,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FirmwareImage {
+    pub fn new(data: Bytes, signature: heapless::Vec<u8, MAX_SIGNATURE>) -> Self {
+        Self {
+            sha256: Sha256::digest(&data).into(),
+            data,
+            signature,
+            uploaded: Utc::now(),
+        }
+    }
+}
+
 impl Deref for PlugId {
     type Target = Uuid;
 
@@ -69,6 +867,136 @@ impl Deref for PlugId {
     }
 }
 
+impl PlugState {
+    /// Whether a connection is serving this plug, the state outlives it
+    pub fn online(&self) -> bool {
+        self.availability == Availability::Online && !self.task_tx.is_closed()
+    }
+
+    pub fn availability(&self) -> (Availability, chrono::DateTime<Utc>) {
+        (self.availability, self.since)
+    }
+
+    pub fn power_state(&self, channel: u8) -> PowerState {
+        self.channels
+            .get(channel as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether `channel` may exist, the count is unknown before the first
+    /// status report
+    pub fn has_channel(&self, channel: u8) -> bool {
+        self.channels.is_empty() || (channel as usize) < self.channels.len()
+    }
+
+    fn set_power_state(&mut self, channel: u8, state: PowerState) {
+        let channel = channel as usize;
+        if channel >= self.channels.len() {
+            self.channels.resize(channel + 1, PowerState::Unknown);
+        }
+        self.channels[channel] = state;
+    }
+
+    pub fn timer(&self, channel: u8) -> Option<ChannelTimer> {
+        self.timers.get(channel as usize).copied().flatten()
+    }
+
+    fn set_timer(&mut self, channel: u8, timer: Option<ChannelTimer>) {
+        let channel = channel as usize;
+        if channel >= self.timers.len() {
+            self.timers.resize(channel + 1, None);
+        }
+        self.timers[channel] = timer;
+    }
+
+    /// Tracks a channel switching to `state`, which ends a timer reverting
+    /// to it
+    fn switched(&mut self, channel: u8, state: PowerState) {
+        self.set_power_state(channel, state);
+        if self.timer(channel).is_some_and(|t| t.reverts_to == state) {
+            self.set_timer(channel, None);
+        }
+    }
+
+    /// Replaces the timers with the remaining seconds reported by the plug,
+    /// channels must be up to date
+    fn set_remaining(&mut self, remaining: &[u32]) {
+        let now = Utc::now();
+        self.timers = self
+            .channels
+            .iter()
+            .zip(remaining)
+            .map(|(&state, &seconds)| {
+                let reverts_to = match state {
+                    PowerState::On => PowerState::Off,
+                    PowerState::Off => PowerState::On,
+                    PowerState::Unknown => return None,
+                };
+                (seconds > 0).then(|| ChannelTimer {
+                    reverts_at: now + TimeDelta::seconds(seconds.into()),
+                    reverts_to,
+                })
+            })
+            .collect();
+    }
+
+    /// Replaces every channel with a full report from the plug
+    fn set_channels(&mut self, channels: u8, on: ChannelMask) {
+        self.channels = (0..channels)
+            .map(|c| {
+                if on.is_on(c) {
+                    PowerState::On
+                } else {
+                    PowerState::Off
+                }
+            })
+            .collect();
+    }
+}
+
+impl PlugCommand {
+    /// Channel and state of commands that switch a relay
+    pub fn switch(&self) -> Option<(u8, PowerState)> {
+        match *self {
+            PlugCommand::TurnOn { channel }
+            | PlugCommand::TurnOnFor { channel, .. }
+            | PlugCommand::Pulse { channel, .. } => Some((channel, PowerState::On)),
+            PlugCommand::TurnOff { channel } | PlugCommand::TurnOffFor { channel, .. } => {
+                Some((channel, PowerState::Off))
+            }
+            _ => None,
+        }
+    }
+
+    /// Timer the plug starts once it accepts the command
+    fn timer(&self) -> Option<ChannelTimer> {
+        let (duration, reverts_to) = match *self {
+            PlugCommand::TurnOnFor { seconds, .. } => {
+                (TimeDelta::seconds(seconds.into()), PowerState::Off)
+            }
+            PlugCommand::TurnOffFor { seconds, .. } => {
+                (TimeDelta::seconds(seconds.into()), PowerState::On)
+            }
+            PlugCommand::Pulse { millis, .. } => {
+                (TimeDelta::milliseconds(millis.into()), PowerState::Off)
+            }
+            _ => return None,
+        };
+        Some(ChannelTimer {
+            reverts_at: Utc::now() + duration,
+            reverts_to,
+        })
+    }
+}
+
+impl JobState {
+    /// Whether the job won't change anymore
+    pub fn finished(self) -> bool {
+        !matches!(self, JobState::Pending | JobState::Queued)
+    }
+}
+
 impl PlugTask {
     pub fn new(command: PlugCommand) -> (Self, Receiver<bool>) {
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -81,8 +1009,8 @@ impl PlugTask {
         )
     }
 
-    pub fn command(&self) -> PlugCommand {
-        self.command
+    pub fn command(&self) -> &PlugCommand {
+        &self.command
     }
 
     pub fn complete(self, success: bool) {
@@ -1,7 +1,7 @@
 use std::net::Ipv4Addr;
 
 use axum::{body::Body, http::Request};
-use broker::{Broker, SharedState, api, cli::ARGS};
+use broker::{Broker, SharedState, api, cli::ARGS, load_tls};
 use tokio::{net::TcpListener, select};
 use tower_http::trace::TraceLayer;
 use tracing::{info, level_filters::LevelFilter};
@@ -29,6 +29,15 @@ async fn main() -> anyhow::Result<()> {
     let state = SharedState::default();
 
     let mut broker = Broker::new((Ipv4Addr::UNSPECIFIED, ARGS.broker_port), state.clone()).await;
+    if ARGS.tcp {
+        let tls = match (&ARGS.tls_cert, &ARGS.tls_key) {
+            (Some(cert), Some(key)) => Some(load_tls(cert, key)?),
+            _ => None,
+        };
+        broker
+            .listen_stream((Ipv4Addr::UNSPECIFIED, ARGS.broker_port), tls)
+            .await?;
+    }
 
     let trace_layer = TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
         tracing::info_span!(
@@ -0,0 +1,54 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{StatusCode, Uri},
+};
+use broker::{
+    PlugConfig, PlugId, SharedState,
+    api::{plug_shadow, set_state, stream_logs},
+};
+use uuid::Uuid;
+
+fn unknown() -> PlugId {
+    PlugId::from(Uuid::from_bytes(rand::random()))
+}
+
+#[tokio::test]
+async fn unknown_plugs_have_no_log_stream() {
+    let stream = stream_logs(State(SharedState::default()), Path(unknown())).await;
+    assert!(matches!(stream, Err(StatusCode::NOT_FOUND)));
+}
+
+#[tokio::test]
+async fn unknown_plugs_get_no_desired_state() {
+    let state = SharedState::default();
+    let id = unknown();
+    let uri: Uri = format!("/api/setstate?id={}&state=on", *id)
+        .parse()
+        .unwrap();
+    let query = Query::try_from_uri(&uri).unwrap();
+    let response = set_state(State(state.clone()), query).await;
+    assert!(matches!(response, Err(StatusCode::NOT_FOUND)));
+
+    let shadow = plug_shadow(State(state), Path(id)).await;
+    assert_eq!(
+        serde_json::to_value(&shadow.0).unwrap()["desired"],
+        serde_json::json!([])
+    );
+}
+
+#[test]
+fn reported_heartbeat_is_clamped() {
+    let reported = |heartbeat_secs| {
+        Some(PlugConfig::from(common::PlugConfig {
+            heartbeat_secs,
+            ..Default::default()
+        }))
+    };
+    let range = common::PlugConfig::HEARTBEAT_SECS;
+    assert_eq!(PlugConfig::heartbeat(reported(0)), *range.start() as u64);
+    assert_eq!(
+        PlugConfig::heartbeat(reported(u16::MAX)),
+        *range.end() as u64
+    );
+    assert_eq!(PlugConfig::heartbeat(reported(60)), 60);
+}
@@ -0,0 +1,186 @@
+use std::{net::SocketAddr, time::Duration};
+
+use broker::{
+    Broker, BrokerCodec, CodecError, IngressFilter, MALFORMED_LIMIT, MAX_DATAGRAM, SharedState,
+};
+use common::{DisconnectReason, MessagePayload, PlugMessage};
+use proptest::{prelude::*, strategy::ValueTree, test_runner::TestRunner};
+use tokio::{net::UdpSocket, time::timeout};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder},
+};
+
+fn decode(bytes: &[u8]) -> Option<Result<PlugMessage, CodecError>> {
+    let mut src = BytesMut::from(bytes);
+    let item = BrokerCodec.decode(&mut src).expect("decoding can't fail");
+    assert!(src.is_empty(), "the datagram must be consumed");
+    item
+}
+
+fn encode(msg: PlugMessage) -> Vec<u8> {
+    let mut dst = BytesMut::new();
+    BrokerCodec.encode(msg, &mut dst).unwrap();
+    dst.to_vec()
+}
+
+fn payload() -> impl Strategy<Value = MessagePayload> {
+    prop_oneof![
+        any::<[u8; 16]>().prop_map(|id| MessagePayload::Conn {
+            id: uuid::Uuid::from_bytes(id)
+        }),
+        any::<u64>().prop_map(|time| MessagePayload::ConnAck { time }),
+        any::<[u8; 16]>().prop_map(|data| MessagePayload::Ping { data }),
+        any::<[u8; 16]>().prop_map(|data| MessagePayload::Pong { data }),
+        any::<u8>().prop_map(|channel| MessagePayload::TurnOn { channel }),
+        any::<u8>().prop_map(|channel| MessagePayload::TurnOffAck { channel }),
+        Just(MessagePayload::QueryStatus),
+        Just(MessagePayload::Disconnect {
+            reason: DisconnectReason::Timeout
+        }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_fail_the_stream(bytes in prop::collection::vec(any::<u8>(), 1..2048)) {
+        prop_assert!(decode(&bytes).is_some());
+    }
+
+    #[test]
+    fn messages_round_trip(seq in any::<u32>(), payload in payload()) {
+        let msg = PlugMessage::new(seq, payload);
+        let bytes = encode(msg.clone());
+        prop_assert!(bytes.len() <= MAX_DATAGRAM);
+        prop_assert_eq!(decode(&bytes).unwrap().unwrap(), msg);
+    }
+
+    #[test]
+    fn oversized_datagrams_are_rejected(len in MAX_DATAGRAM + 1..4 * MAX_DATAGRAM) {
+        let bytes = encode(PlugMessage::new(1, MessagePayload::QueryStatus));
+        let mut padded = vec![0; len];
+        padded[..bytes.len()].copy_from_slice(&bytes);
+        prop_assert!(matches!(decode(&padded), Some(Err(CodecError::TooLarge(l))) if l == len));
+    }
+}
+
+#[test]
+fn empty_buffer_is_not_a_datagram() {
+    assert!(decode(&[]).is_none());
+}
+
+#[test]
+fn offenders_are_ignored_for_a_while() {
+    let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+    let other: SocketAddr = "192.0.2.2:4000".parse().unwrap();
+    let now = std::time::Instant::now();
+    let mut filter = IngressFilter::new();
+
+    for n in 1..MALFORMED_LIMIT {
+        assert_eq!(filter.malformed(addr, now), n);
+        assert!(filter.admits(addr, now));
+    }
+    filter.malformed(addr, now);
+    assert!(!filter.admits(addr, now));
+    assert!(filter.admits(other, now));
+
+    let later = now + broker::IGNORE_FOR;
+    assert!(filter.admits(addr, later));
+    filter.prune(later);
+    assert!(filter.is_empty());
+}
+
+#[test]
+fn sporadic_errors_are_forgiven() {
+    let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+    let mut now = std::time::Instant::now();
+    let mut filter = IngressFilter::new();
+
+    for _ in 0..3 * MALFORMED_LIMIT {
+        assert_eq!(filter.malformed(addr, now), 1);
+        assert!(filter.admits(addr, now));
+        now += broker::MALFORMED_WINDOW;
+    }
+}
+
+async fn recv(socket: &UdpSocket) -> Option<MessagePayload> {
+    let mut buf = [0; MAX_DATAGRAM];
+    let (len, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+        .await
+        .ok()?
+        .ok()?;
+    postcard::from_bytes::<PlugMessage>(&buf[..len])
+        .ok()
+        .map(|m| m.payload)
+}
+
+/// Goes through the handshake from `socket`, returning whether it got a
+/// `ConnAck`
+async fn connects(socket: &UdpSocket, broker: SocketAddr) -> bool {
+    let id = uuid::Uuid::from_bytes(rand::random());
+    let conn = PlugMessage::new(1, MessagePayload::Conn { id });
+    socket.send_to(&encode(conn), broker).await.unwrap();
+    let Some(MessagePayload::ConnCookie { cookie }) = recv(socket).await else {
+        return false;
+    };
+    let confirm = PlugMessage::new(2, MessagePayload::ConnConfirm { id, cookie });
+    socket.send_to(&encode(confirm), broker).await.unwrap();
+    matches!(recv(socket).await, Some(MessagePayload::ConnAck { .. }))
+}
+
+#[tokio::test]
+async fn broker_survives_garbage() {
+    let mut broker = Broker::new("127.0.0.1:0", SharedState::default()).await;
+    let addr = broker.local_addr();
+    tokio::spawn(async move { broker.run().await });
+
+    let mut runner = TestRunner::default();
+    let garbage = prop::collection::vec(any::<u8>(), 1..4 * MAX_DATAGRAM);
+    for _ in 0..8 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..32 {
+            let bytes = garbage.new_tree(&mut runner).unwrap().current();
+            socket.send_to(&bytes, addr).await.unwrap();
+        }
+    }
+
+    let plug = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    assert!(connects(&plug, addr).await);
+}
+
+#[tokio::test]
+async fn broker_ignores_offenders() {
+    let mut broker = Broker::new("127.0.0.1:0", SharedState::default()).await;
+    let addr = broker.local_addr();
+    tokio::spawn(async move { broker.run().await });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for _ in 0..MALFORMED_LIMIT {
+        socket.send_to(&[0xff; 8], addr).await.unwrap();
+    }
+    assert!(!connects(&socket, addr).await);
+}
+
+#[tokio::test]
+async fn sessions_outlive_forged_garbage() {
+    let mut broker = Broker::new("127.0.0.1:0", SharedState::default()).await;
+    let addr = broker.local_addr();
+    tokio::spawn(async move { broker.run().await });
+
+    let plug = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    assert!(connects(&plug, addr).await);
+    // anyone can send these with the plug's address
+    for _ in 0..MALFORMED_LIMIT {
+        plug.send_to(&[0xff; 8], addr).await.unwrap();
+    }
+
+    let ping = PlugMessage::new(3, MessagePayload::Ping { data: [7; 16] });
+    plug.send_to(&encode(ping), addr).await.unwrap();
+    loop {
+        match recv(&plug).await {
+            Some(MessagePayload::Pong { data }) => break assert_eq!(data, [7; 16]),
+            Some(_) => continue,
+            None => panic!("the session was cut off"),
+        }
+    }
+}
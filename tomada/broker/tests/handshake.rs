@@ -0,0 +1,158 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use broker::{
+    Broker, COOKIE_LIFETIME, Cookies, GLOBAL_BURST, Limited, PREFIX_BURST, SessionLimits,
+    SharedState, prefix,
+};
+use common::{Cookie, MessagePayload, PlugMessage};
+use tokio::{net::UdpSocket, time::timeout};
+use uuid::Uuid;
+
+const NOW: u64 = 1_750_000_000;
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn cookie_is_bound_to_address_and_plug() {
+    let cookies = Cookies::new();
+    let plug = addr("192.0.2.10:4000");
+    let id = Uuid::from_bytes([1; 16]);
+    let cookie = cookies.issue(plug, id, NOW);
+
+    assert!(cookies.verify(plug, id, &cookie, NOW));
+    assert!(!cookies.verify(addr("192.0.2.11:4000"), id, &cookie, NOW));
+    assert!(!cookies.verify(addr("192.0.2.10:4001"), id, &cookie, NOW));
+    assert!(!cookies.verify(plug, Uuid::from_bytes([2; 16]), &cookie, NOW));
+    assert!(!Cookies::new().verify(plug, id, &cookie, NOW));
+}
+
+#[test]
+fn cookie_expires() {
+    let cookies = Cookies::new();
+    let plug = addr("192.0.2.10:4000");
+    let id = Uuid::from_bytes([1; 16]);
+    let cookie = cookies.issue(plug, id, NOW);
+
+    assert!(cookies.verify(plug, id, &cookie, NOW + COOKIE_LIFETIME));
+    assert!(!cookies.verify(plug, id, &cookie, NOW + COOKIE_LIFETIME + 1));
+    // issued in the future
+    assert!(!cookies.verify(plug, id, &cookie, NOW - 1));
+}
+
+#[test]
+fn tampered_cookie_is_refused() {
+    let cookies = Cookies::new();
+    let plug = addr("192.0.2.10:4000");
+    let id = Uuid::from_bytes([1; 16]);
+    let cookie = cookies.issue(plug, id, NOW);
+
+    let mut mac = cookie.mac;
+    mac[0] ^= 1;
+    assert!(!cookies.verify(plug, id, &Cookie { mac, ..cookie }, NOW));
+    let later = Cookie {
+        issued: NOW + 1,
+        ..cookie
+    };
+    assert!(!cookies.verify(plug, id, &later, NOW + 1));
+}
+
+#[test]
+fn prefixes() {
+    assert_eq!(
+        prefix("192.0.2.77".parse().unwrap()),
+        "192.0.2.0".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        prefix("2001:db8:1:2:3::1".parse().unwrap()),
+        "2001:db8:1::".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn prefix_limit() {
+    let now = Instant::now();
+    let mut limits = SessionLimits::new(now);
+    for port in 0..PREFIX_BURST as u16 {
+        let plug = SocketAddr::new("192.0.2.1".parse().unwrap(), port);
+        assert_eq!(limits.admit(plug, now), Ok(()));
+    }
+    let plug = addr("192.0.2.200:1");
+    assert_eq!(
+        limits.admit(plug, now),
+        Err(Limited::Prefix("192.0.2.0".parse().unwrap()))
+    );
+    // other networks aren't affected
+    assert_eq!(limits.admit(addr("198.51.100.1:1"), now), Ok(()));
+    // and the bucket refills
+    assert_eq!(limits.admit(plug, now + Duration::from_secs(1)), Ok(()));
+}
+
+#[test]
+fn global_limit() {
+    let now = Instant::now();
+    let mut limits = SessionLimits::new(now);
+    for n in 0..GLOBAL_BURST as u32 {
+        let ip = IpAddr::from((0x0a00_0000 + (n << 8)).to_be_bytes());
+        assert_eq!(limits.admit(SocketAddr::new(ip, 1), now), Ok(()));
+    }
+    assert_eq!(
+        limits.admit(addr("198.51.100.1:1"), now),
+        Err(Limited::Global)
+    );
+
+    limits.prune(now + Duration::from_secs(3600));
+    assert!(limits.is_empty());
+}
+
+#[tokio::test]
+async fn unconfirmed_conn_leaves_no_state() {
+    let state = SharedState::default();
+    let mut events = state.subscribe_availability();
+    let mut broker = Broker::new("127.0.0.1:0", state).await;
+    let broker_addr = broker.local_addr();
+    tokio::spawn(async move { broker.run().await });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let id = Uuid::from_bytes([3; 16]);
+    let send = async |payload| {
+        let bytes = postcard::to_stdvec(&PlugMessage::new(1, payload)).unwrap();
+        socket.send_to(&bytes, broker_addr).await.unwrap();
+    };
+    let recv = async || {
+        let mut buf = [0; 512];
+        let (len, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(
+            postcard::from_bytes::<PlugMessage>(&buf[..len])
+                .unwrap()
+                .payload,
+        )
+    };
+
+    send(MessagePayload::Conn { id }).await;
+    let Some(MessagePayload::ConnCookie { cookie }) = recv().await else {
+        panic!("expected a cookie");
+    };
+    assert!(events.is_empty());
+
+    // a forged cookie is ignored
+    let forged = Cookie {
+        mac: [0; 16],
+        ..cookie
+    };
+    send(MessagePayload::ConnConfirm { id, cookie: forged }).await;
+    assert_eq!(recv().await, None);
+    assert!(events.is_empty());
+
+    send(MessagePayload::ConnConfirm { id, cookie }).await;
+    assert!(matches!(recv().await, Some(MessagePayload::ConnAck { .. })));
+    // the plug came online
+    assert!(events.try_recv().is_ok());
+}
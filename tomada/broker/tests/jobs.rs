@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::{StatusCode, Uri},
+};
+use broker::{
+    Broker, FrameCodec, SharedState,
+    api::{create_job, get_job},
+};
+use common::{MessagePayload, PlugMessage};
+use futures::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use tokio_util::codec::Framed;
+use uuid::Uuid;
+
+type Plug = Framed<TcpStream, FrameCodec>;
+
+async fn recv(plug: &mut Plug) -> Option<MessagePayload> {
+    let msg = timeout(Duration::from_secs(1), plug.next()).await.ok()??;
+    Some(msg.unwrap().payload)
+}
+
+/// Opens a session as `id`, returning the next sequence number to use
+async fn connect(addr: std::net::SocketAddr, id: Uuid) -> (Plug, u32) {
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut plug = Framed::new(tcp, FrameCodec::default());
+    plug.send(PlugMessage::new(1, MessagePayload::Conn { id }))
+        .await
+        .unwrap();
+    let Some(MessagePayload::ConnCookie { cookie }) = recv(&mut plug).await else {
+        panic!("expected a cookie");
+    };
+    plug.send(PlugMessage::new(
+        2,
+        MessagePayload::ConnConfirm { id, cookie },
+    ))
+    .await
+    .unwrap();
+    assert!(matches!(
+        recv(&mut plug).await,
+        Some(MessagePayload::ConnAck { .. })
+    ));
+    (plug, 3)
+}
+
+fn no_wait() -> Query<broker::api::WaitQuery> {
+    Query::try_from_uri(&Uri::from_static("/?wait=0")).unwrap()
+}
+
+#[tokio::test]
+async fn queued_job_finishes_when_the_plug_is_back() {
+    let state = SharedState::default();
+    let mut events = state.subscribe_availability();
+    let mut broker = Broker::new("127.0.0.1:0", state.clone()).await;
+    let addr = broker.listen_stream("127.0.0.1:0", None).await.unwrap();
+    tokio::spawn(async move { broker.run().await });
+
+    let id = Uuid::from_bytes(rand::random());
+    let (plug, _) = connect(addr, id).await;
+    plug.into_inner().shutdown().await.unwrap();
+    // online, then offline
+    for _ in 0..2 {
+        timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    let request = json!({"id": id, "command": {"type": "set_state", "channel": 0, "state": "on"}});
+    let (status, Json(job)) = create_job(
+        State(state.clone()),
+        no_wait(),
+        Json(serde_json::from_value(request).unwrap()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let job = serde_json::to_value(job).unwrap();
+    assert_eq!(job["state"], "queued");
+    let job_id = job["id"].as_u64().unwrap();
+
+    // still queued, not finished, a while later
+    let get = async || {
+        let (status, Json(job)) = get_job(State(state.clone()), Path(job_id), no_wait())
+            .await
+            .unwrap();
+        (status, serde_json::to_value(job).unwrap())
+    };
+    let (status, job) = get().await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(job["state"], "queued");
+    assert_eq!(job["finished"], Value::Null);
+
+    // the plug comes back and gets the command
+    let (mut plug, seq) = connect(addr, id).await;
+    assert_eq!(
+        recv(&mut plug).await,
+        Some(MessagePayload::TurnOn { channel: 0 })
+    );
+    plug.send(PlugMessage::new(
+        seq,
+        MessagePayload::TurnOnAck { channel: 0 },
+    ))
+    .await
+    .unwrap();
+
+    let (status, job) = timeout(Duration::from_secs(1), async {
+        loop {
+            let (status, job) = get().await;
+            if status == StatusCode::OK {
+                break (status, job);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(job["state"], "succeeded");
+}
@@ -0,0 +1,197 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use broker::{
+    Broker, FrameCodec, HANDSHAKE_TIMEOUT, MAX_DATAGRAM, MAX_STREAMS_PER_PREFIX, SharedState,
+    tls_acceptor,
+};
+use common::{MessagePayload, PlugMessage};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+    },
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, Framed},
+};
+use uuid::Uuid;
+
+async fn recv<S: AsyncRead + AsyncWrite + Unpin>(
+    plug: &mut Framed<S, FrameCodec>,
+) -> Option<MessagePayload> {
+    let msg = timeout(Duration::from_secs(1), plug.next()).await.ok()??;
+    Some(msg.unwrap().payload)
+}
+
+/// Goes through the handshake, returning whether it got a `ConnAck`
+async fn connects<S: AsyncRead + AsyncWrite + Unpin>(plug: &mut Framed<S, FrameCodec>) -> bool {
+    let id = Uuid::from_bytes(rand::random());
+    plug.send(PlugMessage::new(1, MessagePayload::Conn { id }))
+        .await
+        .unwrap();
+    let Some(MessagePayload::ConnCookie { cookie }) = recv(plug).await else {
+        return false;
+    };
+    plug.send(PlugMessage::new(
+        2,
+        MessagePayload::ConnConfirm { id, cookie },
+    ))
+    .await
+    .unwrap();
+    matches!(recv(plug).await, Some(MessagePayload::ConnAck { .. }))
+}
+
+async fn broker(state: SharedState, tls: Option<tokio_rustls::TlsAcceptor>) -> SocketAddr {
+    let mut broker = Broker::new("127.0.0.1:0", state).await;
+    let addr = broker.listen_stream("127.0.0.1:0", tls).await.unwrap();
+    tokio::spawn(async move { broker.run().await });
+    addr
+}
+
+#[test]
+fn frames_round_trip() {
+    let msg = PlugMessage::new(7, MessagePayload::QueryStatus);
+    let mut buf = BytesMut::new();
+    FrameCodec::default().encode(msg.clone(), &mut buf).unwrap();
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    assert_eq!(len, buf.len() - 2);
+
+    // a partial frame waits for the rest
+    let mut codec = FrameCodec::default();
+    let mut partial = buf.split_to(buf.len() - 1);
+    assert!(codec.decode(&mut partial).unwrap().is_none());
+    partial.unsplit(buf);
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(msg));
+}
+
+#[test]
+fn oversized_frames_are_refused() {
+    let mut buf = BytesMut::from(&((MAX_DATAGRAM + 1) as u16).to_be_bytes()[..]);
+    buf.extend_from_slice(&[0; MAX_DATAGRAM + 1]);
+    assert!(FrameCodec::default().decode(&mut buf).is_err());
+}
+
+#[tokio::test]
+async fn plugs_connect_over_tcp() {
+    let state = SharedState::default();
+    let mut events = state.subscribe_availability();
+    let addr = broker(state, None).await;
+
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut plug = Framed::new(tcp, FrameCodec::default());
+    assert!(connects(&mut plug).await);
+    assert!(events.try_recv().is_ok());
+
+    // closing the connection ends the session
+    plug.into_inner().shutdown().await.unwrap();
+    assert!(
+        timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .is_ok()
+    );
+}
+
+#[tokio::test]
+async fn plugs_connect_over_tls() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let der = CertificateDer::from(cert.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+    let addr = broker(
+        SharedState::default(),
+        Some(tls_acceptor(vec![der.clone()], key).unwrap()),
+    )
+    .await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(der).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let tls = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+    let mut plug = Framed::new(tls, FrameCodec::default());
+    assert!(connects(&mut plug).await);
+}
+
+#[tokio::test]
+async fn garbage_closes_the_connection() {
+    let addr = broker(SharedState::default(), None).await;
+
+    let mut tcp = TcpStream::connect(addr).await.unwrap();
+    tcp.write_all(&[0, 4, 0xff, 0xff, 0xff, 0xff])
+        .await
+        .unwrap();
+    let mut plug = Framed::new(tcp, FrameCodec::default());
+    let end = timeout(Duration::from_secs(1), plug.next()).await.unwrap();
+    assert!(matches!(end, None | Some(Err(_))));
+}
+
+/// Whether the broker closes `plug` within `wait`
+async fn closed<S: AsyncRead + AsyncWrite + Unpin>(
+    plug: &mut Framed<S, FrameCodec>,
+    wait: Duration,
+) -> bool {
+    matches!(timeout(wait, plug.next()).await, Ok(None | Some(Err(_))))
+}
+
+#[tokio::test]
+async fn silent_connections_are_closed() {
+    let addr = broker(SharedState::default(), None).await;
+
+    let mut silent = Framed::new(
+        TcpStream::connect(addr).await.unwrap(),
+        FrameCodec::default(),
+    );
+    let mut plug = Framed::new(
+        TcpStream::connect(addr).await.unwrap(),
+        FrameCodec::default(),
+    );
+    assert!(connects(&mut plug).await);
+    assert!(closed(&mut silent, HANDSHAKE_TIMEOUT + Duration::from_secs(1)).await);
+
+    // a connection that got through the handshake stays open
+    let ping = PlugMessage::new(3, MessagePayload::Ping { data: [7; 16] });
+    plug.send(ping).await.unwrap();
+    loop {
+        match recv(&mut plug).await {
+            Some(MessagePayload::Pong { data }) => break assert_eq!(data, [7; 16]),
+            Some(_) => continue,
+            None => panic!("the session was closed"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn connections_are_capped_per_network() {
+    let addr = broker(SharedState::default(), None).await;
+
+    let mut open = Vec::new();
+    for _ in 0..MAX_STREAMS_PER_PREFIX {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        open.push(Framed::new(tcp, FrameCodec::default()));
+    }
+    let mut refused = Framed::new(
+        TcpStream::connect(addr).await.unwrap(),
+        FrameCodec::default(),
+    );
+    assert!(closed(&mut refused, Duration::from_secs(1)).await);
+    assert!(!closed(&mut open[0], Duration::from_millis(100)).await);
+
+    // other networks still get in
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.1.1:0".parse().unwrap()).unwrap();
+    let mut plug = Framed::new(socket.connect(addr).await.unwrap(), FrameCodec::default());
+    assert!(connects(&mut plug).await);
+}
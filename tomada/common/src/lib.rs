@@ -3,15 +3,24 @@
 #[cfg(all(feature = "std", feature = "defmt"))]
 compile_error!("CANNOT HAVE BOTH std AND defmt ENABLED");
 
+use core::ops::RangeInclusive;
+
+pub use heapless;
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MessagePayload {
     /// Request from plug to initiate connection
     Conn {
         id: uuid::Uuid,
     },
-    ConnAck,
+    /// Carries the broker clock, so the plug can run its schedule before
+    /// having another time source
+    ConnAck {
+        /// Seconds since the Unix epoch, UTC
+        time: u64,
+    },
     Disconnect {
         reason: DisconnectReason,
     },
@@ -21,18 +30,172 @@ pub enum MessagePayload {
     Pong {
         data: [u8; 16],
     },
-    /// Request from broker to turn plug on
-    TurnOn,
-    TurnOnAck,
-    TurnOnNotify,
-    /// Request from broker to turn plug off
-    TurnOff,
-    TurnOffAck,
-    TurnOffNotify,
+    /// Request from broker to turn a channel on
+    TurnOn {
+        channel: u8,
+    },
+    TurnOnAck {
+        channel: u8,
+    },
+    TurnOnNotify {
+        channel: u8,
+    },
+    /// Request from broker to turn a channel off
+    TurnOff {
+        channel: u8,
+    },
+    TurnOffAck {
+        channel: u8,
+    },
+    TurnOffNotify {
+        channel: u8,
+    },
+    /// Request from broker to turn a channel on and back off after `seconds`,
+    /// acked with [TurnOnAck](MessagePayload::TurnOnAck)
+    TurnOnFor {
+        channel: u8,
+        seconds: u32,
+    },
+    /// Request from broker to turn a channel off and back on after `seconds`,
+    /// acked with [TurnOffAck](MessagePayload::TurnOffAck)
+    TurnOffFor {
+        channel: u8,
+        seconds: u32,
+    },
+    /// Request from broker to turn a channel on for `millis`, for gate
+    /// openers and the like, acked with [TurnOnAck](MessagePayload::TurnOnAck)
+    Pulse {
+        channel: u8,
+        millis: u32,
+    },
     /// Request from broker to query plug status
     QueryStatus,
+    /// Also sent instead of an ack when a request names a channel the plug
+    /// doesn't have
     StatusResp {
-        is_on: bool,
+        channels: u8,
+        on: ChannelMask,
+        /// Seconds until each channel reverts a timed command, rounded up,
+        /// 0 without one
+        remaining: [u32; MAX_CHANNELS],
+    },
+    /// Request from broker to change what the plug does after a power cut
+    SetPowerOnBehavior {
+        behavior: PowerOnBehavior,
+    },
+    /// Sent by the plug right after connecting and as the reply to
+    /// [SetPowerOnBehavior](MessagePayload::SetPowerOnBehavior)
+    PowerOnReport {
+        behavior: PowerOnBehavior,
+        channels: u8,
+        on: ChannelMask,
+    },
+    /// Request from broker to install the firmware image it serves over HTTP
+    /// at `/api/firmware/{image_id}` on `http_port`
+    OtaBegin {
+        image_id: u32,
+        size: u32,
+        sha256: [u8; 32],
+        http_port: u16,
+        /// DER encoded ECDSA P-256 signature over `sha256`, made with the key
+        /// the plug's firmware trusts
+        signature: Vec<u8, MAX_SIGNATURE>,
+    },
+    /// Progress of an update, sent by the plug
+    OtaStatus {
+        image_id: u32,
+        status: OtaStatus,
+    },
+    /// Sent by the plug when it can't reach an SNTP server
+    TimeRequest,
+    /// Reply to [TimeRequest](MessagePayload::TimeRequest)
+    TimeSync {
+        /// Seconds since the Unix epoch, UTC
+        time: u64,
+    },
+    /// Request from broker to replace the schedule stored on the plug
+    SetSchedule {
+        schedule: Schedule,
+    },
+    /// Request from broker for the schedule stored on the plug
+    GetSchedule,
+    /// Reply to [SetSchedule](MessagePayload::SetSchedule) and
+    /// [GetSchedule](MessagePayload::GetSchedule)
+    ScheduleReport {
+        schedule: Schedule,
+    },
+    /// Sent by the plug every minute while connected
+    HealthReport {
+        health: Health,
+    },
+    /// Sent by the plug after connecting if it rebooted because of a panic,
+    /// until the broker answers with [CrashAck](MessagePayload::CrashAck)
+    CrashReport {
+        report: CrashReport,
+    },
+    CrashAck,
+    /// Log line from the plug, `dropped` lines were lost before it because
+    /// the plug's buffer was full
+    Log {
+        line: LogLine,
+        dropped: u16,
+    },
+    /// Request from broker to change which lines the plug sends
+    SetLogLevel {
+        level: LogLevel,
+    },
+    /// Reply to [SetLogLevel](MessagePayload::SetLogLevel)
+    LogLevelReport {
+        level: LogLevel,
+    },
+    /// Request from broker to blink the LED so the plug can be found
+    Identify {
+        seconds: u32,
+    },
+    IdentifyAck {
+        seconds: u32,
+    },
+    /// Request from broker to restart the plug, it reboots after answering
+    Reboot,
+    RebootAck,
+    /// First step of a factory reset, the plug answers with a token that
+    /// must be sent back in [FactoryReset](MessagePayload::FactoryReset)
+    FactoryResetRequest,
+    /// Only valid on the same connection and for
+    /// [FACTORY_RESET_TOKEN_SECS] seconds
+    FactoryResetChallenge {
+        token: u32,
+    },
+    /// Wipes everything the plug stored, including its identity
+    FactoryReset {
+        token: u32,
+    },
+    /// The plug is wiping itself, it connects again as `id`
+    FactoryResetAck {
+        id: uuid::Uuid,
+    },
+    /// The token was wrong or expired
+    FactoryResetRefused,
+    /// Request from broker to replace the plug's settings, answered with
+    /// [ConfigReport](MessagePayload::ConfigReport)
+    SetConfig {
+        config: PlugConfig,
+    },
+    GetConfig,
+    /// Settings in use, values out of range are clamped by the plug. Also
+    /// sent by the plug after connecting
+    ConfigReport {
+        config: PlugConfig,
+    },
+    /// Broker answer to [Conn](MessagePayload::Conn). Nothing is set up until
+    /// the plug echoes the cookie back from the same address
+    ConnCookie {
+        cookie: Cookie,
+    },
+    /// Opens the session, answered with [ConnAck](MessagePayload::ConnAck)
+    ConnConfirm {
+        id: uuid::Uuid,
+        cookie: Cookie,
     },
 }
 
@@ -43,27 +206,164 @@ impl defmt::Format for MessagePayload {
             MessagePayload::Conn { id } => {
                 defmt::write!(fmt, "Conn {{ id: {} }}", &defmt::Display2Format(&id))
             }
-            MessagePayload::ConnAck => defmt::write!(fmt, "ConnAck"),
+            MessagePayload::ConnAck { time } => {
+                defmt::write!(fmt, "ConnAck {{ time: {} }}", time)
+            }
             MessagePayload::Disconnect { reason } => {
                 defmt::write!(fmt, "Disconnect {{ reason: {} }}", reason)
             }
             MessagePayload::Ping { data } => defmt::write!(fmt, "Ping {{ data: {} }}", data),
             MessagePayload::Pong { data } => defmt::write!(fmt, "Pong {{ data: {} }}", data),
-            MessagePayload::TurnOn => defmt::write!(fmt, "TurnOn"),
-            MessagePayload::TurnOnAck => defmt::write!(fmt, "TurnOnAck"),
-            MessagePayload::TurnOnNotify => defmt::write!(fmt, "TurnOnNotify"),
-            MessagePayload::TurnOff => defmt::write!(fmt, "TurnOff"),
-            MessagePayload::TurnOffAck => defmt::write!(fmt, "TurnOffAck"),
-            MessagePayload::TurnOffNotify => defmt::write!(fmt, "TurnOffNotify"),
+            MessagePayload::TurnOn { channel } => {
+                defmt::write!(fmt, "TurnOn {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOnAck { channel } => {
+                defmt::write!(fmt, "TurnOnAck {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOnNotify { channel } => {
+                defmt::write!(fmt, "TurnOnNotify {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOff { channel } => {
+                defmt::write!(fmt, "TurnOff {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOffAck { channel } => {
+                defmt::write!(fmt, "TurnOffAck {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOffNotify { channel } => {
+                defmt::write!(fmt, "TurnOffNotify {{ channel: {} }}", channel)
+            }
+            MessagePayload::TurnOnFor { channel, seconds } => defmt::write!(
+                fmt,
+                "TurnOnFor {{ channel: {}, seconds: {} }}",
+                channel,
+                seconds
+            ),
+            MessagePayload::TurnOffFor { channel, seconds } => defmt::write!(
+                fmt,
+                "TurnOffFor {{ channel: {}, seconds: {} }}",
+                channel,
+                seconds
+            ),
+            MessagePayload::Pulse { channel, millis } => {
+                defmt::write!(fmt, "Pulse {{ channel: {}, millis: {} }}", channel, millis)
+            }
             MessagePayload::QueryStatus => defmt::write!(fmt, "QueryStatus"),
-            MessagePayload::StatusResp { is_on } => {
-                defmt::write!(fmt, "StatusResp {{ is_on: {} }}", is_on)
+            MessagePayload::StatusResp {
+                channels,
+                on,
+                remaining,
+            } => defmt::write!(
+                fmt,
+                "StatusResp {{ channels: {}, on: {}, remaining: {} }}",
+                channels,
+                on,
+                remaining
+            ),
+            MessagePayload::SetPowerOnBehavior { behavior } => {
+                defmt::write!(fmt, "SetPowerOnBehavior {{ behavior: {} }}", behavior)
+            }
+            MessagePayload::PowerOnReport {
+                behavior,
+                channels,
+                on,
+            } => defmt::write!(
+                fmt,
+                "PowerOnReport {{ behavior: {}, channels: {}, on: {} }}",
+                behavior,
+                channels,
+                on
+            ),
+            MessagePayload::OtaBegin {
+                image_id,
+                size,
+                sha256,
+                http_port,
+                signature: _,
+            } => defmt::write!(
+                fmt,
+                "OtaBegin {{ image_id: {}, size: {}, sha256: {:02x}, http_port: {} }}",
+                image_id,
+                size,
+                sha256,
+                http_port
+            ),
+            MessagePayload::OtaStatus { image_id, status } => defmt::write!(
+                fmt,
+                "OtaStatus {{ image_id: {}, status: {} }}",
+                image_id,
+                status
+            ),
+            MessagePayload::TimeRequest => defmt::write!(fmt, "TimeRequest"),
+            MessagePayload::TimeSync { time } => {
+                defmt::write!(fmt, "TimeSync {{ time: {} }}", time)
+            }
+            MessagePayload::SetSchedule { schedule } => {
+                defmt::write!(fmt, "SetSchedule {{ schedule: {} }}", schedule)
+            }
+            MessagePayload::GetSchedule => defmt::write!(fmt, "GetSchedule"),
+            MessagePayload::ScheduleReport { schedule } => {
+                defmt::write!(fmt, "ScheduleReport {{ schedule: {} }}", schedule)
+            }
+            MessagePayload::HealthReport { health } => {
+                defmt::write!(fmt, "HealthReport {{ health: {} }}", health)
+            }
+            MessagePayload::CrashReport { report } => {
+                defmt::write!(fmt, "CrashReport {{ report: {} }}", report)
+            }
+            MessagePayload::CrashAck => defmt::write!(fmt, "CrashAck"),
+            MessagePayload::Log { line, dropped } => {
+                defmt::write!(fmt, "Log {{ line: {}, dropped: {} }}", line, dropped)
+            }
+            MessagePayload::SetLogLevel { level } => {
+                defmt::write!(fmt, "SetLogLevel {{ level: {} }}", level)
+            }
+            MessagePayload::LogLevelReport { level } => {
+                defmt::write!(fmt, "LogLevelReport {{ level: {} }}", level)
+            }
+            MessagePayload::Identify { seconds } => {
+                defmt::write!(fmt, "Identify {{ seconds: {} }}", seconds)
             }
+            MessagePayload::IdentifyAck { seconds } => {
+                defmt::write!(fmt, "IdentifyAck {{ seconds: {} }}", seconds)
+            }
+            MessagePayload::Reboot => defmt::write!(fmt, "Reboot"),
+            MessagePayload::RebootAck => defmt::write!(fmt, "RebootAck"),
+            MessagePayload::FactoryResetRequest => defmt::write!(fmt, "FactoryResetRequest"),
+            MessagePayload::FactoryResetChallenge { token } => {
+                defmt::write!(fmt, "FactoryResetChallenge {{ token: {} }}", token)
+            }
+            MessagePayload::FactoryReset { token } => {
+                defmt::write!(fmt, "FactoryReset {{ token: {} }}", token)
+            }
+            MessagePayload::FactoryResetAck { id } => {
+                defmt::write!(
+                    fmt,
+                    "FactoryResetAck {{ id: {} }}",
+                    &defmt::Display2Format(&id)
+                )
+            }
+            MessagePayload::FactoryResetRefused => defmt::write!(fmt, "FactoryResetRefused"),
+            MessagePayload::SetConfig { config } => {
+                defmt::write!(fmt, "SetConfig {{ config: {} }}", config)
+            }
+            MessagePayload::GetConfig => defmt::write!(fmt, "GetConfig"),
+            MessagePayload::ConfigReport { config } => {
+                defmt::write!(fmt, "ConfigReport {{ config: {} }}", config)
+            }
+            MessagePayload::ConnCookie { cookie } => {
+                defmt::write!(fmt, "ConnCookie {{ cookie: {} }}", cookie)
+            }
+            MessagePayload::ConnConfirm { id, cookie } => defmt::write!(
+                fmt,
+                "ConnConfirm {{ id: {}, cookie: {} }}",
+                &defmt::Display2Format(&id),
+                cookie
+            ),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PlugMessage {
     /// sequential ID for dropped packet detection
@@ -82,8 +382,261 @@ pub enum DisconnectReason {
     Closed,
 }
 
+/// Most relays a single plug can have
+pub const MAX_CHANNELS: usize = 8;
+
+/// Relay states of a plug, bit `n` is set when channel `n` is on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelMask(pub u8);
+
+impl ChannelMask {
+    /// Every one of the first `channels` channels on
+    pub fn all(channels: u8) -> Self {
+        Self(((1u16 << channels.min(MAX_CHANNELS as u8)) - 1) as u8)
+    }
+
+    pub fn is_on(self, channel: u8) -> bool {
+        channel < MAX_CHANNELS as u8 && self.0 & (1 << channel) != 0
+    }
+
+    pub fn set(&mut self, channel: u8, on: bool) {
+        if channel >= MAX_CHANNELS as u8 {
+            return;
+        }
+        if on {
+            self.0 |= 1 << channel;
+        } else {
+            self.0 &= !(1 << channel);
+        }
+    }
+
+    pub fn any(self) -> bool {
+        self.0 != 0
+    }
+}
+
+/// Relay state applied when the plug boots
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerOnBehavior {
+    AlwaysOff,
+    AlwaysOn,
+    /// Goes back to whatever state the relay was in before losing power
+    #[default]
+    RestoreLast,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaStatus {
+    /// Download started
+    Accepted,
+    /// Another update is already running
+    Busy,
+    DownloadFailed,
+    HashMismatch,
+    FlashFailed,
+    /// Image written and verified, rebooting into it
+    Installed,
+    /// New image booted and reached the broker, it is now permanent
+    Confirmed,
+    /// New image didn't reach the broker and the previous one was restored
+    RolledBack,
+    /// Image wasn't signed with the key the firmware trusts
+    BadSignature,
+}
+
+/// Most entries a [Schedule] can hold
+pub const MAX_SCHEDULE_ENTRIES: usize = 16;
+
+/// Weekly table of relay switches, run by the plug against its own clock
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Schedule {
+    /// Minutes added to UTC to get the local time the entries are written in
+    pub utc_offset: i16,
+    pub entries: Vec<ScheduleEntry, MAX_SCHEDULE_ENTRIES>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScheduleEntry {
+    /// Bit 0 is Monday, bit 6 is Sunday
+    pub days: u8,
+    /// Minutes since local midnight
+    pub minute: u16,
+    pub channel: u8,
+    pub on: bool,
+}
+
+impl Schedule {
+    /// Entries due at `time`, in seconds since the Unix epoch
+    pub fn due(&self, time: u64) -> impl Iterator<Item = &ScheduleEntry> {
+        let local = time.saturating_add_signed(i64::from(self.utc_offset) * 60) / 60;
+        let minute = (local % (24 * 60)) as u16;
+        // the epoch was on a Thursday
+        let weekday = (local / (24 * 60) + 3) % 7;
+        self.entries
+            .iter()
+            .filter(move |e| e.minute == minute && e.days & (1 << weekday) != 0)
+    }
+}
+
+/// Why the plug last booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    PowerOn,
+    /// Requested by the firmware, after an update or a factory reset
+    Software,
+    Watchdog,
+    Brownout,
+    DeepSleep,
+    /// Software reset after a panic, see [CrashReport]
+    Panic,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Health {
+    /// Seconds since boot
+    pub uptime: u32,
+    /// Signal of the AP the plug is connected to, in dBm
+    pub rssi: i8,
+    pub wifi_channel: u8,
+    /// Free bytes across every heap region
+    pub free_heap: u32,
+    pub reset_reason: ResetReason,
+    /// Times the Wi-Fi connection was lost and made again since boot
+    pub reconnects: u32,
+    pub firmware: heapless::String<16>,
+}
+
+/// Longest DER encoded ECDSA P-256 signature
+pub const MAX_SIGNATURE: usize = 72;
+
+/// Longest panic message kept, longer ones are cut
+pub const MAX_CRASH_MESSAGE: usize = 96;
+/// Longest source path kept, the start is cut from longer ones
+pub const MAX_CRASH_FILE: usize = 48;
+/// Most return addresses kept from the panicking stack
+pub const MAX_BACKTRACE: usize = 8;
+
+/// Last words of a plug that panicked
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CrashReport {
+    pub message: heapless::String<MAX_CRASH_MESSAGE>,
+    pub file: heapless::String<MAX_CRASH_FILE>,
+    pub line: u32,
+    /// Return addresses, innermost first, to be resolved against the ELF
+    pub backtrace: Vec<u32, MAX_BACKTRACE>,
+    /// Seconds between boot and the panic
+    pub uptime: u32,
+}
+
+/// Longest log line sent by the plug, longer ones are cut
+pub const MAX_LOG_MESSAGE: usize = 120;
+
+/// Ordered from the least to the most verbose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogLine {
+    pub level: LogLevel,
+    /// Milliseconds since boot
+    pub uptime: u64,
+    pub message: heapless::String<MAX_LOG_MESSAGE>,
+}
+
 impl PlugMessage {
     pub fn new(seq: u32, payload: MessagePayload) -> Self {
         Self { seq, payload }
     }
 }
+
+/// Handshake cookie, opaque to the plug. Only the broker that issued it can
+/// tell whether it is valid for an address and plug
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Cookie {
+    /// Seconds since the Unix epoch, UTC, when the broker issued it
+    pub issued: u64,
+    pub mac: [u8; 16],
+}
+
+/// How long a [FactoryResetChallenge](MessagePayload::FactoryResetChallenge)
+/// token is accepted
+pub const FACTORY_RESET_TOKEN_SECS: u64 = 30;
+
+/// Settings the broker can change without a firmware update, kept by the plug
+/// across reboots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PlugConfig {
+    /// Seconds without hearing from the other side before pinging it, used
+    /// by the plug and the broker alike
+    pub heartbeat_secs: u16,
+    /// Highest Wi-Fi transmit power, in 0.25dBm steps
+    pub tx_power: u8,
+    pub led: LedBehavior,
+    /// Whether a click toggles the relays, the gestures that hold the button
+    /// down keep working
+    pub button_enabled: bool,
+}
+
+/// What the status LED shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LedBehavior {
+    /// Connection status blink codes, and the relay state once connected
+    #[default]
+    Status,
+    /// Only the relay state
+    RelayOnly,
+    /// Always off, except when asked to identify the plug
+    Off,
+}
+
+impl PlugConfig {
+    pub const HEARTBEAT_SECS: RangeInclusive<u16> = 10..=300;
+    /// What the radio accepts, 2dBm to 21dBm
+    pub const TX_POWER: RangeInclusive<u8> = 8..=84;
+
+    /// Brings every value into its range
+    pub fn clamped(self) -> Self {
+        Self {
+            heartbeat_secs: self
+                .heartbeat_secs
+                .clamp(*Self::HEARTBEAT_SECS.start(), *Self::HEARTBEAT_SECS.end()),
+            tx_power: self
+                .tx_power
+                .clamp(*Self::TX_POWER.start(), *Self::TX_POWER.end()),
+            ..self
+        }
+    }
+}
+
+impl Default for PlugConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: 30,
+            // lower than the radio default, as the firmware always used
+            tx_power: 8,
+            led: LedBehavior::Status,
+            button_enabled: true,
+        }
+    }
+}
@@ -1,6 +1,26 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
 fn main() {
     linker_be_nice();
     println!("cargo::rerun-if-changed=.env");
+    generate_board();
+    // stands in for the time until the clock is set, see `link::tls`
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    println!("cargo::rustc-env=BUILD_TIME={}", now.as_secs());
+    // host builds are only used by the tests, they link against std
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+    println!(
+        "cargo:rustc-link-arg=--error-handling-script={}",
+        std::env::current_exe().unwrap().display()
+    );
     #[cfg(feature = "defmt")]
     println!("cargo:rustc-link-arg=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
@@ -53,9 +73,190 @@ fn linker_be_nice() {
 
         std::process::exit(0);
     }
+}
 
-    println!(
-        "cargo:rustc-link-arg=--error-handling-script={}",
-        std::env::current_exe().unwrap().display()
+/// Highest GPIO on the ESP32C3
+const MAX_GPIO: u8 = 21;
+const DEFAULT_BOARD: &str = "ekaza";
+/// Same as `common::MAX_CHANNELS`
+const MAX_CHANNELS: usize = 8;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Board {
+    name: String,
+    relay: Relay,
+    button: Button,
+    onboard_led: Led,
+    plug_led: Option<Led>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Relay {
+    pins: Vec<u8>,
+    active: Active,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Button {
+    pin: u8,
+    active: Active,
+    #[serde(default)]
+    pull: Pull,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Led {
+    pin: u8,
+    active: Active,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Active {
+    Low,
+    High,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Pull {
+    #[default]
+    None,
+    Up,
+    Down,
+}
+
+impl Active {
+    fn pin_state(self) -> &'static str {
+        match self {
+            Active::Low => "PinState::Low",
+            Active::High => "PinState::High",
+        }
+    }
+
+    /// esp-hal level that turns the output off
+    fn inactive_level(self) -> &'static str {
+        match self {
+            Active::Low => "esp_hal::gpio::Level::High",
+            Active::High => "esp_hal::gpio::Level::Low",
+        }
+    }
+}
+
+impl Pull {
+    fn esp_hal(self) -> &'static str {
+        match self {
+            Pull::None => "esp_hal::gpio::Pull::None",
+            Pull::Up => "esp_hal::gpio::Pull::Up",
+            Pull::Down => "esp_hal::gpio::Pull::Down",
+        }
+    }
+}
+
+/// Reads `boards/$BOARD.toml` and writes `board.rs` with the polarities for
+/// the library and `board_pins.rs` with the pin setup for the binary
+fn generate_board() {
+    println!("cargo::rerun-if-env-changed=BOARD");
+    let name = std::env::var("BOARD").unwrap_or_else(|_| DEFAULT_BOARD.to_owned());
+    let path = PathBuf::from("boards").join(format!("{name}.toml"));
+    println!("cargo::rerun-if-changed={}", path.display());
+
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read board profile {}: {e}", path.display()));
+    let board: Board = toml::from_str(&contents)
+        .unwrap_or_else(|e| panic!("invalid board profile {}: {e}", path.display()));
+
+    let mut pins = HashSet::new();
+    let all_pins = board
+        .relay
+        .pins
+        .iter()
+        .chain([&board.button.pin, &board.onboard_led.pin])
+        .chain(board.plug_led.as_ref().map(|l| &l.pin));
+    for &pin in all_pins {
+        assert!(
+            pin <= MAX_GPIO,
+            "{}: GPIO{pin} doesn't exist",
+            path.display()
+        );
+        assert!(pins.insert(pin), "{}: GPIO{pin} used twice", path.display());
+    }
+    assert!(
+        (1..=MAX_CHANNELS).contains(&board.relay.pins.len()),
+        "{}: boards need 1 to {MAX_CHANNELS} relays",
+        path.display()
     );
+
+    let plug_led_active = board.plug_led.as_ref().unwrap_or(&board.onboard_led).active;
+    let lib = format!(
+        r#"/// Board profile name
+pub const NAME: &str = {name:?};
+pub const RELAY_COUNT: usize = {relay_count};
+/// Level that closes the relays
+pub const RELAY_ACTIVE: PinState = {relay_active};
+/// Level while the button is pressed
+pub const BUTTON_ACTIVE: PinState = {button_active};
+/// Level that turns the onboard LED on
+pub const ONBOARD_LED_ACTIVE: PinState = {onboard_led_active};
+/// Level that turns the plug LED on, if there is one
+pub const PLUG_LED_ACTIVE: PinState = {plug_led_active};
+pub const HAS_PLUG_LED: bool = {has_plug_led};
+"#,
+        name = board.name,
+        relay_count = board.relay.pins.len(),
+        relay_active = board.relay.active.pin_state(),
+        button_active = board.button.active.pin_state(),
+        onboard_led_active = board.onboard_led.active.pin_state(),
+        plug_led_active = plug_led_active.pin_state(),
+        has_plug_led = board.plug_led.is_some(),
+    );
+
+    let output = |pin: u8, active: Active| {
+        format!(
+            "esp_hal::gpio::Output::new($p.GPIO{pin}, {}, esp_hal::gpio::OutputConfig::default())",
+            active.inactive_level()
+        )
+    };
+    let bin = format!(
+        r#"/// Sets up the pins of the {name} board, everything starts off
+///
+/// Returns `(onboard_led, plug_led, relays, button)`
+macro_rules! board_pins {{
+    ($p:ident) => {{
+        (
+            {onboard_led},
+            {plug_led},
+            [{relays}],
+            esp_hal::gpio::Input::new(
+                $p.GPIO{button},
+                esp_hal::gpio::InputConfig::default().with_pull({pull}),
+            ),
+        )
+    }};
+}}
+"#,
+        name = board.name,
+        onboard_led = output(board.onboard_led.pin, board.onboard_led.active),
+        plug_led = match &board.plug_led {
+            Some(led) => format!("Some({})", output(led.pin, led.active)),
+            None => "None".to_owned(),
+        },
+        relays = board
+            .relay
+            .pins
+            .iter()
+            .map(|&pin| output(pin, board.relay.active))
+            .collect::<Vec<_>>()
+            .join(", "),
+        button = board.button.pin,
+        pull = board.button.pull.esp_hal(),
+    );
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("board.rs"), lib).unwrap();
+    std::fs::write(out_dir.join("board_pins.rs"), bin).unwrap();
 }
@@ -18,7 +18,7 @@ use esp_wifi::ble::controller::BleConnector;
 use esp_wifi::wifi::WifiDevice;
 #[cfg(feature = "ble")]
 use goodwe_plug::BleHandler;
-use goodwe_plug::{App, WifiHandler, info};
+use goodwe_plug::{App, WifiHandler, board, info};
 use static_cell::StaticCell;
 #[cfg(feature = "ble")]
 use trouble_host::{Address, HostResources, prelude::DefaultPacketPool};
@@ -28,18 +28,28 @@ use {esp_backtrace as _, esp_println as _};
 
 #[cfg(not(feature = "defmt"))]
 #[panic_handler]
-fn panic_handler(_: &core::panic::PanicInfo) -> ! {
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    goodwe_plug::crash::record(info);
     esp_hal::system::software_reset()
 }
 
 extern crate alloc;
 
+include!(concat!(env!("OUT_DIR"), "/board_pins.rs"));
+
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
 struct MacAddressFmt([u8; 6]);
 
+impl core::fmt::Debug for MacAddressFmt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
 #[cfg(feature = "defmt")]
 impl defmt::Format for MacAddressFmt {
     fn format(&self, fmt: defmt::Formatter) {
@@ -73,6 +83,7 @@ async fn main(_spawner: Spawner) {
     info!("Embassy initialized!");
 
     let mut rng = esp_hal::rng::Trng::new(peripherals.RNG, peripherals.ADC1);
+    goodwe_plug::identity::init(rng.rng);
     let timer1 = TimerGroup::new(peripherals.TIMG0);
 
     static WIFI_INIT: StaticCell<EspWifiController<'static>> = StaticCell::new();
@@ -105,16 +116,23 @@ async fn main(_spawner: Spawner) {
     let ble_host = ble_stack.build();
 
     let mut stack_resources = StackResources::new();
+    let mut ap_stack_resources = StackResources::new();
+
+    info!("Board: {}", board::NAME);
+    // the relays stay open until the power-on state is worked out
+    let (onboard_led, plug_led, relays, button) = board_pins!(peripherals);
 
     let app = App::new(
-        peripherals.GPIO8,
-        peripherals.GPIO10,
-        peripherals.GPIO21,
-        peripherals.GPIO20,
+        onboard_led,
+        plug_led,
+        relays,
+        button,
         WifiHandler::new(
             wifi_controller,
             interfaces.sta,
             &mut stack_resources,
+            interfaces.ap,
+            &mut ap_stack_resources,
             rng.random() as u64 | ((rng.random() as u64) << 32),
         ),
         #[cfg(feature = "ble")]
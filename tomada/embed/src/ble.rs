@@ -1,8 +1,13 @@
 //! GRAHHHHHHHHHHHHHHHHHHHHHHH
 
 use defmt::{debug, error, info, panic};
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, lazy_lock::LazyLock, mutex::Mutex};
-use embassy_time::Duration;
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    lazy_lock::LazyLock,
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{Duration, WithTimeout};
 use esp_wifi::ble::controller::BleConnector;
 use futures::future::join;
 use static_cell::StaticCell;
@@ -15,6 +20,12 @@ pub type BlePeripheral<'b> =
     Peripheral<'b, ExternalController<BleConnector<'b>, 20>, DefaultPacketPool>;
 pub type BleRunner<'b> = Runner<'b, ExternalController<BleConnector<'b>, 20>, DefaultPacketPool>;
 
+/// How long the plug advertises after pairing is requested
+const PAIRING_WINDOW: Duration = Duration::from_secs(120);
+
+/// Starts advertising, raised by a long press on the button
+pub static PAIRING_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 pub struct BleHandler<'b> {
     peripheral: BlePeripheral<'b>,
     runner: Mutex<NoopRawMutex, BleRunner<'b>>,
@@ -87,40 +98,52 @@ impl<'b> BleHandler<'b> {
         .unwrap();
         let logic = async {
             loop {
-                let conn = loop {
-                    info!("Advertising");
-                    let adv_params = AdvertisementParameters {
-                        interval_max: Duration::from_millis(1000),
-                        interval_min: Duration::from_millis(500),
-                        ..Default::default()
-                    };
-                    let adv = self
-                        .peripheral
-                        .advertise(
-                            &adv_params,
-                            Advertisement::ConnectableScannableUndirected {
-                                adv_data: BleHandler::get_adv_data(),
-                                scan_data: &[],
-                            },
-                        )
-                        .await;
-                    info!("Connection request received");
-
-                    match adv {
-                        Ok(adv) => match adv.accept().await {
-                            Ok(conn) => match conn.with_attribute_server(&server) {
-                                Ok(conn) => break conn,
+                PAIRING_SIGNAL.wait().await;
+                info!("Entering pairing mode");
+                let conn = async {
+                    loop {
+                        info!("Advertising");
+                        let adv_params = AdvertisementParameters {
+                            interval_max: Duration::from_millis(1000),
+                            interval_min: Duration::from_millis(500),
+                            ..Default::default()
+                        };
+                        let adv = self
+                            .peripheral
+                            .advertise(
+                                &adv_params,
+                                Advertisement::ConnectableScannableUndirected {
+                                    adv_data: BleHandler::get_adv_data(),
+                                    scan_data: &[],
+                                },
+                            )
+                            .await;
+                        info!("Connection request received");
+
+                        match adv {
+                            Ok(adv) => match adv.accept().await {
+                                Ok(conn) => match conn.with_attribute_server(&server) {
+                                    Ok(conn) => break conn,
+                                    Err(e) => {
+                                        error!("Failed to advertise: {}", crate::Debug2Format(&e))
+                                    }
+                                },
                                 Err(e) => {
-                                    error!("Failed to advertise: {}", defmt::Debug2Format(&e))
+                                    error!("Failed to advertise: {}", crate::Debug2Format(&e))
                                 }
                             },
-                            Err(e) => error!("Failed to advertise: {}", defmt::Debug2Format(&e)),
-                        },
-                        Err(e) => panic!(
-                            "Failed to create BLE advertiser: {}",
-                            defmt::Debug2Format(&e)
-                        ),
+                            Err(e) => panic!(
+                                "Failed to create BLE advertiser: {}",
+                                crate::Debug2Format(&e)
+                            ),
+                        }
                     }
+                }
+                .with_timeout(PAIRING_WINDOW)
+                .await;
+                let Ok(conn) = conn else {
+                    info!("Pairing window closed");
+                    continue;
                 };
                 info!("Connection estabilished(?)");
                 handle_gatt(&server, &conn).await;
@@ -151,7 +174,7 @@ async fn handle_gatt<'s>(server: &'s Server<'_>, conn: &GattConnection<'_, 's, D
     loop {
         match conn.next().await {
             GattConnectionEvent::Disconnected { reason } => {
-                info!("BLE disconnected: {}", defmt::Debug2Format(&reason));
+                info!("BLE disconnected: {}", crate::Debug2Format(&reason));
                 break;
             }
             GattConnectionEvent::Gatt { event } => {
@@ -0,0 +1,9 @@
+//! Hardware of the plug the firmware is built for
+//!
+//! Generated by `build.rs` from `boards/$BOARD.toml` (`boards/ekaza.toml` by
+//! default). The pins themselves are set up by the `board_pins!` macro in the
+//! binary.
+
+use embedded_hal::digital::PinState;
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
@@ -0,0 +1,94 @@
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::{InputPin, PinState};
+use embedded_hal_async::digital::Wait;
+
+use crate::{
+    PinStatus, RELAY_STATUS, RelayMode, board, config, debug,
+    gesture::{ButtonAction, Gesture, GestureRecognizer},
+    info, relay,
+};
+
+pub static BUTTON_STATUS: PinStatus<ButtonEvent> = Watch::new();
+/// Button actions that need the rest of the firmware, everything except
+/// toggling the relays
+pub static BUTTON_ACTION: Signal<CriticalSectionRawMutex, ButtonAction> = Signal::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ButtonEvent {
+    Press,
+    Release,
+}
+
+/// Pin Level when plug button is pressed
+const BUTTON_PRESSED_LEVEL: PinState = board::BUTTON_ACTIVE;
+pub const DEBOUNCE_TIME: Duration = Duration::from_millis(50);
+
+pub async fn button_task<P: InputPin + Wait>(pin: &mut P) -> ! {
+    let sender = BUTTON_STATUS.sender();
+    let mut gestures = GestureRecognizer::new();
+    let mut prev_level = level(pin);
+    loop {
+        let deadline = async {
+            match gestures.deadline() {
+                Some(d) => Timer::at(d).await,
+                None => core::future::pending().await,
+            }
+        };
+        if let Either::Second(()) = select(pin.wait_for_any_edge(), deadline).await {
+            if let Some(g) = gestures.poll(Instant::now()) {
+                handle_gesture(g);
+            }
+            continue;
+        }
+
+        // debounce de pobre
+        Timer::after(DEBOUNCE_TIME).await;
+        let level = level(pin);
+        let event = if level == prev_level {
+            continue;
+        } else if level == BUTTON_PRESSED_LEVEL {
+            ButtonEvent::Press
+        } else {
+            ButtonEvent::Release
+        };
+        prev_level = level;
+        sender.send(event);
+
+        if let Some(g) = gestures.feed(event, Instant::now()) {
+            handle_gesture(g);
+        }
+    }
+}
+
+/// Reads the button, a failed read counts as released
+fn level<P: InputPin>(pin: &mut P) -> PinState {
+    match pin.is_high() {
+        Ok(high) => PinState::from(high),
+        Err(_) => !BUTTON_PRESSED_LEVEL,
+    }
+}
+
+fn handle_gesture(gesture: Gesture) {
+    let Some(action) = gesture.action() else {
+        debug!("[button] No action for {}", gesture);
+        return;
+    };
+    info!("[button] {} -> {}", gesture, action);
+
+    match action {
+        ButtonAction::ToggleRelay if !config::get().button_enabled => {
+            info!("[button] Disabled by the broker");
+        }
+        // switches every channel together, all off if any of them is on
+        ButtonAction::ToggleRelay => {
+            let mode = RelayMode::from(!RELAY_STATUS.try_get().unwrap_or_default().any());
+            for channel in 0..board::RELAY_COUNT as u8 {
+                relay::request(channel, mode);
+            }
+        }
+        action => BUTTON_ACTION.signal(action),
+    }
+}
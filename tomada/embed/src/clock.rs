@@ -0,0 +1,133 @@
+//! Wall-clock time
+//!
+//! The time comes from SNTP when it's reachable and from the broker
+//! otherwise. Between syncs it is extrapolated from [Instant], corrected by
+//! the drift measured between SNTP syncs.
+//!
+//! [Clock] doesn't read the [Instant] counter by itself, every call takes the
+//! current [Instant] so the drift logic can be driven by a fake clock.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{debug, info};
+
+/// How long an SNTP time is preferred over the broker's, which only has
+/// second resolution and an unknown network delay
+pub const SNTP_PRIORITY: Duration = Duration::from_secs(3 * 3600);
+/// Shortest time between SNTP syncs to measure drift over, shorter intervals
+/// are dominated by network jitter
+pub const MIN_DRIFT_INTERVAL: Duration = Duration::from_secs(600);
+/// Drift beyond this is taken as a bad sync, crystals are well within it
+pub const MAX_DRIFT_PPM: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimeSource {
+    Broker,
+    Sntp,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sync {
+    at: Instant,
+    /// Milliseconds since the Unix epoch at `at`
+    time: u64,
+    source: TimeSource,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    last: Option<Sync>,
+    /// How much faster real time runs than [Instant], in parts per million
+    drift_ppm: i64,
+}
+
+impl Clock {
+    pub const fn new() -> Self {
+        Self {
+            last: None,
+            drift_ppm: 0,
+        }
+    }
+
+    /// Takes `time`, in milliseconds since the Unix epoch, read from `source`
+    /// at `at`
+    ///
+    /// Returns `false` if the time was ignored in favor of a recent SNTP sync.
+    pub fn sync(&mut self, at: Instant, time: u64, source: TimeSource) -> bool {
+        if let Some(last) = self.last {
+            let elapsed = at.saturating_duration_since(last.at);
+            if source == TimeSource::Broker
+                && last.source == TimeSource::Sntp
+                && elapsed < SNTP_PRIORITY
+            {
+                return false;
+            }
+            if source == TimeSource::Sntp
+                && last.source == TimeSource::Sntp
+                && elapsed >= MIN_DRIFT_INTERVAL
+            {
+                let elapsed = elapsed.as_millis() as i64;
+                let error = time as i64 - (last.time as i64 + elapsed);
+                let measured = error * 1_000_000 / elapsed;
+                if measured.abs() <= MAX_DRIFT_PPM {
+                    // averaged so a single slow reply doesn't throw it off
+                    self.drift_ppm = (self.drift_ppm + measured) / 2;
+                }
+            }
+        }
+        self.last = Some(Sync { at, time, source });
+        true
+    }
+
+    /// Milliseconds since the Unix epoch at `at`, `None` until the first sync
+    pub fn now_millis(&self, at: Instant) -> Option<u64> {
+        let last = self.last?;
+        let elapsed = at.saturating_duration_since(last.at).as_millis() as i64;
+        let time = last.time as i64 + elapsed + elapsed * self.drift_ppm / 1_000_000;
+        Some(time.max(0) as u64)
+    }
+
+    pub fn drift_ppm(&self) -> i64 {
+        self.drift_ppm
+    }
+
+    /// Where the last accepted time came from
+    pub fn source(&self) -> Option<TimeSource> {
+        self.last.map(|l| l.source)
+    }
+}
+
+static CLOCK: BlockingMutex<CriticalSectionRawMutex, Cell<Clock>> =
+    BlockingMutex::new(Cell::new(Clock::new()));
+
+/// Sets the clock to `time`, in milliseconds since the Unix epoch
+pub fn sync(time: u64, source: TimeSource) {
+    CLOCK.lock(|c| {
+        let mut clock = c.get();
+        if clock.sync(Instant::now(), time, source) {
+            info!(
+                "[clock] Synced to {} from {}, drift {}ppm",
+                time / 1000,
+                source,
+                clock.drift_ppm()
+            );
+        } else {
+            debug!("[clock] Ignored time from {}", source);
+        }
+        c.set(clock);
+    });
+}
+
+/// Milliseconds since the Unix epoch, `None` until the clock is set
+pub fn now_millis() -> Option<u64> {
+    CLOCK.lock(|c| c.get().now_millis(Instant::now()))
+}
+
+/// Seconds since the Unix epoch, `None` until the clock is set
+pub fn now() -> Option<u64> {
+    now_millis().map(|t| t / 1000)
+}
@@ -0,0 +1,61 @@
+//! Settings pushed by the broker
+//!
+//! [set] applies a [PlugConfig] right away, [persist_task] saves it to the
+//! [Settings](crate::storage::Settings) record so it survives reboots.
+//! Tasks that need to react to a change hold a receiver of [CONFIG], the rest
+//! just read [get] when they need a value.
+
+pub use common::{LedBehavior, PlugConfig};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+
+/// Settings in use, unset until [load] runs
+pub static CONFIG: Watch<CriticalSectionRawMutex, PlugConfig, 2> = Watch::new();
+
+pub fn get() -> PlugConfig {
+    CONFIG.try_get().unwrap_or_default()
+}
+
+/// Applies `config` with its values clamped, returning what was applied
+pub fn set(config: PlugConfig) -> PlugConfig {
+    let config = config.clamped();
+    CONFIG.sender().send(config);
+    #[cfg(feature = "esp")]
+    esp::PERSIST_SIGNAL.signal(config);
+    config
+}
+
+#[cfg(feature = "esp")]
+pub use esp::{load, persist_task};
+
+#[cfg(feature = "esp")]
+mod esp {
+    use common::PlugConfig;
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+    use super::CONFIG;
+    use crate::{error, info, storage};
+
+    pub(super) static PERSIST_SIGNAL: Signal<CriticalSectionRawMutex, PlugConfig> = Signal::new();
+
+    /// Loads the stored settings, must run before anything reads them
+    pub async fn load() {
+        let config = match storage::load_settings().await {
+            Ok(s) => s.config.unwrap_or_default().clamped(),
+            Err(e) => {
+                error!("[config] Failed to load config: {}", e);
+                PlugConfig::default()
+            }
+        };
+        info!("[config] Using {}", config);
+        CONFIG.sender().send(config);
+    }
+
+    pub async fn persist_task() -> ! {
+        loop {
+            let config = PERSIST_SIGNAL.wait().await;
+            if let Err(e) = storage::update_settings(|s| s.config = Some(config)).await {
+                error!("[config] Failed to save config: {}", e);
+            }
+        }
+    }
+}
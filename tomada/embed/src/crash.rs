@@ -0,0 +1,241 @@
+//! Crash reports that survive the reboot after a panic
+//!
+//! The panic handler writes a [CrashRecord] to RTC fast memory, which isn't
+//! cleared by a software reset. On the next boot [load] picks it up and the
+//! report is sent to the broker every time the plug connects, until the
+//! broker acknowledges it.
+//!
+//! That memory holds garbage after a power cut, so records carry a magic
+//! number and a checksum.
+
+use core::fmt::{self, Write};
+
+use common::{CrashReport, MAX_BACKTRACE, MAX_CRASH_FILE, MAX_CRASH_MESSAGE};
+
+const MAGIC: u32 = 0x7a11_c0de;
+
+/// Fixed size form of a [CrashReport], written without allocating from the
+/// panic handler
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    message_len: u8,
+    file_len: u8,
+    frame_count: u8,
+    line: u32,
+    uptime: u32,
+    message: [u8; MAX_CRASH_MESSAGE],
+    file: [u8; MAX_CRASH_FILE],
+    frames: [u32; MAX_BACKTRACE],
+    checksum: u32,
+}
+
+/// Writes into a fixed buffer, dropping whatever doesn't fit without
+/// splitting a character
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - self.len;
+        let mut end = s.len().min(room);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+impl CrashRecord {
+    /// Keeps as much of `message` as fits, and the end of `file` since that's
+    /// the part that tells files apart
+    pub fn new(
+        message: impl fmt::Display,
+        file: &str,
+        line: u32,
+        uptime: u32,
+        frames: &[u32],
+    ) -> Self {
+        let mut record = Self {
+            magic: MAGIC,
+            message_len: 0,
+            file_len: 0,
+            frame_count: 0,
+            line,
+            uptime,
+            message: [0; MAX_CRASH_MESSAGE],
+            file: [0; MAX_CRASH_FILE],
+            frames: [0; MAX_BACKTRACE],
+            checksum: 0,
+        };
+
+        let mut w = Truncating {
+            buf: &mut record.message,
+            len: 0,
+        };
+        let _ = write!(w, "{message}");
+        record.message_len = w.len as u8;
+
+        let mut start = file.len().saturating_sub(MAX_CRASH_FILE);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let file = &file.as_bytes()[start..];
+        record.file[..file.len()].copy_from_slice(file);
+        record.file_len = file.len() as u8;
+
+        let frames = &frames[..frames.len().min(MAX_BACKTRACE)];
+        record.frames[..frames.len()].copy_from_slice(frames);
+        record.frame_count = frames.len() as u8;
+
+        record.checksum = record.compute_checksum();
+        record
+    }
+
+    /// FNV-1a over every field but the checksum
+    fn compute_checksum(&self) -> u32 {
+        let mut hash = 0x811c_9dc5u32;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u32;
+                hash = hash.wrapping_mul(0x0100_0193);
+            }
+        };
+        feed(&self.magic.to_le_bytes());
+        feed(&[self.message_len, self.file_len, self.frame_count]);
+        feed(&self.line.to_le_bytes());
+        feed(&self.uptime.to_le_bytes());
+        feed(&self.message);
+        feed(&self.file);
+        for f in self.frames {
+            feed(&f.to_le_bytes());
+        }
+        hash
+    }
+
+    /// The report this record holds, `None` if it's not a valid record
+    pub fn report(&self) -> Option<CrashReport> {
+        if self.magic != MAGIC || self.checksum != self.compute_checksum() {
+            return None;
+        }
+        let message = self.message.get(..self.message_len as usize)?;
+        let file = self.file.get(..self.file_len as usize)?;
+        let frames = self.frames.get(..self.frame_count as usize)?;
+        Some(CrashReport {
+            message: core::str::from_utf8(message).ok()?.try_into().ok()?,
+            file: core::str::from_utf8(file).ok()?.try_into().ok()?,
+            line: self.line,
+            backtrace: frames.try_into().ok()?,
+            uptime: self.uptime,
+        })
+    }
+
+    /// Marks the record as consumed
+    pub fn clear(&mut self) {
+        self.magic = 0;
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use esp::*;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use core::{cell::RefCell, mem::MaybeUninit, panic::PanicInfo};
+
+    use common::{CrashReport, MAX_BACKTRACE};
+    use embassy_sync::blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex};
+    use embassy_time::Instant;
+    use portable_atomic::{AtomicBool, Ordering};
+
+    use super::CrashRecord;
+    use crate::warn;
+
+    #[unsafe(link_section = ".rtc_fast.persistent")]
+    static mut RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+    static PENDING: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<CrashReport>>> =
+        BlockingMutex::new(RefCell::new(None));
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
+    /// Where the ESP32-C3 keeps its stacks, frame pointers outside it mean
+    /// the walk went astray
+    const DRAM: core::ops::Range<usize> = 0x3fc8_0000..0x3fce_0000;
+
+    /// Called from the panic handler, right before resetting
+    pub fn record(info: &PanicInfo) {
+        let (file, line) = info
+            .location()
+            .map(|l| (l.file(), l.line()))
+            .unwrap_or(("", 0));
+        let mut frames = [0u32; MAX_BACKTRACE];
+        let count = backtrace(&mut frames);
+        let record = CrashRecord::new(
+            info.message(),
+            file,
+            line,
+            Instant::now().as_secs() as u32,
+            &frames[..count],
+        );
+        // Safety: nothing else runs while panicking
+        unsafe { (&raw mut RECORD).write(MaybeUninit::new(record)) };
+    }
+
+    /// Walks the frame pointer chain, the firmware is built with
+    /// `-Cforce-frame-pointers`
+    fn backtrace(frames: &mut [u32]) -> usize {
+        let mut fp: usize;
+        // Safety: only reads a register
+        unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+        let mut count = 0;
+        while count < frames.len() && fp % 4 == 0 && DRAM.contains(&fp.wrapping_sub(8)) {
+            // Safety: checked to be in DRAM, the RISC-V frame layout keeps
+            // the return address and the caller's frame pointer right below
+            let (ra, prev) = unsafe { (*((fp - 4) as *const u32), *((fp - 8) as *const usize)) };
+            if ra == 0 {
+                break;
+            }
+            frames[count] = ra;
+            count += 1;
+            fp = prev;
+        }
+        count
+    }
+
+    /// Picks up the record left by a panic before this boot
+    pub fn load() {
+        // Safety: runs once at boot, before anything can panic again, and
+        // any bit pattern is a valid record
+        let record = unsafe { (*(&raw mut RECORD)).assume_init_mut() };
+        if let Some(report) = record.report() {
+            warn!(
+                "[crash] Rebooted after panic at {}:{}: {}",
+                report.file.as_str(),
+                report.line,
+                report.message.as_str()
+            );
+            PANICKED.store(true, Ordering::Relaxed);
+            PENDING.lock(|p| p.replace(Some(report)));
+        }
+        record.clear();
+    }
+
+    /// Whether this boot followed a panic
+    pub fn panicked() -> bool {
+        PANICKED.load(Ordering::Relaxed)
+    }
+
+    /// Report the broker hasn't acknowledged yet
+    pub fn pending() -> Option<CrashReport> {
+        PENDING.lock(|p| p.borrow().clone())
+    }
+
+    pub fn acked() {
+        PENDING.lock(|p| p.take());
+    }
+}
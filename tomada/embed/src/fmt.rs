@@ -1,75 +1,80 @@
 #![allow(unused)]
 
+/// Binds every argument once, then passes them to defmt and to
+/// [netlog](crate::netlog)
+#[doc(hidden)]
 #[macro_export]
-macro_rules! trace {
-    ($format:literal $(,$arg:expr)*) => {
+macro_rules! __log {
+    ($level:ident, $defmt:ident, $format:literal, [$($bound:ident)*], []) => {{
         #[cfg(feature = "defmt")]
         {
-            defmt::trace!($format $(,$arg)*);
+            defmt::$defmt!($format $(,$bound)*);
         }
-        #[cfg(not(feature = "defmt"))]
-        {
-            let _ = ($format $(,$arg)*);
+        $crate::netlog::record(
+            $crate::netlog::LogLevel::$level,
+            $format,
+            &[$($bound as &dyn core::fmt::Debug),*],
+        );
+    }};
+    ($level:ident, $defmt:ident, $format:literal, [$($bound:ident)*], [$head:expr $(,$rest:expr)*]) => {
+        match &$head {
+            arg => $crate::__log!($level, $defmt, $format, [$($bound)* arg], [$($rest),*]),
         }
     };
 }
 
+#[macro_export]
+macro_rules! trace {
+    ($format:literal $(,$arg:expr)*) => {
+        $crate::__log!(Trace, trace, $format, [], [$($arg),*])
+    };
+}
+
 #[macro_export]
 macro_rules! debug {
     ($format:literal $(,$arg:expr)*) => {
-        #[cfg(feature = "defmt")]
-        {
-            defmt::debug!($format $(,$arg)*);
-        }
-        #[cfg(not(feature = "defmt"))]
-        {
-            let _ = ($format $(,$arg)*);
-        }
+        $crate::__log!(Debug, debug, $format, [], [$($arg),*])
     };
 }
 
 #[macro_export]
 macro_rules! info {
     ($format:literal $(,$arg:expr)*) => {
-        #[cfg(feature = "defmt")]
-        {
-            defmt::info!($format $(,$arg)*);
-        }
-        #[cfg(not(feature = "defmt"))]
-        {
-            let _ = ($format $(,$arg)*);
-        }
+        $crate::__log!(Info, info, $format, [], [$($arg),*])
     };
 }
 
 #[macro_export]
 macro_rules! _warn {
     ($format:literal $(,$arg:expr)*) => {
-        #[cfg(feature = "defmt")]
-        {
-            defmt::warn!($format $(,$arg)*);
-        }
-        #[cfg(not(feature = "defmt"))]
-        {
-            let _ = ($format $(,$arg)*);
-        }
+        $crate::__log!(Warn, warn, $format, [], [$($arg),*])
     };
 }
 
 #[macro_export]
 macro_rules! error {
     ($format:literal $(,$arg:expr)*) => {
-        #[cfg(feature = "defmt")]
-        {
-            defmt::error!($format $(,$arg)*);
-        }
-        #[cfg(not(feature = "defmt"))]
-        {
-            let _ = ($format $(,$arg)*);
-        }
+        $crate::__log!(Error, error, $format, [], [$($arg),*])
     };
 }
 
+/// Like `defmt::Debug2Format`, but also `Debug` itself so the line can be
+/// rendered for the broker
+pub struct Debug2Format<'a, T: core::fmt::Debug + ?Sized>(pub &'a T);
+
+impl<T: core::fmt::Debug + ?Sized> core::fmt::Debug for Debug2Format<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: core::fmt::Debug + ?Sized> defmt::Format for Debug2Format<'_, T> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Debug2Format(self.0))
+    }
+}
+
 pub use _warn as warn;
 pub use debug;
 pub use error;
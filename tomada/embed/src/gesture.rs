@@ -0,0 +1,126 @@
+//! Button gesture recognition on top of debounced [ButtonEvent]s
+//!
+//! The recognizer doesn't read the clock by itself, every call takes the
+//! current [Instant] so the timing logic can be driven by a fake clock.
+
+use embassy_time::{Duration, Instant};
+
+use crate::ButtonEvent;
+
+/// Time after a release in which another press continues a multi-click
+pub const MULTI_CLICK_WINDOW: Duration = Duration::from_millis(400);
+/// Presses held at least this long are long presses instead of clicks
+pub const LONG_PRESS: Duration = Duration::from_secs(5);
+pub const VERY_LONG_PRESS: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Gesture {
+    SingleClick,
+    DoubleClick,
+    TripleClick,
+    LongPress,
+    VeryLongPress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ButtonAction {
+    ToggleRelay,
+    BlePairing,
+    StartProvisioning,
+    FactoryReset,
+}
+
+impl Gesture {
+    pub fn action(self) -> Option<ButtonAction> {
+        match self {
+            Gesture::SingleClick => Some(ButtonAction::ToggleRelay),
+            Gesture::DoubleClick => None,
+            Gesture::TripleClick => Some(ButtonAction::StartProvisioning),
+            Gesture::LongPress => Some(ButtonAction::BlePairing),
+            Gesture::VeryLongPress => Some(ButtonAction::FactoryReset),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GestureRecognizer {
+    /// When the button went down, if it's still held
+    pressed_at: Option<Instant>,
+    last_release: Option<Instant>,
+    /// Clicks waiting for the multi-click window to close
+    clicks: u8,
+}
+
+impl GestureRecognizer {
+    pub const fn new() -> Self {
+        Self {
+            pressed_at: None,
+            last_release: None,
+            clicks: 0,
+        }
+    }
+
+    /// Feeds a debounced button event, returns a gesture if it completed one
+    pub fn feed(&mut self, event: ButtonEvent, now: Instant) -> Option<Gesture> {
+        match event {
+            ButtonEvent::Press => {
+                // a press after the window closed starts a new sequence, in
+                // case poll wasn't called in time
+                let expired = self.poll(now);
+                self.pressed_at = Some(now);
+                expired
+            }
+            ButtonEvent::Release => {
+                let pressed_at = self.pressed_at.take()?;
+                let held = now.saturating_duration_since(pressed_at);
+
+                if held >= VERY_LONG_PRESS {
+                    self.reset();
+                    Some(Gesture::VeryLongPress)
+                } else if held >= LONG_PRESS {
+                    self.reset();
+                    Some(Gesture::LongPress)
+                } else {
+                    self.clicks += 1;
+                    self.last_release = Some(now);
+                    if self.clicks >= 3 {
+                        self.reset();
+                        Some(Gesture::TripleClick)
+                    } else {
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Completes pending clicks once the multi-click window is over
+    pub fn poll(&mut self, now: Instant) -> Option<Gesture> {
+        let deadline = self.deadline()?;
+        if now < deadline {
+            return None;
+        }
+        let gesture = match self.clicks {
+            1 => Gesture::SingleClick,
+            _ => Gesture::DoubleClick,
+        };
+        self.reset();
+        Some(gesture)
+    }
+
+    /// When [poll](Self::poll) should be called next, if there are pending
+    /// clicks
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.clicks == 0 || self.pressed_at.is_some() {
+            return None;
+        }
+        self.last_release.map(|r| r + MULTI_CLICK_WINDOW)
+    }
+
+    fn reset(&mut self) {
+        self.clicks = 0;
+        self.last_release = None;
+    }
+}
@@ -0,0 +1,86 @@
+//! Periodic health reports for the broker
+//!
+//! The Wi-Fi code records the link quality and reconnections as they happen,
+//! [run] bundles them with the heap usage and why the chip last reset.
+
+use core::cell::Cell;
+
+use common::{Health, MessagePayload, ResetReason};
+use embassy_sync::{
+    blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex},
+    channel::TrySendError,
+};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    rtc_cntl::{self, SocResetReason},
+    system::Cpu,
+};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::{crash, debug, wifi::WIFI_MSG_CHANNEL};
+
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// RSSI and channel of the current AP
+static LINK: BlockingMutex<CriticalSectionRawMutex, Cell<(i8, u8)>> =
+    BlockingMutex::new(Cell::new((0, 0)));
+/// Wi-Fi connections made since boot, the first one included
+static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_link(rssi: i8, channel: u8) {
+    LINK.lock(|l| l.set((rssi, channel)));
+}
+
+/// Counts a Wi-Fi connection
+pub fn connected() {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn reset_reason() -> ResetReason {
+    if crash::panicked() {
+        return ResetReason::Panic;
+    }
+    match rtc_cntl::reset_reason(Cpu::ProCpu) {
+        Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::Cpu0Mwdt0
+            | SocResetReason::Cpu0Mwdt1
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt
+            | SocResetReason::SysSuperWdt,
+        ) => ResetReason::Watchdog,
+        Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+        Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleep,
+        _ => ResetReason::Other,
+    }
+}
+
+pub fn report() -> Health {
+    let (rssi, wifi_channel) = LINK.lock(|l| l.get());
+    Health {
+        uptime: Instant::now().as_secs() as u32,
+        rssi,
+        wifi_channel,
+        free_heap: esp_alloc::HEAP.free() as u32,
+        reset_reason: reset_reason(),
+        reconnects: CONNECTIONS.load(Ordering::Relaxed).saturating_sub(1),
+        firmware: env!("CARGO_PKG_VERSION").try_into().unwrap_or_default(),
+    }
+}
+
+/// Sends a report every [REPORT_INTERVAL] while connected
+pub async fn run() -> ! {
+    loop {
+        Timer::after(REPORT_INTERVAL).await;
+        let health = report();
+        if let Err(TrySendError::Full(_)) =
+            WIFI_MSG_CHANNEL.try_send(MessagePayload::HealthReport { health })
+        {
+            debug!("[health] Broker queue full, skipped report");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Just enough HTTP/1.0 to serve small pages and forms from the plug
+
+use core::str::FromStr;
+
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, WithTimeout};
+use embedded_io_async::Write;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HttpError {
+    Io,
+    Timeout,
+    TooLarge,
+    Malformed,
+}
+
+pub struct Request<'b> {
+    pub method: &'b str,
+    pub path: &'b str,
+    headers: &'b str,
+    pub body: &'b [u8],
+}
+
+impl Request<'_> {
+    /// Case insensitive header lookup
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.split("\r\n").find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+        })
+    }
+}
+
+/// Reads a whole request, including a body if `Content-Length` is present
+pub async fn read_request<'b>(
+    socket: &mut TcpSocket<'_>,
+    buf: &'b mut [u8],
+) -> Result<Request<'b>, HttpError> {
+    let mut len = 0;
+    let head_end = loop {
+        if len == buf.len() {
+            return Err(HttpError::TooLarge);
+        }
+        let n = socket
+            .read(&mut buf[len..])
+            .with_timeout(READ_TIMEOUT)
+            .await
+            .map_err(|_| HttpError::Timeout)?
+            .map_err(|_| HttpError::Io)?;
+        if n == 0 {
+            return Err(HttpError::Io);
+        }
+        len += n;
+        if let Some(pos) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+    };
+
+    let head = core::str::from_utf8(&buf[..head_end]).map_err(|_| HttpError::Malformed)?;
+    let content_length = head
+        .split("\r\n")
+        .skip(1)
+        .find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.trim()
+                .eq_ignore_ascii_case("content-length")
+                .then(|| usize::from_str(v.trim()).ok())?
+        })
+        .unwrap_or(0);
+
+    let body_start = head_end + 4;
+    let body_end = body_start + content_length;
+    if body_end > buf.len() {
+        return Err(HttpError::TooLarge);
+    }
+    while len < body_end {
+        let n = socket
+            .read(&mut buf[len..body_end])
+            .with_timeout(READ_TIMEOUT)
+            .await
+            .map_err(|_| HttpError::Timeout)?
+            .map_err(|_| HttpError::Io)?;
+        if n == 0 {
+            return Err(HttpError::Io);
+        }
+        len += n;
+    }
+
+    let (head, body) = buf[..body_end].split_at(body_start);
+    let head = core::str::from_utf8(head).map_err(|_| HttpError::Malformed)?;
+    let (request_line, headers) = head.split_once("\r\n").ok_or(HttpError::Malformed)?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next().ok_or(HttpError::Malformed)?;
+    let path = parts.next().ok_or(HttpError::Malformed)?;
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Writes a complete response and flushes the socket
+pub async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), HttpError> {
+    let len = itoa_buf(body.len());
+    for part in [
+        "HTTP/1.0 ",
+        status,
+        "\r\nContent-Type: ",
+        content_type,
+        "\r\nContent-Length: ",
+        len.as_str(),
+        "\r\nConnection: close\r\n\r\n",
+    ] {
+        socket
+            .write_all(part.as_bytes())
+            .await
+            .map_err(|_| HttpError::Io)?;
+    }
+    socket.write_all(body).await.map_err(|_| HttpError::Io)?;
+    socket.flush().await.map_err(|_| HttpError::Io)
+}
+
+/// Finds `key` in an `application/x-www-form-urlencoded` body and decodes it
+pub fn form_value<const N: usize>(body: &[u8], key: &str) -> Option<heapless::String<N>> {
+    let body = core::str::from_utf8(body).ok()?;
+    let raw = body.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (k == key).then_some(v)
+    })?;
+
+    let mut out = heapless::Vec::<u8, N>::new();
+    let mut bytes = raw.bytes();
+    while let Some(b) = bytes.next() {
+        let decoded = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hi = hex_digit(bytes.next()?)?;
+                let lo = hex_digit(bytes.next()?)?;
+                (hi << 4) | lo
+            }
+            b => b,
+        };
+        out.push(decoded).ok()?;
+    }
+    heapless::String::from_utf8(out).ok()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn itoa_buf(n: usize) -> heapless::String<20> {
+    let mut s = heapless::String::new();
+    let _ = core::fmt::write(&mut s, format_args!("{n}"));
+    s
+}
@@ -0,0 +1,122 @@
+//! UUID the plug connects to the broker with, and the token of its LAN API
+//!
+//! Both are made from the hardware RNG on first boot and kept in
+//! [Record::Identity], so a factory reset, which wipes it, makes the plug a
+//! new one as far as the broker is concerned. The token is per plug, knowing
+//! one doesn't open any other, and the provisioning portal shows it.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex};
+use esp_hal::rng::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error, info,
+    storage::{self, Record, StorageError},
+};
+
+/// Length of the [token], in hex digits
+pub const TOKEN_LEN: usize = 32;
+
+static RNG: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Rng>>> =
+    BlockingMutex::new(Cell::new(None));
+static STORED: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Stored>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// What [Record::Identity] holds, before the token it was a bare UUID
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Stored {
+    id: [u8; 16],
+    token: [u8; TOKEN_LEN / 2],
+}
+
+/// Hands over the hardware RNG, must be called before anything else here
+pub fn init(rng: Rng) {
+    RNG.lock(|r| r.set(Some(rng)));
+}
+
+pub fn random() -> u32 {
+    RNG.lock(|r| r.get().expect("identity::init wasn't called").random())
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(4) {
+        chunk.copy_from_slice(&random().to_le_bytes());
+    }
+    bytes
+}
+
+/// A new random (version 4) UUID
+pub fn generate() -> uuid::Uuid {
+    uuid::Builder::from_random_bytes(random_bytes()).into_uuid()
+}
+
+/// Reads the stored identity, making one if there's none yet
+pub async fn load() -> uuid::Uuid {
+    uuid::Uuid::from_bytes(stored().await.id)
+}
+
+/// Token LAN API requests must carry, made along with the identity
+pub async fn token() -> heapless::String<TOKEN_LEN> {
+    let mut token = heapless::String::new();
+    for b in stored().await.token {
+        let _ = core::fmt::write(&mut token, format_args!("{b:02x}"));
+    }
+    token
+}
+
+async fn stored() -> Stored {
+    if let Some(stored) = STORED.lock(Cell::get) {
+        return stored;
+    }
+    let stored = match storage::load::<Stored>(Record::Identity).await {
+        Ok(Some(stored)) => stored,
+        res => {
+            let stored = match res {
+                // written before the token existed, keep the UUID the
+                // broker knows
+                Err(StorageError::Postcard) => {
+                    match storage::load::<[u8; 16]>(Record::Identity).await {
+                        Ok(Some(id)) => Some(Stored {
+                            id,
+                            token: random_bytes(),
+                        }),
+                        _ => None,
+                    }
+                }
+                Err(e) => {
+                    error!("[identity] Failed to read identity: {}", e);
+                    None
+                }
+                Ok(_) => None,
+            };
+            let stored = stored.unwrap_or_else(|| {
+                let id = generate();
+                info!("[identity] New identity {}", crate::Debug2Format(&id));
+                Stored {
+                    id: id.into_bytes(),
+                    token: random_bytes(),
+                }
+            });
+            // a new one on every boot is better than none
+            if let Err(e) = storage::store(Record::Identity, &stored).await {
+                error!("[identity] Failed to store identity: {}", e);
+            }
+            stored
+        }
+    };
+    STORED.lock(|s| s.set(Some(stored)));
+    stored
+}
+
+/// Replaces the identity used from the next boot on, with a new token, a
+/// reset plug shouldn't answer whoever had the old one
+pub async fn store(id: uuid::Uuid) -> Result<(), StorageError> {
+    let stored = Stored {
+        id: id.into_bytes(),
+        token: random_bytes(),
+    };
+    storage::store(Record::Identity, &stored).await
+}
@@ -0,0 +1,127 @@
+//! Local HTTP control so the plug can still be operated when the broker is
+//! unreachable
+//!
+//! | Method | Path        | Description                              |
+//! |--------|-------------|------------------------------------------|
+//! | GET    | `/status`   | `{"is_on":true,"channels":[true,false]}` |
+//! | POST   | `/on`       | closes the relay of channel 0            |
+//! | POST   | `/off`      | opens the relay of channel 0             |
+//! | POST   | `/on/{n}`   | closes the relay of channel `n`          |
+//! | POST   | `/off/{n}`  | opens the relay of channel `n`           |
+//!
+//! `is_on` is set while any channel is on. Every request must carry
+//! `Authorization: Bearer <token>`, with the plug's own
+//! [token](identity::token) as shown by the provisioning portal.
+
+use core::fmt::Write;
+
+use embassy_net::{Stack, tcp::TcpSocket};
+use embassy_time::{Duration, Timer};
+
+use common::{ChannelMask, MAX_CHANNELS};
+use heapless::String;
+
+use crate::{RELAY_STATUS, RelayMode, board, debug, http, identity, info, relay, warn};
+
+const HTTP_PORT: u16 = 80;
+
+pub async fn run(stack: Stack<'_>) -> ! {
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 512];
+    let mut req_buf = [0u8; 1024];
+    let token = identity::token().await;
+
+    info!("[lan_api] Listening on port {}", HTTP_PORT);
+    loop {
+        let mut sock = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        sock.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = sock.accept(HTTP_PORT).await {
+            warn!("[lan_api] Accept failed: {}", e);
+            continue;
+        }
+
+        let (status, body) = match http::read_request(&mut sock, &mut req_buf).await {
+            Ok(req) if !authorized(req.header("authorization"), &token) => {
+                warn!("[lan_api] Unauthorized request to {}", req.path);
+                ("401 Unauthorized", None)
+            }
+            Ok(req) => match (req.method, route(req.path)) {
+                ("GET", Some(("/status", None))) => ("200 OK", Some(status())),
+                ("POST", Some(("/on", channel))) => {
+                    set_relay(channel.unwrap_or(0), RelayMode::Closed).await
+                }
+                ("POST", Some(("/off", channel))) => {
+                    set_relay(channel.unwrap_or(0), RelayMode::Open).await
+                }
+                _ => ("404 Not Found", None),
+            },
+            Err(e) => {
+                debug!("[lan_api] Bad request: {}", e);
+                ("400 Bad Request", None)
+            }
+        };
+
+        let body = match &body {
+            Some(on) => status_json(*on),
+            None => String::try_from("{}").unwrap(),
+        };
+        let _ = http::write_response(&mut sock, status, "application/json", body.as_bytes()).await;
+
+        sock.close();
+        Timer::after_millis(100).await;
+        sock.abort();
+    }
+}
+
+fn authorized(header: Option<&str>, expected: &str) -> bool {
+    let Some(token) = header.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    // constant time comparison, the length isn't a secret
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Splits `/on/3` into `("/on", Some(3))`, `None` if the channel isn't a
+/// number
+fn route(path: &str) -> Option<(&str, Option<u8>)> {
+    match path.get(1..).and_then(|p| p.split_once('/')) {
+        Some((base, channel)) => Some((&path[..base.len() + 1], Some(channel.parse().ok()?))),
+        None => Some((path, None)),
+    }
+}
+
+fn status() -> ChannelMask {
+    RELAY_STATUS.try_get().unwrap_or_default()
+}
+
+/// Room for `{"is_on":false,"channels":[...]}` with [MAX_CHANNELS] entries
+fn status_json(on: ChannelMask) -> String<96> {
+    let mut body = String::new();
+    let _ = write!(body, r#"{{"is_on":{},"channels":["#, on.any());
+    for channel in 0..board::RELAY_COUNT.min(MAX_CHANNELS) as u8 {
+        let sep = if channel == 0 { "" } else { "," };
+        let _ = write!(body, "{sep}{}", on.is_on(channel));
+    }
+    let _ = body.push_str("]}");
+    body
+}
+
+/// Asks relay_task for `mode` and waits a bit for it to be applied
+async fn set_relay(channel: u8, mode: RelayMode) -> (&'static str, Option<ChannelMask>) {
+    info!("[lan_api] Local request for channel {} {}", channel, mode);
+    if !relay::request(channel, mode) {
+        return ("404 Not Found", None);
+    }
+    for _ in 0..10 {
+        if relay::channel_mode(channel) == Some(mode) {
+            break;
+        }
+        Timer::after_millis(50).await;
+    }
+    ("200 OK", Some(status()))
+}
@@ -7,59 +7,112 @@
 #![warn(clippy::todo)]
 #![warn(clippy::unimplemented)]
 
+#[cfg(feature = "esp")]
 use common::MessagePayload;
-#[cfg(not(feature = "ble"))]
-use embassy_futures::select::select4;
-#[cfg(feature = "ble")]
+#[cfg(feature = "esp")]
+use embassy_futures::join::join4;
+#[cfg(all(feature = "esp", not(feature = "ble")))]
 use embassy_futures::select::select5;
+#[cfg(feature = "ble")]
+use embassy_futures::select::select6;
+#[cfg(feature = "esp")]
+use embassy_sync::channel::TrySendError;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
-use embassy_time::Timer;
-use esp_hal::gpio::{Input, InputConfig, InputPin, Level, Output, OutputConfig, OutputPin, Pull};
+#[cfg(feature = "esp")]
+use embedded_hal::digital::{InputPin, OutputPin, StatefulOutputPin};
+#[cfg(feature = "esp")]
+use embedded_hal_async::digital::Wait;
 
-use crate::{status_led::StatusLed, wifi::WIFI_MSG_CHANNEL};
+#[cfg(feature = "esp")]
+use crate::{gesture::ButtonAction, wifi::WIFI_MSG_CHANNEL};
 
 #[cfg(feature = "ble")]
 mod ble;
+pub mod board;
+mod button;
+pub mod clock;
+pub mod config;
+pub mod crash;
 mod fmt;
-mod status_led;
+pub mod gesture;
+#[cfg(feature = "esp")]
+mod health;
+#[cfg(feature = "esp")]
+mod http;
+#[cfg(feature = "esp")]
+pub mod identity;
+#[cfg(feature = "lan-api")]
+mod lan_api;
+pub mod link;
+pub mod netlog;
+#[cfg(feature = "esp")]
+mod ota;
+#[cfg(feature = "esp")]
+mod power_on;
+#[cfg(feature = "esp")]
+mod provisioning;
+mod relay;
+pub mod restart;
+pub mod roaming;
+pub mod schedule;
+pub mod signing;
+pub mod sntp;
+pub mod status_led;
+#[cfg(feature = "esp")]
+pub mod storage;
+#[cfg(feature = "esp")]
 mod wifi;
 
 extern crate alloc;
 
 #[cfg(feature = "ble")]
 pub use ble::BleHandler;
+pub use button::{BUTTON_ACTION, BUTTON_STATUS, ButtonEvent, button_task};
 pub use fmt::*;
+pub use relay::{
+    RELAY_SIGNAL, RELAY_STATUS, RelayMode, RelayRequest, relay_task, remaining_secs, settled,
+};
+pub use status_led::StatusLed;
+#[cfg(feature = "esp")]
 pub use wifi::WifiHandler;
 
-pub struct App<'a> {
+pub type PinSignal<T> = Signal<CriticalSectionRawMutex, T>;
+pub type PinStatus<T> = Watch<CriticalSectionRawMutex, T, 4>;
+
+#[cfg(feature = "esp")]
+pub struct App<'a, L, R, B> {
     /// Status LED handler
-    status_led: StatusLed<'a>,
-    /// Relay on plug
-    relay: Output<'a>,
+    status_led: StatusLed<L>,
+    /// Relays on plug, one per channel
+    relays: [R; board::RELAY_COUNT],
     /// Button on plug
-    button: Input<'a>,
+    button: B,
     wifi: WifiHandler<'a>,
     #[cfg(feature = "ble")]
     ble: BleHandler<'a>,
 }
 
-impl<'a> App<'a> {
+#[cfg(feature = "esp")]
+impl<'a, L, R, B> App<'a, L, R, B>
+where
+    L: OutputPin,
+    R: StatefulOutputPin,
+    B: InputPin + Wait,
+{
+    /// Takes pins that are already configured by `board_pins!`, the relays
+    /// should start open
     pub fn new(
-        onboard_led_pin: impl OutputPin + 'a,
-        plug_led_pin: impl OutputPin + 'a,
-        relay_pin: impl OutputPin + 'a,
-        button_pin: impl InputPin + 'a,
+        onboard_led: L,
+        plug_led: Option<L>,
+        relays: [R; board::RELAY_COUNT],
+        button: B,
         wifi_handler: WifiHandler<'a>,
         #[cfg(feature = "ble")] ble_handler: BleHandler<'a>,
     ) -> Self {
-        let relay = Output::new(relay_pin, RelayMode::Closed.into(), OutputConfig::default());
-        let button = Input::new(button_pin, InputConfig::default().with_pull(Pull::None));
-
-        let status_led = StatusLed::new(onboard_led_pin, plug_led_pin);
-
+        crash::load();
         Self {
-            status_led,
-            relay,
+            status_led: StatusLed::new(onboard_led, plug_led),
+            relays,
             button,
             wifi: wifi_handler,
             #[cfg(feature = "ble")]
@@ -68,21 +121,34 @@ impl<'a> App<'a> {
     }
 
     pub async fn run(#[allow(unused_mut)] mut self) -> ! {
+        config::load().await;
         #[cfg(feature = "ble")]
-        select5(
+        select6(
             self.wifi.run(),
             self.ble.run(),
             self.status_led.run(),
-            relay_task(&mut self.relay),
-            button_task(&mut self.button),
+            relays(&mut self.relays),
+            join4(
+                button_task(&mut self.button),
+                action_task(),
+                restart::run(),
+                config::persist_task(),
+            ),
+            ota::watchdog(),
         )
         .await;
         #[cfg(not(feature = "ble"))]
-        select4(
+        select5(
             self.wifi.run(),
             self.status_led.run(),
-            relay_task(&mut self.relay),
-            button_task(&mut self.button),
+            relays(&mut self.relays),
+            join4(
+                button_task(&mut self.button),
+                action_task(),
+                restart::run(),
+                config::persist_task(),
+            ),
+            ota::watchdog(),
         )
         .await;
 
@@ -90,98 +156,48 @@ impl<'a> App<'a> {
     }
 }
 
-pub type PinSignal<T> = Signal<CriticalSectionRawMutex, T>;
-pub type PinStatus<T> = Watch<CriticalSectionRawMutex, T, 4>;
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum RelayMode {
-    Open,
-    Closed,
-}
-
-impl From<RelayMode> for Level {
-    fn from(value: RelayMode) -> Self {
-        match value {
-            RelayMode::Closed => Level::High,
-            RelayMode::Open => Level::Low,
-        }
-    }
-}
-
-impl From<Level> for RelayMode {
-    fn from(value: Level) -> Self {
-        match value {
-            Level::Low => RelayMode::Open,
-            Level::High => RelayMode::Closed,
-        }
-    }
-}
-
-impl RelayMode {
-    fn toggle(&self) -> Self {
-        match self {
-            RelayMode::Open => RelayMode::Closed,
-            RelayMode::Closed => RelayMode::Open,
-        }
-    }
-}
-
-pub static RELAY_SIGNAL: PinSignal<RelayMode> = Signal::new();
-pub static RELAY_STATUS: PinStatus<RelayMode> = Watch::new();
-
-async fn relay_task(pin: &mut Output<'_>) {
-    let orig_level = pin.output_level();
-    let should_signal = RELAY_STATUS
-        .try_get()
-        .is_none_or(|l| l == orig_level.into());
-    let sender = RELAY_STATUS.sender();
-    if should_signal {
-        sender.send(orig_level.into());
-    }
-    loop {
-        let mode = RELAY_SIGNAL.wait().await;
-        if mode != pin.output_level().into() {
-            pin.set_level(mode.into());
-            sender.send(mode);
-            match mode {
-                RelayMode::Open => WIFI_MSG_CHANNEL
-                    .sender()
-                    .send(MessagePayload::TurnOffNotify),
-                RelayMode::Closed => WIFI_MSG_CHANNEL.sender().send(MessagePayload::TurnOnNotify),
+/// Runs the relays with the power-on state and the schedule, and tells the
+/// broker about changes
+#[cfg(feature = "esp")]
+async fn relays(pins: &mut [impl StatefulOutputPin]) {
+    let initial = power_on::initial_mode().await;
+    schedule::load().await;
+    join4(
+        power_on::persist_task(),
+        schedule::persist_task(),
+        schedule::run(),
+        relay_task(pins, initial, |channel, mode| {
+            let msg = match mode {
+                RelayMode::Open => MessagePayload::TurnOffNotify { channel },
+                RelayMode::Closed => MessagePayload::TurnOnNotify { channel },
             };
-        }
-    }
+            if let Err(TrySendError::Full(msg)) = WIFI_MSG_CHANNEL.try_send(msg) {
+                warn!("[relay] Broker queue full, dropped {}", msg);
+            }
+        }),
+    )
+    .await;
 }
 
-pub static BUTTON_STATUS: PinStatus<ButtonEvent> = Watch::new();
-
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum ButtonEvent {
-    Press,
-    Release,
-}
-
-/// Pin Level when plug button is pressed
-const BUTTON_PRESSED_LEVEL: Level = Level::Low;
-
-async fn button_task(pin: &mut Input<'_>) {
-    let sender = BUTTON_STATUS.sender();
-    let mut prev_level = pin.level();
+/// Performs the button actions that [button_task] can't do by itself
+#[cfg(feature = "esp")]
+async fn action_task() -> ! {
     loop {
-        pin.wait_for_any_edge().await;
-        // debounce de pobre
-        Timer::after_millis(50).await;
-        let level = pin.level();
-        if level == prev_level {
-            continue;
-        } else if level == BUTTON_PRESSED_LEVEL {
-            sender.send(ButtonEvent::Press);
-            RELAY_SIGNAL.signal(RELAY_STATUS.try_get().unwrap_or(RelayMode::Closed).toggle());
-        } else {
-            sender.send(ButtonEvent::Release);
+        match BUTTON_ACTION.wait().await {
+            ButtonAction::ToggleRelay => {}
+            #[cfg(feature = "ble")]
+            ButtonAction::BlePairing => ble::PAIRING_SIGNAL.signal(()),
+            #[cfg(not(feature = "ble"))]
+            ButtonAction::BlePairing => warn!("[button] Built without BLE support"),
+            ButtonAction::StartProvisioning => provisioning::PROVISIONING_SIGNAL.signal(()),
+            ButtonAction::FactoryReset => {
+                warn!("[button] Factory reset requested");
+                if let Err(e) = storage::wipe().await {
+                    error!("[button] Factory reset failed: {}", e);
+                    continue;
+                }
+                esp_hal::system::software_reset();
+            }
         }
-        prev_level = level;
     }
 }
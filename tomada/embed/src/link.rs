@@ -0,0 +1,411 @@
+//! How messages travel between the plug and the broker
+//!
+//! UDP comes first. When [FALLBACK_AFTER] handshakes in a row go unanswered
+//! the plug switches to TCP, where every [PlugMessage] is prefixed with its
+//! length as a big endian u16, and goes back to UDP once TCP failed as many
+//! times. With the `tls` feature the TCP connection is wrapped in TLS.
+
+use common::PlugMessage;
+use embedded_io_async::Read;
+
+/// Failed handshakes before trying the other transport
+pub const FALLBACK_AFTER: u8 = 3;
+/// Largest message body on a stream, what the broker accepts too
+pub const MAX_FRAME: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transport {
+    Datagram,
+    Stream,
+}
+
+/// Picks the transport for the next handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fallback {
+    transport: Transport,
+    /// Failed handshakes in a row on `transport`
+    failures: u8,
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fallback {
+    pub const fn new() -> Self {
+        Self {
+            transport: Transport::Datagram,
+            failures: 0,
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Records a session that got through the handshake
+    pub fn connected(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a failed handshake, returning the transport for the next one
+    pub fn failed(&mut self) -> Transport {
+        self.failures += 1;
+        if self.failures >= FALLBACK_AFTER {
+            self.failures = 0;
+            self.transport = match self.transport {
+                Transport::Datagram => Transport::Stream,
+                Transport::Stream => Transport::Datagram,
+            };
+        }
+        self.transport
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError<E> {
+    Io(E),
+    /// The other side closed the connection
+    Closed,
+    TooLarge(usize),
+    Decode(postcard::Error),
+}
+
+/// Serializes `msg` into `buf` with its length in front, returning the frame
+pub fn encode<'b>(msg: &PlugMessage, buf: &'b mut [u8]) -> Result<&'b [u8], postcard::Error> {
+    if buf.len() < 2 {
+        return Err(postcard::Error::SerializeBufferFull);
+    }
+    let len = postcard::to_slice(msg, &mut buf[2..])?.len();
+    buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+    Ok(&buf[..2 + len])
+}
+
+/// Splits a byte stream into messages
+///
+/// Partial frames are kept between calls, so a [read](FrameReader::read) can
+/// be cancelled without losing data as long as the underlying read can.
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME + 2],
+    len: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME + 2],
+            len: 0,
+        }
+    }
+
+    /// Drops whatever was left from the previous connection
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub async fn read<R: Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<PlugMessage, FrameError<R::Error>> {
+        loop {
+            if self.len >= 2 {
+                let size = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+                if size > MAX_FRAME {
+                    return Err(FrameError::TooLarge(size));
+                }
+                if self.len >= 2 + size {
+                    let msg = postcard::from_bytes(&self.buf[2..2 + size]);
+                    self.buf.copy_within(2 + size..self.len, 0);
+                    self.len -= 2 + size;
+                    return msg.map_err(FrameError::Decode);
+                }
+            }
+            let n = reader
+                .read(&mut self.buf[self.len..])
+                .await
+                .map_err(FrameError::Io)?;
+            if n == 0 {
+                return Err(FrameError::Closed);
+            }
+            self.len += n;
+        }
+    }
+}
+
+#[cfg(feature = "esp")]
+pub(crate) use esp::*;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use core::net::SocketAddrV4;
+
+    use common::PlugMessage;
+    use embassy_net::udp::{RecvError, SendError, UdpSocket};
+    use embassy_time::TimeoutError;
+    use embedded_io_async::{Read, Write};
+
+    use super::{FrameError, FrameReader, MAX_FRAME, encode};
+
+    #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub(crate) enum ConnError {
+        NoRoute,
+        PacketTooLarge,
+        SocketNotBound,
+        Postcard(postcard::Error),
+        SendTimeout,
+        RecvBufferTooSmall,
+        Stream(embedded_io::ErrorKind),
+        Closed,
+    }
+
+    impl From<postcard::Error> for ConnError {
+        fn from(value: postcard::Error) -> Self {
+            Self::Postcard(value)
+        }
+    }
+
+    impl From<SendError> for ConnError {
+        fn from(value: SendError) -> Self {
+            match value {
+                SendError::NoRoute => Self::NoRoute,
+                SendError::SocketNotBound => Self::SocketNotBound,
+                SendError::PacketTooLarge => Self::PacketTooLarge,
+            }
+        }
+    }
+
+    impl From<RecvError> for ConnError {
+        fn from(_value: RecvError) -> Self {
+            Self::RecvBufferTooSmall
+        }
+    }
+
+    impl From<TimeoutError> for ConnError {
+        fn from(_value: TimeoutError) -> Self {
+            Self::SendTimeout
+        }
+    }
+
+    impl<E: embedded_io::Error> From<FrameError<E>> for ConnError {
+        fn from(value: FrameError<E>) -> Self {
+            match value {
+                FrameError::Io(e) => Self::Stream(e.kind()),
+                FrameError::Closed => Self::Closed,
+                FrameError::TooLarge(_) => Self::RecvBufferTooSmall,
+                FrameError::Decode(e) => Self::Postcard(e),
+            }
+        }
+    }
+
+    /// Sending half of a connection to the broker
+    pub(crate) trait Link {
+        async fn send(&mut self, msg: &PlugMessage) -> Result<(), ConnError>;
+    }
+
+    /// Receiving half of a connection to the broker
+    ///
+    /// A [recv](Incoming::recv) isn't cancel safe: TLS takes a record's
+    /// header before waiting for its body, so dropping the read in between
+    /// loses the record and garbles the ones after it. Reads have to run to
+    /// completion until the connection is abandoned.
+    pub(crate) trait Incoming {
+        async fn recv(&mut self) -> Result<PlugMessage, ConnError>;
+    }
+
+    /// Both halves of a UDP "connection", which share the socket
+    #[derive(Clone, Copy)]
+    pub(crate) struct Datagram<'s, 'a> {
+        pub socket: &'s UdpSocket<'a>,
+        pub addr: SocketAddrV4,
+    }
+
+    impl<'s, 'a> Datagram<'s, 'a> {
+        /// Sending and receiving halves talking to `addr`
+        pub fn pair(socket: &'s UdpSocket<'a>, addr: SocketAddrV4) -> (Self, Self) {
+            let link = Self { socket, addr };
+            (link, link)
+        }
+    }
+
+    impl Link for Datagram<'_, '_> {
+        async fn send(&mut self, msg: &PlugMessage) -> Result<(), ConnError> {
+            let mut buf = [0u8; 256];
+            let bytes = postcard::to_slice(msg, &mut buf)?;
+            self.socket
+                .send_to(bytes, (*self.addr.ip(), self.addr.port()))
+                .await?;
+            Ok(())
+        }
+    }
+
+    impl Incoming for Datagram<'_, '_> {
+        async fn recv(&mut self) -> Result<PlugMessage, ConnError> {
+            let mut buf = [0u8; 512];
+            let (len, _) = self.socket.recv_from(&mut buf).await?;
+            Ok(postcard::from_bytes(&buf[..len])?)
+        }
+    }
+
+    /// Sends length prefixed messages over TCP or TLS
+    pub(crate) struct StreamTx<S> {
+        pub io: S,
+    }
+
+    impl<S: Write> Link for StreamTx<S> {
+        async fn send(&mut self, msg: &PlugMessage) -> Result<(), ConnError> {
+            let mut buf = [0u8; MAX_FRAME + 2];
+            let frame = encode(msg, &mut buf)?;
+            self.io
+                .write_all(frame)
+                .await
+                .map_err(|e| ConnError::Stream(embedded_io::Error::kind(&e)))?;
+            self.io
+                .flush()
+                .await
+                .map_err(|e| ConnError::Stream(embedded_io::Error::kind(&e)))
+        }
+    }
+
+    /// Receives length prefixed messages over TCP or TLS
+    pub(crate) struct StreamRx<'r, S> {
+        pub io: S,
+        pub reader: &'r mut FrameReader,
+    }
+
+    impl<S: Read> Incoming for StreamRx<'_, S> {
+        async fn recv(&mut self) -> Result<PlugMessage, ConnError> {
+            Ok(self.reader.read(&mut self.io).await?)
+        }
+    }
+}
+
+/// TLS for the stream transport
+///
+/// The broker's certificate has to be signed by the CA pinned at build time
+/// and carry `BROKER_TLS_NAME`. Without an allocator embedded-tls only checks
+/// ECDSA and Ed25519 signatures, so neither can be RSA.
+#[cfg(feature = "tls")]
+pub(crate) mod tls {
+    use dotenvy_macro::dotenv;
+    use embassy_net::tcp::{self, TcpReader, TcpWriter};
+    use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+    use embedded_io_async::{Read, Write};
+    use embedded_tls::{
+        Aes128GcmSha256, Certificate, TlsClock, TlsConfig, TlsConnection, TlsContext, TlsError,
+        webpki::CertVerifier,
+    };
+
+    /// Largest TLS record, both buffers need room for one
+    pub(crate) const RECORD_SIZE: usize = 16640;
+    /// Largest broker certificate the verifier keeps around, an ECDSA one
+    /// takes well under half of it
+    const CERT_SIZE: usize = 2048;
+
+    /// Name the broker's certificate must be valid for
+    const SERVER_NAME: &str = dotenv!("BROKER_TLS_NAME");
+    /// DER certificate of the CA that signed the broker's, `BROKER_TLS_CA` is
+    /// its path relative to the crate
+    const CA: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/",
+        dotenv!("BROKER_TLS_CA")
+    ));
+    /// When the firmware was built, in seconds since the Unix epoch
+    const BUILD_TIME: &str = env!("BUILD_TIME");
+
+    /// The TCP socket under a TLS connection, which splits it in two
+    ///
+    /// The reading half only ever locks `rx` and the writing one `tx`, so
+    /// neither waits on the other.
+    #[derive(Clone, Copy)]
+    pub(crate) struct SharedTcp<'s, 'a> {
+        pub rx: &'s Mutex<NoopRawMutex, TcpReader<'a>>,
+        pub tx: &'s Mutex<NoopRawMutex, TcpWriter<'a>>,
+    }
+
+    impl embedded_io_async::ErrorType for SharedTcp<'_, '_> {
+        type Error = tcp::Error;
+    }
+
+    impl Read for SharedTcp<'_, '_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.rx.lock().await.read(buf).await
+        }
+    }
+
+    impl Write for SharedTcp<'_, '_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx.lock().await.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.tx.lock().await.flush().await
+        }
+    }
+
+    /// Time for checking the certificate's validity
+    ///
+    /// The broker only sends the time after the handshake, so without an SNTP
+    /// sync the build time stands in for it. Certificates that expired after
+    /// the build still pass until the clock is set.
+    struct Clock;
+
+    impl TlsClock for Clock {
+        fn now() -> Option<u64> {
+            crate::clock::now().or(BUILD_TIME.parse().ok())
+        }
+    }
+
+    /// Hardware RNG for the handshake, which is a true RNG while the radio is
+    /// on
+    struct HardwareRng;
+
+    impl rand_core::RngCore for HardwareRng {
+        fn next_u32(&mut self) -> u32 {
+            crate::identity::random()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(4) {
+                chunk.copy_from_slice(&self.next_u32().to_le_bytes()[..chunk.len()]);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand_core::CryptoRng for HardwareRng {}
+
+    /// Runs the TLS handshake on an already connected socket, failing if the
+    /// broker's certificate doesn't check out
+    pub(crate) async fn open<S: Read + Write>(
+        tls: &mut TlsConnection<'_, S, Aes128GcmSha256>,
+    ) -> Result<(), TlsError> {
+        let config = TlsConfig::new()
+            .with_server_name(SERVER_NAME)
+            .with_ca(Certificate::X509(CA));
+        tls.open::<_, CertVerifier<Aes128GcmSha256, Clock, CERT_SIZE>>(TlsContext::new(
+            &config,
+            &mut HardwareRng,
+        ))
+        .await
+    }
+}
@@ -0,0 +1,149 @@
+//! Log lines for the broker
+//!
+//! Every logging macro also hands its line to [record], which keeps the ones
+//! at or above the remote [level] in a small ring buffer. The broker
+//! connection drains it with [next] when it has nothing more urgent to send,
+//! the oldest lines are dropped if it can't keep up.
+//!
+//! defmt only ships the format string and leaves the formatting to the host,
+//! so here every argument is rendered with its `Debug` impl instead.
+
+use core::{
+    cell::RefCell,
+    fmt::{self, Debug, Write},
+};
+
+pub use common::LogLevel;
+use common::{LogLine, MAX_LOG_MESSAGE};
+use embassy_sync::{
+    blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::Instant;
+use heapless::{Deque, String};
+use portable_atomic::{AtomicU8, Ordering};
+
+pub const BUFFER_LINES: usize = 16;
+/// Level the plug boots with, the broker can change it until the next reboot
+pub const DEFAULT_LEVEL: LogLevel = LogLevel::Warn;
+
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+struct Buffer {
+    lines: Deque<LogLine, BUFFER_LINES>,
+    /// Lines lost since the last one taken
+    dropped: u16,
+}
+
+static BUFFER: BlockingMutex<CriticalSectionRawMutex, RefCell<Buffer>> =
+    BlockingMutex::new(RefCell::new(Buffer {
+        lines: Deque::new(),
+        dropped: 0,
+    }));
+static NEW_LINE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        3 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Appends to a [String], dropping everything from the first character that
+/// doesn't fit
+struct Truncating<'a, const N: usize> {
+    out: &'a mut String<N>,
+    full: bool,
+}
+
+impl<const N: usize> Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full || self.out.push(c).is_err() {
+                self.full = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fills the `{}` placeholders of a defmt format string with `args`
+///
+/// Display hints such as `{:02X}` are ignored, `{{` and `}}` are escapes.
+pub fn render<const N: usize>(format: &str, args: &[&dyn Debug]) -> String<N> {
+    let mut out = String::new();
+    let mut w = Truncating {
+        out: &mut out,
+        full: false,
+    };
+    let mut args = args.iter();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                let _ = w.write_char(c);
+            }
+            '{' => {
+                chars.by_ref().find(|c| *c == '}');
+                let _ = match args.next() {
+                    Some(arg) => write!(w, "{arg:?}"),
+                    None => w.write_str("{?}"),
+                };
+            }
+            c => {
+                let _ = w.write_char(c);
+            }
+        }
+    }
+    out
+}
+
+/// Called by the logging macros
+#[doc(hidden)]
+pub fn record(level: LogLevel, format: &str, args: &[&dyn Debug]) {
+    if level > self::level() {
+        return;
+    }
+    let line = LogLine {
+        level,
+        uptime: Instant::now().as_millis(),
+        message: render::<MAX_LOG_MESSAGE>(format, args),
+    };
+    BUFFER.lock(|b| {
+        let mut b = b.borrow_mut();
+        if b.lines.is_full() {
+            b.lines.pop_front();
+            b.dropped = b.dropped.saturating_add(1);
+        }
+        let _ = b.lines.push_back(line);
+    });
+    NEW_LINE.signal(());
+}
+
+/// Takes the oldest buffered line, with how many were dropped before it
+pub fn take() -> Option<(LogLine, u16)> {
+    BUFFER.lock(|b| {
+        let mut b = b.borrow_mut();
+        let line = b.lines.pop_front()?;
+        Some((line, core::mem::take(&mut b.dropped)))
+    })
+}
+
+/// Waits for a line to send
+pub async fn next() -> (LogLine, u16) {
+    loop {
+        if let Some(line) = take() {
+            return line;
+        }
+        NEW_LINE.wait().await;
+    }
+}
@@ -0,0 +1,320 @@
+//! Over-the-air updates using the esp-idf A/B (`ota_0`/`ota_1`) partitions
+//!
+//! The broker announces an image with [OtaBegin](MessagePayload::OtaBegin),
+//! the plug downloads it over HTTP into the inactive slot, checks its SHA-256
+//! and its [signature](crate::signing) against `OTA_PUBLIC_KEY`, and reboots
+//! into it. Neither the announcement nor the download is authenticated, the
+//! signature is what keeps a forged broker from flashing its own image. The new image starts in the `PendingVerify` state and
+//! only becomes permanent once it gets a `ConnAck` from the broker; if that
+//! doesn't happen within [CONFIRM_TIMEOUT], or the image reboots before
+//! confirming, the previous slot is booted again.
+
+use core::{net::Ipv4Addr, str::FromStr};
+
+use common::{MAX_SIGNATURE, MessagePayload, OtaStatus};
+use dotenvy_macro::dotenv;
+use embassy_futures::select::{Either, select};
+use embassy_net::{Stack, tcp::TcpSocket};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use embedded_storage::Storage;
+use esp_bootloader_esp_idf::{
+    ota::{Ota, OtaImageState, Slot},
+    partitions::{
+        self, AppPartitionSubType, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
+    },
+};
+use esp_storage::FlashStorage;
+use portable_atomic::{AtomicBool, Ordering};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error, info, signing,
+    storage::{self, Record},
+    warn,
+    wifi::{BROKER_IP, WIFI_MSG_CHANNEL},
+};
+
+/// Time a freshly installed image has to reach the broker
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(300);
+const CHUNK_SIZE: usize = 4096;
+/// Key images must be signed with, a SEC1 P-256 point in hex
+const PUBLIC_KEY: &str = dotenv!("OTA_PUBLIC_KEY");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OtaRequest {
+    pub image_id: u32,
+    pub size: u32,
+    pub sha256: [u8; 32],
+    pub http_port: u16,
+    pub signature: heapless::Vec<u8, MAX_SIGNATURE>,
+}
+
+/// Update requested by the broker
+pub static OTA_SIGNAL: Signal<CriticalSectionRawMutex, OtaRequest> = Signal::new();
+/// Raised when the broker accepts our connection
+pub static OTA_CONFIRM: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Set while an image is being downloaded
+pub static OTA_BUSY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum OtaError {
+    Partition,
+    Flash,
+}
+
+/// Runs `f` with the otadata partition
+fn with_ota<T>(
+    f: impl FnOnce(&mut Ota<'_, FlashStorage>) -> Result<T, OtaError>,
+) -> Result<T, OtaError> {
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(&mut flash, &mut pt_mem)
+        .map_err(|_| OtaError::Partition)?;
+    let otadata = pt
+        .find_partition(PartitionType::Data(DataPartitionSubType::Ota))
+        .map_err(|_| OtaError::Partition)?
+        .ok_or(OtaError::Partition)?;
+    let mut otadata = otadata.as_embedded_storage(&mut flash);
+    let mut ota = Ota::new(&mut otadata).map_err(|_| OtaError::Partition)?;
+    f(&mut ota)
+}
+
+fn rollback() -> ! {
+    warn!("[ota] Rolling back to the previous image");
+    let res = with_ota(|ota| {
+        ota.set_current_ota_state(OtaImageState::Invalid)
+            .map_err(|_| OtaError::Flash)?;
+        let slot = ota.current_slot().map_err(|_| OtaError::Flash)?;
+        ota.set_current_slot(slot.next())
+            .map_err(|_| OtaError::Flash)
+    });
+    if let Err(e) = res {
+        error!("[ota] Rollback failed: {}", e);
+    }
+    esp_hal::system::software_reset()
+}
+
+/// Makes sure a new image gets confirmed or rolled back, must run for as long
+/// as the firmware does
+pub async fn watchdog() -> ! {
+    let state = with_ota(|ota| ota.current_ota_state().map_err(|_| OtaError::Flash));
+    match state {
+        Ok(OtaImageState::New) => {
+            info!("[ota] First boot of a new image, waiting for the broker");
+            if let Err(e) = with_ota(|ota| {
+                ota.set_current_ota_state(OtaImageState::PendingVerify)
+                    .map_err(|_| OtaError::Flash)
+            }) {
+                error!("[ota] Failed to mark image as pending: {}", e);
+            }
+        }
+        // rebooted before the broker saw us
+        Ok(OtaImageState::PendingVerify) => rollback(),
+        Ok(_) => {
+            // nothing to confirm, just keep the confirmations from piling up
+            loop {
+                OTA_CONFIRM.wait().await;
+            }
+        }
+        Err(e) => {
+            error!("[ota] Failed to read otadata: {}", e);
+            loop {
+                OTA_CONFIRM.wait().await;
+            }
+        }
+    }
+
+    if let Either::Second(()) = select(OTA_CONFIRM.wait(), Timer::after(CONFIRM_TIMEOUT)).await {
+        rollback();
+    }
+
+    let image_id = storage::load::<u32>(Record::OtaPending)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    match with_ota(|ota| {
+        ota.set_current_ota_state(OtaImageState::Valid)
+            .map_err(|_| OtaError::Flash)
+    }) {
+        Ok(()) => {
+            info!("[ota] Image {} confirmed", image_id);
+            let _ = storage::erase(Record::OtaPending).await;
+            WIFI_MSG_CHANNEL
+                .send(MessagePayload::OtaStatus {
+                    image_id,
+                    status: OtaStatus::Confirmed,
+                })
+                .await;
+        }
+        Err(e) => error!("[ota] Failed to confirm image: {}", e),
+    }
+
+    loop {
+        OTA_CONFIRM.wait().await;
+    }
+}
+
+/// Handles update requests from the broker
+pub async fn update_task(stack: Stack<'_>) -> ! {
+    loop {
+        let req = OTA_SIGNAL.wait().await;
+        info!(
+            "[ota] Installing image {} ({} bytes)",
+            req.image_id, req.size
+        );
+        OTA_BUSY.store(true, Ordering::Relaxed);
+        let status = match install(stack, &req).await {
+            Ok(()) => OtaStatus::Installed,
+            Err(s) => s,
+        };
+        OTA_BUSY.store(false, Ordering::Relaxed);
+        WIFI_MSG_CHANNEL
+            .send(MessagePayload::OtaStatus {
+                image_id: req.image_id,
+                status,
+            })
+            .await;
+
+        if status == OtaStatus::Installed {
+            if let Err(e) = storage::store(Record::OtaPending, &req.image_id).await {
+                warn!("[ota] Failed to save pending image id: {}", e);
+            }
+            // give the status message a chance to go out
+            Timer::after_secs(2).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+async fn install(stack: Stack<'_>, req: &OtaRequest) -> Result<(), OtaStatus> {
+    let mut flash = FlashStorage::new();
+    let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(&mut flash, &mut pt_mem)
+        .map_err(|_| OtaStatus::FlashFailed)?;
+
+    let next = with_ota(|ota| ota.current_slot().map_err(|_| OtaError::Flash))
+        .map_err(|_| OtaStatus::FlashFailed)?
+        .next();
+    let subtype = match next {
+        Slot::Slot1 => AppPartitionSubType::Ota1,
+        _ => AppPartitionSubType::Ota0,
+    };
+    let target = pt
+        .find_partition(PartitionType::App(subtype))
+        .map_err(|_| OtaStatus::FlashFailed)?
+        .ok_or(OtaStatus::FlashFailed)?;
+    if req.size > target.len() {
+        error!("[ota] Image doesn't fit in the target slot");
+        return Err(OtaStatus::FlashFailed);
+    }
+    let mut target = target.as_embedded_storage(&mut flash);
+
+    let mut rx_buf = [0u8; 2048];
+    let mut tx_buf = [0u8; 256];
+    let mut sock = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+    sock.set_timeout(Some(Duration::from_secs(10)));
+
+    let broker_ip = Ipv4Addr::from_str(BROKER_IP).unwrap();
+    sock.connect((broker_ip, req.http_port))
+        .await
+        .map_err(|_| OtaStatus::DownloadFailed)?;
+
+    let mut request = heapless::String::<64>::new();
+    let _ = core::fmt::write(
+        &mut request,
+        format_args!("GET /api/firmware/{} HTTP/1.0\r\n\r\n", req.image_id),
+    );
+    sock.write_all(request.as_bytes())
+        .await
+        .map_err(|_| OtaStatus::DownloadFailed)?;
+
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut filled = skip_response_head(&mut sock, &mut chunk).await?;
+    let mut written = 0u32;
+    let mut hasher = Sha256::new();
+
+    while written < req.size {
+        let remaining = (req.size - written) as usize;
+        let want = remaining.min(CHUNK_SIZE);
+        while filled < want {
+            let n = sock
+                .read(&mut chunk[filled..want])
+                .with_timeout(Duration::from_secs(10))
+                .await
+                .map_err(|_| OtaStatus::DownloadFailed)?
+                .map_err(|_| OtaStatus::DownloadFailed)?;
+            if n == 0 {
+                error!("[ota] Connection closed after {} bytes", written);
+                return Err(OtaStatus::DownloadFailed);
+            }
+            filled += n;
+        }
+
+        hasher.update(&chunk[..want]);
+        target
+            .write(written, &chunk[..want])
+            .map_err(|_| OtaStatus::FlashFailed)?;
+        written += want as u32;
+        filled = 0;
+    }
+    sock.close();
+
+    if hasher.finalize().as_slice() != req.sha256 {
+        error!("[ota] SHA-256 mismatch, discarding image");
+        return Err(OtaStatus::HashMismatch);
+    }
+    if let Err(e) = signing::verify(PUBLIC_KEY, &req.sha256, &req.signature) {
+        error!("[ota] Bad signature ({}), discarding image", e);
+        return Err(OtaStatus::BadSignature);
+    }
+
+    with_ota(|ota| {
+        ota.set_current_slot(next).map_err(|_| OtaError::Flash)?;
+        ota.set_current_ota_state(OtaImageState::New)
+            .map_err(|_| OtaError::Flash)
+    })
+    .map_err(|_| OtaStatus::FlashFailed)?;
+
+    info!("[ota] Image {} installed", req.image_id);
+    Ok(())
+}
+
+/// Reads the response status line and headers, leaving the first body bytes
+/// at the start of `buf`
+async fn skip_response_head(
+    sock: &mut TcpSocket<'_>,
+    buf: &mut [u8; CHUNK_SIZE],
+) -> Result<usize, OtaStatus> {
+    let mut len = 0;
+    let head_end = loop {
+        if len == buf.len() {
+            return Err(OtaStatus::DownloadFailed);
+        }
+        let n = sock
+            .read(&mut buf[len..])
+            .with_timeout(Duration::from_secs(10))
+            .await
+            .map_err(|_| OtaStatus::DownloadFailed)?
+            .map_err(|_| OtaStatus::DownloadFailed)?;
+        if n == 0 {
+            return Err(OtaStatus::DownloadFailed);
+        }
+        len += n;
+        if let Some(pos) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    if !buf.starts_with(b"HTTP/1.1 200") && !buf.starts_with(b"HTTP/1.0 200") {
+        error!("[ota] Broker refused to serve the image");
+        return Err(OtaStatus::DownloadFailed);
+    }
+
+    buf.copy_within(head_end..len, 0);
+    Ok(len - head_end)
+}
@@ -0,0 +1,115 @@
+//! Relay state after a power cut
+//!
+//! The last relay states are stored as a [ChannelMask] byte, which reads the
+//! same as the single `bool` older firmware stored for channel 0. Channels
+//! running a timed command are stored as they will be once it runs out, as
+//! timers don't survive a reboot. They are only written to flash after it stayed the same for
+//! [SETTLE_TIME] and never more often than [MIN_WRITE_INTERVAL], so someone
+//! playing with the button doesn't eat through the flash.
+
+use core::cell::Cell;
+
+use common::{ChannelMask, PowerOnBehavior};
+use dotenvy_macro::option_dotenv;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{
+    blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer, WithTimeout};
+
+use crate::{
+    RELAY_STATUS, board, error, info, relay,
+    storage::{self, Record},
+};
+
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Compile time default, one of `off`, `on` or `restore`
+const DEFAULT_BEHAVIOR: Option<&str> = option_dotenv!("POWER_ON_BEHAVIOR");
+
+static BEHAVIOR: BlockingMutex<CriticalSectionRawMutex, Cell<PowerOnBehavior>> =
+    BlockingMutex::new(Cell::new(PowerOnBehavior::RestoreLast));
+
+/// New behavior requested by the broker, persisted by [persist_task]
+pub static POWER_ON_SIGNAL: Signal<CriticalSectionRawMutex, PowerOnBehavior> = Signal::new();
+
+/// Currently configured power-on behavior
+pub fn behavior() -> PowerOnBehavior {
+    BEHAVIOR.lock(|b| b.get())
+}
+
+/// Applies a new behavior right away, [persist_task] takes care of saving it
+pub fn set_behavior(behavior: PowerOnBehavior) {
+    BEHAVIOR.lock(|b| b.set(behavior));
+    POWER_ON_SIGNAL.signal(behavior);
+}
+
+fn default_behavior() -> PowerOnBehavior {
+    match DEFAULT_BEHAVIOR {
+        Some("off") => PowerOnBehavior::AlwaysOff,
+        Some("on") => PowerOnBehavior::AlwaysOn,
+        _ => PowerOnBehavior::RestoreLast,
+    }
+}
+
+/// Loads the configured behavior and works out the relay states to boot with
+pub async fn initial_mode() -> ChannelMask {
+    let behavior = storage::load_settings()
+        .await
+        .inspect_err(|e| error!("[power_on] Failed to load behavior: {}", e))
+        .ok()
+        .and_then(|s| s.power_on)
+        .unwrap_or_else(default_behavior);
+    BEHAVIOR.lock(|b| b.set(behavior));
+
+    let all = ChannelMask::all(board::RELAY_COUNT as u8);
+    let on = match behavior {
+        PowerOnBehavior::AlwaysOff => ChannelMask::default(),
+        PowerOnBehavior::AlwaysOn => all,
+        PowerOnBehavior::RestoreLast => match storage::load::<u8>(Record::RelayState).await {
+            Ok(mask) => ChannelMask(mask.unwrap_or_default() & all.0),
+            Err(e) => {
+                error!("[power_on] Failed to load last relay state: {}", e);
+                ChannelMask::default()
+            }
+        },
+    };
+    info!("[power_on] Booting with relays {} ({})", on, behavior);
+    on
+}
+
+/// Saves behavior changes and, with wear limiting, the last relay state
+pub async fn persist_task() -> ! {
+    let mut relay = RELAY_STATUS.receiver().unwrap();
+    loop {
+        match select(POWER_ON_SIGNAL.wait(), relay.changed()).await {
+            Either::First(behavior) => {
+                if let Err(e) = storage::update_settings(|s| s.power_on = Some(behavior)).await {
+                    error!("[power_on] Failed to save behavior: {}", e);
+                }
+                // the stored state may be stale if we weren't restoring before
+                if behavior == PowerOnBehavior::RestoreLast
+                    && let Some(on) = relay.try_get()
+                    && let Err(e) = storage::store(Record::RelayState, &relay::settled(on).0).await
+                {
+                    error!("[power_on] Failed to save relay state: {}", e);
+                }
+            }
+            Either::Second(mut on) => {
+                // wait until the relays stop changing
+                while let Ok(m) = relay.changed().with_timeout(SETTLE_TIME).await {
+                    on = m;
+                }
+                if behavior() != PowerOnBehavior::RestoreLast {
+                    continue;
+                }
+                match storage::store(Record::RelayState, &relay::settled(on).0).await {
+                    Ok(()) => Timer::after(MIN_WRITE_INTERVAL).await,
+                    Err(e) => error!("[power_on] Failed to save relay state: {}", e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! SoftAP captive portal used when no known network can be joined
+//!
+//! Runs a tiny DHCP server, a DNS server that resolves every name to the plug
+//! and an HTTP server with the Wi-Fi configuration form. Once the form is
+//! submitted the credentials are saved, the confirmation shows the
+//! [token](crate::identity::token) of the LAN API, and the plug reboots into
+//! station mode.
+
+use core::net::Ipv4Addr;
+
+use embassy_futures::select::select3;
+use embassy_net::{
+    IpListenEndpoint, Stack,
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    debug, error, http, info,
+    storage::{self, Record},
+    warn,
+};
+
+/// Address of the plug on its own access point
+pub const AP_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+/// First address handed out to clients
+const FIRST_LEASE: u8 = 100;
+const MAX_LEASES: usize = 8;
+const LEASE_SECS: u32 = 3600;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DNS_PORT: u16 = 53;
+const HTTP_PORT: u16 = 80;
+
+/// Starts the provisioning portal even if a known network is reachable
+pub static PROVISIONING_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Credentials saved by the provisioning form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+}
+
+pub async fn run(stack: Stack<'_>) -> ! {
+    info!("[provisioning] Portal up at {}", AP_ADDR);
+    select3(dhcp_task(stack), dns_task(stack), http_task(stack)).await;
+    unreachable!()
+}
+
+async fn dhcp_task(stack: Stack<'_>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buf = [0u8; 1024];
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    sock.bind(IpListenEndpoint {
+        addr: None,
+        port: DHCP_SERVER_PORT,
+    })
+    .unwrap();
+
+    let mut leases = [None::<[u8; 6]>; MAX_LEASES];
+    let mut buf = [0u8; 576];
+    let mut resp = [0u8; 300];
+
+    loop {
+        let Ok((len, _)) = sock.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(len) = dhcp_reply(&buf[..len], &mut leases, &mut resp) else {
+            continue;
+        };
+        if let Err(e) = sock
+            .send_to(&resp[..len], (Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))
+            .await
+        {
+            warn!("[provisioning] Failed to send DHCP reply: {}", e);
+        }
+    }
+}
+
+/// Builds the reply for a DHCP request, returns its length
+fn dhcp_reply(
+    req: &[u8],
+    leases: &mut [Option<[u8; 6]>; MAX_LEASES],
+    resp: &mut [u8; 300],
+) -> Option<usize> {
+    const MAGIC: [u8; 4] = [99, 130, 83, 99];
+    const DISCOVER: u8 = 1;
+    const REQUEST: u8 = 3;
+    const OFFER: u8 = 2;
+    const ACK: u8 = 5;
+
+    // op must be BOOTREQUEST and the options must be there
+    if req.len() < 240 || req[0] != 1 || req[236..240] != MAGIC {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&req[28..34]);
+
+    let mut msg_type = None;
+    let mut opts = &req[240..];
+    while let [code, rest @ ..] = opts {
+        match code {
+            0 => opts = rest,
+            255 => break,
+            _ => {
+                let [len, rest @ ..] = rest else { break };
+                let len = *len as usize;
+                if rest.len() < len {
+                    break;
+                }
+                if *code == 53 && len == 1 {
+                    msg_type = Some(rest[0]);
+                }
+                opts = &rest[len..];
+            }
+        }
+    }
+
+    let reply_type = match msg_type? {
+        DISCOVER => OFFER,
+        REQUEST => ACK,
+        _ => return None,
+    };
+
+    let slot = match leases.iter().position(|l| *l == Some(mac)) {
+        Some(i) => i,
+        None => {
+            // hand out a free slot or recycle the first one
+            let i = leases.iter().position(Option::is_none).unwrap_or(0);
+            leases[i] = Some(mac);
+            i
+        }
+    };
+    let client_ip = Ipv4Addr::new(192, 168, 4, FIRST_LEASE + slot as u8);
+    debug!("[provisioning] Leasing {} (type {})", client_ip, reply_type);
+
+    resp.fill(0);
+    resp[0] = 2; // BOOTREPLY
+    resp[1] = 1; // ethernet
+    resp[2] = 6; // hardware address length
+    resp[4..8].copy_from_slice(&req[4..8]); // xid
+    resp[10..12].copy_from_slice(&req[10..12]); // flags
+    resp[16..20].copy_from_slice(&client_ip.octets()); // yiaddr
+    resp[20..24].copy_from_slice(&AP_ADDR.octets()); // siaddr
+    resp[28..44].copy_from_slice(&req[28..44]); // chaddr
+    resp[236..240].copy_from_slice(&MAGIC);
+
+    let mut i = 240;
+    let mut opt = |code: u8, data: &[u8]| {
+        resp[i] = code;
+        resp[i + 1] = data.len() as u8;
+        resp[i + 2..i + 2 + data.len()].copy_from_slice(data);
+        i += 2 + data.len();
+    };
+    opt(53, &[reply_type]);
+    opt(54, &AP_ADDR.octets());
+    opt(51, &LEASE_SECS.to_be_bytes());
+    opt(1, &[255, 255, 255, 0]);
+    opt(3, &AP_ADDR.octets());
+    opt(6, &AP_ADDR.octets());
+    resp[i] = 255;
+
+    Some(i + 1)
+}
+
+async fn dns_task(stack: Stack<'_>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 1024];
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    sock.bind(IpListenEndpoint {
+        addr: None,
+        port: DNS_PORT,
+    })
+    .unwrap();
+
+    let mut buf = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = sock.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(len) = dns_reply(&mut buf, len) else {
+            continue;
+        };
+        let _ = sock.send_to(&buf[..len], meta.endpoint).await;
+    }
+}
+
+/// Turns the query in `buf` into an answer pointing at [AP_ADDR], in place
+fn dns_reply(buf: &mut [u8; 512], len: usize) -> Option<usize> {
+    // header + at least one question
+    if len < 12 || buf[2] & 0x80 != 0 || u16::from_be_bytes([buf[4], buf[5]]) == 0 {
+        return None;
+    }
+
+    // skip the question name
+    let mut i = 12;
+    while i < len && buf[i] != 0 {
+        i += buf[i] as usize + 1;
+    }
+    // null label + type + class
+    let question_end = i + 5;
+    if question_end > len || question_end + 16 > buf.len() {
+        return None;
+    }
+
+    buf[2] = 0x81; // response, recursion desired
+    buf[3] = 0x80; // recursion available, no error
+    buf[4..6].copy_from_slice(&1u16.to_be_bytes()); // qdcount
+    buf[6..8].copy_from_slice(&1u16.to_be_bytes()); // ancount
+    buf[8..12].fill(0); // nscount, arcount
+
+    let answer = &mut buf[question_end..question_end + 16];
+    answer[0..2].copy_from_slice(&[0xC0, 0x0C]); // pointer to the question name
+    answer[2..4].copy_from_slice(&1u16.to_be_bytes()); // A
+    answer[4..6].copy_from_slice(&1u16.to_be_bytes()); // IN
+    answer[6..10].copy_from_slice(&60u32.to_be_bytes()); // ttl
+    answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+    answer[12..16].copy_from_slice(&AP_ADDR.octets());
+
+    Some(question_end + 16)
+}
+
+const FORM_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width">
+<title>Tomada Goodwe</title></head>
+<body style="font-family:sans-serif;max-width:24em;margin:auto">
+<h1>Tomada Goodwe</h1>
+<form method="post" action="/save">
+<p><label>Rede Wi-Fi<br><input name="ssid" maxlength="32" required></label></p>
+<p><label>Senha<br><input name="password" type="password" maxlength="64"></label></p>
+<p><button type="submit">Salvar</button></p>
+</form></body></html>"#;
+
+const SAVED_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Tomada Goodwe</title></head>
+<body style="font-family:sans-serif;max-width:24em;margin:auto">
+<h1>Salvo!</h1><p>A tomada vai reiniciar e se conectar à rede.</p>
+"#;
+
+/// [SAVED_PAGE], with the token of the LAN API so whoever sets the plug up
+/// can write it down
+async fn saved_page() -> heapless::String<512> {
+    let mut page = heapless::String::new();
+    let _ = page.push_str(SAVED_PAGE);
+    #[cfg(feature = "lan-api")]
+    {
+        let token = crate::identity::token().await;
+        let _ = core::fmt::write(
+            &mut page,
+            format_args!("<p>Token do controle local: <code>{token}</code></p>\n"),
+        );
+    }
+    let _ = page.push_str("</body></html>");
+    page
+}
+
+async fn http_task(stack: Stack<'_>) -> ! {
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 2048];
+    let mut req_buf = [0u8; 1024];
+
+    loop {
+        let mut sock = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        sock.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = sock.accept(HTTP_PORT).await {
+            warn!("[provisioning] Accept failed: {}", e);
+            continue;
+        }
+
+        let saved = match http::read_request(&mut sock, &mut req_buf).await {
+            Ok(req) if req.method == "POST" && req.path == "/save" => {
+                match parse_credentials(req.body) {
+                    Some(creds) => {
+                        info!(
+                            "[provisioning] Received credentials for {}",
+                            creds.ssid.as_str()
+                        );
+                        match storage::store(Record::WifiCredentials, &creds).await {
+                            Ok(()) => {
+                                let page = saved_page().await;
+                                let _ = http::write_response(
+                                    &mut sock,
+                                    "200 OK",
+                                    "text/html; charset=utf-8",
+                                    page.as_bytes(),
+                                )
+                                .await;
+                                true
+                            }
+                            Err(e) => {
+                                error!("[provisioning] Saving credentials failed: {}", e);
+                                let _ = http::write_response(
+                                    &mut sock,
+                                    "500 Internal Server Error",
+                                    "text/plain",
+                                    b"failed to save credentials",
+                                )
+                                .await;
+                                false
+                            }
+                        }
+                    }
+                    None => {
+                        let _ = http::write_response(
+                            &mut sock,
+                            "400 Bad Request",
+                            "text/plain",
+                            b"invalid ssid or password",
+                        )
+                        .await;
+                        false
+                    }
+                }
+            }
+            // every other path gets the form so OS captive portal probes open it
+            Ok(_) => {
+                let _ = http::write_response(
+                    &mut sock,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    FORM_PAGE.as_bytes(),
+                )
+                .await;
+                false
+            }
+            Err(e) => {
+                debug!("[provisioning] Bad request: {}", e);
+                false
+            }
+        };
+
+        sock.close();
+        Timer::after_millis(100).await;
+        sock.abort();
+
+        if saved {
+            info!("[provisioning] Rebooting into station mode");
+            Timer::after_secs(1).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+fn parse_credentials(body: &[u8]) -> Option<WifiCredentials> {
+    let ssid = http::form_value::<32>(body, "ssid")?;
+    let password = http::form_value::<64>(body, "password").unwrap_or_default();
+    // WPA2 passphrases are 8 to 63 characters, empty means an open network
+    let valid_password = password.is_empty() || (8..=63).contains(&password.len());
+    (!ssid.is_empty() && valid_password).then_some(WifiCredentials { ssid, password })
+}
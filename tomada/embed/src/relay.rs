@@ -0,0 +1,213 @@
+use core::cell::Cell;
+
+use common::{ChannelMask, MAX_CHANNELS};
+use embassy_futures::select::{Either, select, select_array};
+use embassy_sync::{
+    blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+    watch::Watch,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::{PinState, StatefulOutputPin};
+
+use crate::{PinSignal, PinStatus, board, debug, error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RelayMode {
+    Open,
+    Closed,
+}
+
+impl From<RelayMode> for PinState {
+    fn from(value: RelayMode) -> Self {
+        match value {
+            RelayMode::Closed => board::RELAY_ACTIVE,
+            RelayMode::Open => !board::RELAY_ACTIVE,
+        }
+    }
+}
+
+impl From<PinState> for RelayMode {
+    fn from(value: PinState) -> Self {
+        if value == board::RELAY_ACTIVE {
+            RelayMode::Closed
+        } else {
+            RelayMode::Open
+        }
+    }
+}
+
+impl RelayMode {
+    pub fn toggle(&self) -> Self {
+        match self {
+            RelayMode::Open => RelayMode::Closed,
+            RelayMode::Closed => RelayMode::Open,
+        }
+    }
+}
+
+/// Relay request, optionally switching back on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RelayRequest {
+    pub mode: RelayMode,
+    /// Switches back to the other mode after this long, unless another
+    /// request for the channel comes first
+    pub revert_after: Option<Duration>,
+}
+
+impl From<RelayMode> for RelayRequest {
+    fn from(mode: RelayMode) -> Self {
+        Self {
+            mode,
+            revert_after: None,
+        }
+    }
+}
+
+/// One request signal per channel, indexed by channel number
+pub static RELAY_SIGNAL: [PinSignal<RelayRequest>; MAX_CHANNELS] =
+    [const { Signal::new() }; MAX_CHANNELS];
+/// Sent again whenever a timer starts or stops, even if no relay changed
+pub static RELAY_STATUS: PinStatus<ChannelMask> = Watch::new();
+
+/// When each channel reverts, kept by [relay_task]
+static TIMERS: BlockingMutex<CriticalSectionRawMutex, Cell<[Option<Instant>; MAX_CHANNELS]>> =
+    BlockingMutex::new(Cell::new([None; MAX_CHANNELS]));
+
+/// Asks [relay_task] to switch `channel`, returns `false` if the board
+/// doesn't have it
+pub fn request(channel: u8, request: impl Into<RelayRequest>) -> bool {
+    let Some(signal) = RELAY_SIGNAL[..board::RELAY_COUNT].get(channel as usize) else {
+        return false;
+    };
+    signal.signal(request.into());
+    true
+}
+
+/// Current mode of `channel`, if the relays are running
+#[cfg(feature = "lan-api")]
+pub fn channel_mode(channel: u8) -> Option<RelayMode> {
+    RELAY_STATUS
+        .try_get()
+        .map(|on| RelayMode::from(on.is_on(channel)))
+}
+
+/// Seconds until each channel reverts, rounded up, 0 without a timer
+pub fn remaining_secs() -> [u32; MAX_CHANNELS] {
+    let now = Instant::now();
+    TIMERS.lock(|t| t.get()).map(|at| match at {
+        Some(at) => at
+            .saturating_duration_since(now)
+            .as_millis()
+            .div_ceil(1000)
+            .max(1) as u32,
+        None => 0,
+    })
+}
+
+/// `on` with every timed channel already switched back, what the relays
+/// settle to if nothing else happens
+pub fn settled(on: ChannelMask) -> ChannelMask {
+    let timers = TIMERS.lock(|t| t.get());
+    let timed = (0u8..)
+        .zip(timers)
+        .filter(|(_, at)| at.is_some())
+        .fold(0, |mask, (channel, _)| mask | 1 << channel);
+    ChannelMask(on.0 ^ timed)
+}
+
+impl From<bool> for RelayMode {
+    fn from(on: bool) -> Self {
+        if on {
+            RelayMode::Closed
+        } else {
+            RelayMode::Open
+        }
+    }
+}
+
+/// Drives one relay pin per channel from [RELAY_SIGNAL], starting in
+/// `initial`
+///
+/// Timed requests are reverted here, so they run out even without the
+/// broker. Any later request for the channel cancels the timer. `on_change`
+/// is called with the channel every time a relay actually changes.
+pub async fn relay_task<P: StatefulOutputPin>(
+    pins: &mut [P],
+    initial: ChannelMask,
+    mut on_change: impl FnMut(u8, RelayMode),
+) -> ! {
+    let count = pins.len().min(MAX_CHANNELS);
+    let pins = &mut pins[..count];
+    let mut on = ChannelMask::default();
+    for (channel, pin) in (0u8..).zip(pins.iter_mut()) {
+        let mode = RelayMode::from(initial.is_on(channel));
+        if pin.set_state(mode.into()).is_err() {
+            error!("[relay] Failed to set initial state of channel {}", channel);
+        }
+        on.set(
+            channel,
+            output_mode(pin).unwrap_or(mode) == RelayMode::Closed,
+        );
+    }
+    let mut timers = [None; MAX_CHANNELS];
+    TIMERS.lock(|t| t.set(timers));
+    let sender = RELAY_STATUS.sender();
+    sender.send(on);
+    loop {
+        let next_timer = timers.iter().flatten().min().copied();
+        let timer = async {
+            match next_timer {
+                Some(at) => Timer::at(at).await,
+                None => core::future::pending().await,
+            }
+        };
+        let requests = select_array(core::array::from_fn::<_, MAX_CHANNELS, _>(|c| {
+            RELAY_SIGNAL[c].wait()
+        }));
+        let (request, channel) = match select(requests, timer).await {
+            Either::First(r) => r,
+            Either::Second(()) => {
+                let now = Instant::now();
+                let Some(channel) = timers.iter().position(|t| t.is_some_and(|at| at <= now))
+                else {
+                    continue;
+                };
+                let mode = RelayMode::from(!on.is_on(channel as u8));
+                debug!("[relay] Timer of channel {} ran out", channel);
+                (mode.into(), channel)
+            }
+        };
+        let Some(pin) = pins.get_mut(channel) else {
+            continue;
+        };
+
+        let timer = request.revert_after.map(|d| Instant::now() + d);
+        let timer_changed = timers[channel] != timer;
+        timers[channel] = timer;
+        TIMERS.lock(|t| t.set(timers));
+
+        let mode = request.mode;
+        if output_mode(pin) == Some(mode) {
+            if timer_changed {
+                sender.send(on);
+            }
+            continue;
+        }
+        if pin.set_state(mode.into()).is_err() {
+            error!("[relay] Failed to switch channel {} to {}", channel, mode);
+            continue;
+        }
+        on.set(channel as u8, mode == RelayMode::Closed);
+        sender.send(on);
+        on_change(channel as u8, mode);
+    }
+}
+
+fn output_mode<P: StatefulOutputPin>(pin: &mut P) -> Option<RelayMode> {
+    pin.is_set_high()
+        .ok()
+        .map(|high| PinState::from(high).into())
+}
@@ -0,0 +1,78 @@
+//! Reboots and factory resets requested by the broker
+//!
+//! A factory reset takes two round trips: the plug first hands out a
+//! [Challenge] token, and only wipes itself if the same connection sends it
+//! back before it expires. A stray or replayed message can't wipe a plug.
+
+use common::FACTORY_RESET_TOKEN_SECS;
+use embassy_time::{Duration, Instant};
+
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(FACTORY_RESET_TOKEN_SECS);
+
+/// Token handed out for a factory reset, valid for [TOKEN_LIFETIME]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Challenge {
+    token: u32,
+    issued: Instant,
+}
+
+impl Challenge {
+    pub fn new(token: u32, at: Instant) -> Self {
+        Self { token, issued: at }
+    }
+
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    /// Whether `token`, received at `at`, answers this challenge
+    pub fn confirms(&self, token: u32, at: Instant) -> bool {
+        token == self.token
+            && at
+                .checked_duration_since(self.issued)
+                .is_some_and(|age| age < TOKEN_LIFETIME)
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use esp::*;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+    use embassy_time::{Duration, Timer};
+
+    use crate::{error, identity, storage, warn};
+
+    /// Time for the answer to reach the broker before resetting
+    const GRACE: Duration = Duration::from_millis(500);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Restart {
+        Reboot,
+        /// Wipe everything, then come back as `id`
+        FactoryReset {
+            id: uuid::Uuid,
+        },
+    }
+
+    pub static RESTART_SIGNAL: Signal<CriticalSectionRawMutex, Restart> = Signal::new();
+
+    pub async fn run() -> ! {
+        let restart = RESTART_SIGNAL.wait().await;
+        Timer::after(GRACE).await;
+        if let Restart::FactoryReset { id } = restart {
+            warn!("[restart] Factory reset requested by the broker");
+            if let Err(e) = storage::wipe().await {
+                error!("[restart] Failed to wipe storage: {}", e);
+            }
+            if let Err(e) = identity::store(id).await {
+                error!("[restart] Failed to store new identity: {}", e);
+            }
+        } else {
+            warn!("[restart] Reboot requested by the broker");
+        }
+        esp_hal::system::software_reset()
+    }
+}
@@ -0,0 +1,154 @@
+//! Picking which access point to join and when to leave it
+//!
+//! One scan is ranked against every known network, the plug then joins the
+//! best AP by BSSID so it doesn't end up on a weaker one broadcasting the same
+//! SSID. While connected, [Roamer] watches the RSSI and asks for a rescan once
+//! the signal has stayed weak for a while.
+//!
+//! Nothing here talks to the radio, every call takes the current [Instant]
+//! and the scan results so it can be driven from tests.
+
+use embassy_time::{Duration, Instant};
+
+/// Most APs remembered from a scan, anything further down isn't worth trying
+pub const MAX_CHOICES: usize = 8;
+/// APs weaker than this are left out, joining them tends to time out
+pub const MIN_RSSI: i8 = -88;
+/// RSSI below which a stronger AP is looked for
+pub const ROAM_THRESHOLD: i8 = -75;
+/// How long the signal has to stay below [ROAM_THRESHOLD] before rescanning,
+/// so a person walking past doesn't trigger it
+pub const ROAM_AFTER: Duration = Duration::from_secs(30);
+/// Shortest time between roaming scans, the plug is deaf while scanning
+pub const ROAM_COOLDOWN: Duration = Duration::from_secs(300);
+/// How much stronger another AP has to be to be worth the reconnect
+pub const ROAM_HYSTERESIS: i8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Security {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+}
+
+impl Security {
+    /// Score added to the RSSI, enough to break near ties in favor of the
+    /// better protected AP but never to prefer a much weaker one
+    pub fn bonus(self) -> i16 {
+        match self {
+            Security::Open | Security::Wep => 0,
+            Security::Wpa => 1,
+            Security::Wpa2 => 3,
+            Security::Wpa3 => 5,
+        }
+    }
+}
+
+/// An AP seen in a scan
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub ssid: &'a str,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+    pub security: Security,
+}
+
+/// A known network reachable through a specific AP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Choice {
+    /// Index into the known networks passed to [rank]
+    pub network: usize,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+    pub score: i16,
+}
+
+/// Ranks the scanned APs belonging to one of the `known` SSIDs, best first
+///
+/// Ties keep the order of `known`, so stored credentials still win over the
+/// built in ones when both are equally good.
+pub fn rank(scan: &[Candidate], known: &[&str]) -> heapless::Vec<Choice, MAX_CHOICES> {
+    let mut choices = heapless::Vec::<Choice, MAX_CHOICES>::new();
+    for ap in scan.iter().filter(|ap| ap.rssi >= MIN_RSSI) {
+        let Some(network) = known.iter().position(|k| *k == ap.ssid) else {
+            continue;
+        };
+        // some drivers report the same AP twice per scan
+        if choices.iter().any(|c| c.bssid == ap.bssid) {
+            continue;
+        }
+        let choice = Choice {
+            network,
+            bssid: ap.bssid,
+            channel: ap.channel,
+            rssi: ap.rssi,
+            score: ap.rssi as i16 + ap.security.bonus(),
+        };
+        if choices.is_full() {
+            let worst = choices.len() - 1;
+            sort(&mut choices);
+            if beats(&choice, &choices[worst]) {
+                choices[worst] = choice;
+            }
+        } else {
+            let _ = choices.push(choice);
+        }
+    }
+    sort(&mut choices);
+    choices
+}
+
+fn beats(a: &Choice, b: &Choice) -> bool {
+    (a.score, core::cmp::Reverse(a.network)) > (b.score, core::cmp::Reverse(b.network))
+}
+
+fn sort(choices: &mut [Choice]) {
+    choices.sort_unstable_by_key(|c| (core::cmp::Reverse(c.score), c.network));
+}
+
+/// Decides when to look for a better AP while connected
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Roamer {
+    weak_since: Option<Instant>,
+    last_scan: Option<Instant>,
+}
+
+impl Roamer {
+    pub const fn new() -> Self {
+        Self {
+            weak_since: None,
+            last_scan: None,
+        }
+    }
+
+    /// Takes the current RSSI, returns `true` when it's time to rescan
+    pub fn poll(&mut self, at: Instant, rssi: i8) -> bool {
+        if rssi >= ROAM_THRESHOLD {
+            self.weak_since = None;
+            return false;
+        }
+        let weak_since = *self.weak_since.get_or_insert(at);
+        if at.saturating_duration_since(weak_since) < ROAM_AFTER {
+            return false;
+        }
+        if self
+            .last_scan
+            .is_some_and(|l| at.saturating_duration_since(l) < ROAM_COOLDOWN)
+        {
+            return false;
+        }
+        self.last_scan = Some(at);
+        true
+    }
+
+    /// Whether `best` from a fresh [rank] is worth leaving `current` for
+    pub fn should_roam(current: &Choice, rssi: i8, best: &Choice) -> bool {
+        best.bssid != current.bssid && best.rssi as i16 >= rssi as i16 + ROAM_HYSTERESIS as i16
+    }
+}
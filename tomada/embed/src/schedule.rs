@@ -0,0 +1,101 @@
+//! Weekly schedule run against the plug's own [clock]
+//!
+//! Remote and local commands always apply right away. An entry only acts once,
+//! at its own minute, so a manual change lasts until the next entry for that
+//! channel, and an entry cancels a timed command running on its channel.
+//! Entries missed while the plug was off or didn't know the time are not
+//! caught up on.
+
+use core::cell::RefCell;
+
+use common::{Schedule, ScheduleEntry};
+use embassy_sync::blocking_mutex::{Mutex as BlockingMutex, raw::CriticalSectionRawMutex};
+#[cfg(feature = "esp")]
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+use crate::{RelayMode, board, clock, debug, info, relay};
+#[cfg(feature = "esp")]
+use crate::{
+    error,
+    storage::{self, Record},
+};
+
+static SCHEDULE: BlockingMutex<CriticalSectionRawMutex, RefCell<Schedule>> =
+    BlockingMutex::new(RefCell::new(Schedule {
+        utc_offset: 0,
+        entries: heapless::Vec::new(),
+    }));
+
+/// Raised when the schedule changes and has to be saved
+#[cfg(feature = "esp")]
+static SCHEDULE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Current schedule
+pub fn get() -> Schedule {
+    SCHEDULE.lock(|s| s.borrow().clone())
+}
+
+/// Replaces the schedule, dropping entries this board can't run
+///
+/// Returns the schedule that was kept.
+pub fn set(mut schedule: Schedule) -> Schedule {
+    schedule.entries.retain(valid);
+    info!(
+        "[schedule] New schedule with {} entries",
+        schedule.entries.len()
+    );
+    SCHEDULE.lock(|s| *s.borrow_mut() = schedule.clone());
+    #[cfg(feature = "esp")]
+    SCHEDULE_CHANGED.signal(());
+    schedule
+}
+
+fn valid(entry: &ScheduleEntry) -> bool {
+    (entry.channel as usize) < board::RELAY_COUNT && entry.minute < 24 * 60 && entry.days != 0
+}
+
+/// Switches the relays as the schedule says, once the clock is set
+pub async fn run() -> ! {
+    let mut last_minute = None;
+    loop {
+        let Some(now) = clock::now() else {
+            Timer::after_secs(1).await;
+            continue;
+        };
+        // the clock may be set back into a minute that already ran
+        if last_minute != Some(now / 60) {
+            last_minute = Some(now / 60);
+            SCHEDULE.lock(|s| {
+                for entry in s.borrow().due(now) {
+                    debug!("[schedule] Running {}", entry);
+                    relay::request(entry.channel, RelayMode::from(entry.on));
+                }
+            });
+        }
+        Timer::after_secs(60 - now % 60).await;
+    }
+}
+
+/// Loads the schedule saved in flash
+#[cfg(feature = "esp")]
+pub async fn load() {
+    match storage::load::<Schedule>(Record::Schedule).await {
+        Ok(Some(schedule)) => {
+            SCHEDULE.lock(|s| *s.borrow_mut() = schedule);
+        }
+        Ok(None) => {}
+        Err(e) => error!("[schedule] Failed to load schedule: {}", e),
+    }
+}
+
+/// Saves the schedule whenever it changes
+#[cfg(feature = "esp")]
+pub async fn persist_task() -> ! {
+    loop {
+        SCHEDULE_CHANGED.wait().await;
+        if let Err(e) = storage::store(Record::Schedule, &get()).await {
+            error!("[schedule] Failed to save schedule: {}", e);
+        }
+    }
+}
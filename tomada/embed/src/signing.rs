@@ -0,0 +1,43 @@
+//! Signatures on firmware images
+//!
+//! Images are signed with ECDSA P-256 over their SHA-256, which the plug
+//! already computes while writing them, so nothing has to be read back. The
+//! signature is DER, as `openssl dgst -sha256 -sign` writes it, and the
+//! trusted public key is a SEC1 point in hex, compiled into the firmware.
+
+use p256::ecdsa::{Signature, VerifyingKey, signature::hazmat::PrehashVerifier};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignatureError {
+    /// The trusted key itself doesn't parse, the firmware was built wrong
+    Key,
+    Malformed,
+    /// Well formed, but not made by the trusted key over this image
+    Invalid,
+}
+
+/// Checks `signature` over an image hashing to `sha256` against
+/// `public_key`
+pub fn verify(public_key: &str, sha256: &[u8; 32], signature: &[u8]) -> Result<(), SignatureError> {
+    let mut point = [0u8; 65];
+    let point = decode_hex(public_key, &mut point).ok_or(SignatureError::Key)?;
+    let key = VerifyingKey::from_sec1_bytes(point).map_err(|_| SignatureError::Key)?;
+    let signature = Signature::from_der(signature).map_err(|_| SignatureError::Malformed)?;
+    key.verify_prehash(sha256, &signature)
+        .map_err(|_| SignatureError::Invalid)
+}
+
+/// Decodes `hex` into the start of `buf`, `None` if it isn't hex or doesn't
+/// fit
+fn decode_hex<'b>(hex: &str, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > buf.len() {
+        return None;
+    }
+    for (byte, pair) in buf.iter_mut().zip(hex.chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(&buf[..hex.len() / 2])
+}
@@ -0,0 +1,165 @@
+//! SNTP client (RFC 4330)
+//!
+//! Resyncs the [clock] every [RESYNC_INTERVAL]. After [BROKER_FALLBACK]
+//! failed attempts in a row the broker is asked for the time instead.
+
+#[cfg(feature = "esp")]
+use common::MessagePayload;
+#[cfg(feature = "esp")]
+use dotenvy_macro::option_dotenv;
+#[cfg(feature = "esp")]
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::Duration;
+#[cfg(feature = "esp")]
+use embassy_time::{Instant, Timer, WithTimeout};
+
+#[cfg(feature = "esp")]
+use crate::{
+    clock::{self, TimeSource},
+    debug, info, warn,
+    wifi::WIFI_MSG_CHANNEL,
+};
+
+pub const NTP_PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+/// Seconds between the NTP epoch (1900) and the Unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+pub const RESYNC_INTERVAL: Duration = Duration::from_secs(3600);
+#[cfg(feature = "esp")]
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "esp")]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const BROKER_FALLBACK: u32 = 3;
+
+#[cfg(feature = "esp")]
+const NTP_SERVER: &str = match option_dotenv!("NTP_SERVER") {
+    Some(s) => s,
+    None => "pool.ntp.org",
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SntpError {
+    Dns,
+    Network,
+    Timeout,
+    /// Too short, not from a server or not an answer to our request
+    BadReply,
+    /// The server asked us to go away or isn't synchronized itself
+    KissOfDeath,
+}
+
+/// Client request, `nonce` comes back in the reply's originate timestamp
+pub fn request(nonce: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    // no leap second warning, version 4, client mode
+    packet[0] = (4 << 3) | 3;
+    packet[40..48].copy_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+/// Reads the server's transmit time from a reply to [request], in
+/// milliseconds since the Unix epoch
+pub fn parse_reply(reply: &[u8], nonce: u64) -> Result<u64, SntpError> {
+    if reply.len() < PACKET_LEN {
+        return Err(SntpError::BadReply);
+    }
+    let mode = reply[0] & 0b111;
+    let originate = u64::from_be_bytes(reply[24..32].try_into().unwrap());
+    if mode != 4 || originate != nonce {
+        return Err(SntpError::BadReply);
+    }
+    let leap = reply[0] >> 6;
+    let stratum = reply[1];
+    if stratum == 0 || stratum > 15 || leap == 3 {
+        return Err(SntpError::KissOfDeath);
+    }
+
+    let seconds = u32::from_be_bytes(reply[40..44].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(reply[44..48].try_into().unwrap()) as u64;
+    // the 32 bit seconds wrapped in 2036
+    let seconds = if seconds < NTP_UNIX_OFFSET {
+        seconds + (1 << 32)
+    } else {
+        seconds
+    };
+    Ok((seconds - NTP_UNIX_OFFSET) * 1000 + ((fraction * 1000) >> 32))
+}
+
+/// Keeps the [clock] in sync for as long as the network is up
+#[cfg(feature = "esp")]
+pub async fn run(stack: Stack<'_>) -> ! {
+    let mut failures = 0;
+    loop {
+        match query(stack).await {
+            Ok(time) => {
+                failures = 0;
+                clock::sync(time, TimeSource::Sntp);
+                Timer::after(RESYNC_INTERVAL).await;
+            }
+            Err(e) => {
+                failures += 1;
+                warn!("[sntp] Sync failed ({} in a row): {}", failures, e);
+                if failures >= BROKER_FALLBACK {
+                    info!("[sntp] Asking the broker for the time");
+                    WIFI_MSG_CHANNEL.send(MessagePayload::TimeRequest).await;
+                }
+                let retry = if failures >= BROKER_FALLBACK {
+                    RESYNC_INTERVAL
+                } else {
+                    RETRY_INTERVAL
+                };
+                Timer::after(retry).await;
+            }
+        }
+    }
+}
+
+/// Asks [NTP_SERVER] for the time, compensating half the round trip
+#[cfg(feature = "esp")]
+async fn query(stack: Stack<'_>) -> Result<u64, SntpError> {
+    let addr = stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .ok()
+        .and_then(|a| a.first().copied())
+        .ok_or(SntpError::Dns)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0u8; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0u8; 128];
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    sock.bind(0).map_err(|_| SntpError::Network)?;
+
+    let sent = Instant::now();
+    // not a secret, only has to tell our reply apart from stale ones
+    let nonce = sent.as_ticks() | 1;
+    let server = IpEndpoint::new(addr, NTP_PORT);
+    sock.send_to(&request(nonce), server)
+        .await
+        .map_err(|_| SntpError::Network)?;
+
+    // servers may append extension fields
+    let mut reply = [0u8; 128];
+    loop {
+        let (len, from) = sock
+            .recv_from(&mut reply)
+            .with_timeout(REPLY_TIMEOUT)
+            .await
+            .map_err(|_| SntpError::Timeout)?
+            .map_err(|_| SntpError::Network)?;
+        if from.endpoint != server {
+            debug!("[sntp] Ignoring packet from {}", from.endpoint);
+            continue;
+        }
+        let time = parse_reply(&reply[..len], nonce)?;
+        let round_trip = Instant::now().saturating_duration_since(sent);
+        return Ok(time + round_trip.as_millis() / 2);
+    }
+}
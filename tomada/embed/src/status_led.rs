@@ -1,16 +1,20 @@
-use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either3, select, select3};
 use embassy_sync::{
     blocking_mutex::{NoopMutex, raw::CriticalSectionRawMutex},
     signal::Signal,
 };
-use embassy_time::Timer;
-use esp_hal::gpio::{Level, Output, OutputConfig, OutputPin};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::{OutputPin, PinState};
 
-use crate::RELAY_STATUS;
+use crate::{
+    RELAY_STATUS, board,
+    config::{self, CONFIG, LedBehavior},
+};
 
-pub struct StatusLed<'a> {
-    plug_led: NoopMutex<Output<'a>>,
-    led: NoopMutex<Output<'a>>,
+/// LED polarity comes from the [board] profile
+pub struct StatusLed<L> {
+    plug_led: Option<NoopMutex<L>>,
+    led: NoopMutex<L>,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -19,18 +23,25 @@ pub enum LedStatusCode {
     Disconnected,
     Connecting,
     Working,
+    /// Waiting for credentials on the provisioning access point
+    Provisioning,
+    /// Asked to stand out from other plugs for a while, the previous code is
+    /// shown again afterwards
+    Identify {
+        seconds: u32,
+    },
     #[default]
     Idle,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PlugLedMode {
     On,
     Off,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum OnboardLedMode {
     On,
@@ -39,15 +50,12 @@ pub enum OnboardLedMode {
 
 pub static LED_STATUS: Signal<CriticalSectionRawMutex, LedStatusCode> = Signal::new();
 
-impl<'a> StatusLed<'a> {
-    pub fn new(onboard_pin: impl OutputPin + 'a, plug_pin: impl OutputPin + 'a) -> Self {
+impl<L: OutputPin> StatusLed<L> {
+    /// `plug_pin` is `None` on boards without a plug LED
+    pub fn new(onboard_pin: L, plug_pin: Option<L>) -> Self {
         Self {
-            led: NoopMutex::new(Output::new(
-                onboard_pin,
-                Level::High,
-                OutputConfig::default(),
-            )),
-            plug_led: NoopMutex::new(Output::new(plug_pin, Level::High, OutputConfig::default())),
+            led: NoopMutex::new(onboard_pin),
+            plug_led: plug_pin.map(NoopMutex::new),
         }
     }
 
@@ -78,14 +86,17 @@ impl<'a> StatusLed<'a> {
     fn set_onboard_led(&self, mode: OnboardLedMode) {
         // Safety: lock_mut isn't called re-entrantly
         unsafe {
-            self.led.lock_mut(|l| l.set_level(mode.into()));
+            let _ = self.led.lock_mut(|l| l.set_state(mode.into()));
         }
     }
 
     fn set_plug_led(&self, mode: PlugLedMode) {
+        let Some(plug_led) = &self.plug_led else {
+            return;
+        };
         // Safety: lock_mut isn't called re-entrantly
         unsafe {
-            self.plug_led.lock_mut(|l| l.set_level(mode.into()));
+            let _ = plug_led.lock_mut(|l| l.set_state(mode.into()));
         }
     }
 
@@ -104,68 +115,112 @@ impl<'a> StatusLed<'a> {
             LedStatusCode::Idle => {
                 self.long_blink().await;
             }
+            LedStatusCode::Provisioning => {
+                self.long_blink().await;
+                self.short_blink().await;
+            }
+            LedStatusCode::Identify { .. } => {
+                self.short_blink().await;
+                self.short_blink().await;
+                self.long_blink().await;
+            }
             LedStatusCode::Working => {
-                let state = rcv.get().await;
-                match state {
-                    crate::RelayMode::Open => self.led_off(),
-                    crate::RelayMode::Closed => self.led_on(),
-                };
-                match rcv.changed().await {
-                    crate::RelayMode::Open => self.led_off(),
-                    crate::RelayMode::Closed => self.led_on(),
-                };
+                // lit while any channel is on
+                if rcv.get().await.any() {
+                    self.led_on();
+                } else {
+                    self.led_off();
+                }
+                if rcv.changed().await.any() {
+                    self.led_on();
+                } else {
+                    self.led_off();
+                }
             }
         };
 
-        let mut code = LED_STATUS.try_take().unwrap_or_default();
+        let mut config = CONFIG.receiver().unwrap();
+        let mut code = LedStatusCode::default();
+        let mut identify: Option<(LedStatusCode, Instant)> = None;
+        let mut next = LED_STATUS.try_take();
 
         loop {
-            match select(LED_STATUS.wait(), async {
-                loop {
-                    blink(code).await;
+            match next {
+                Some(LedStatusCode::Identify { seconds }) => {
+                    let until = Instant::now() + Duration::from_secs(seconds.into());
+                    identify = Some((LedStatusCode::Identify { seconds }, until));
+                }
+                Some(c) => code = c,
+                None => {}
+            }
+            let behavior = config::get().led;
+            match select3(LED_STATUS.wait(), config.changed(), async {
+                // already over if the code changed after it ended
+                if let Some((identify, until)) = identify {
+                    select(Timer::at(until), async {
+                        loop {
+                            blink(identify).await;
+                        }
+                    })
+                    .await;
+                }
+                match behavior {
+                    LedBehavior::Status => loop {
+                        blink(code).await;
+                    },
+                    LedBehavior::RelayOnly => loop {
+                        blink(LedStatusCode::Working).await;
+                    },
+                    LedBehavior::Off => {
+                        self.led_off();
+                        core::future::pending::<()>().await
+                    }
                 }
             })
             .await
             {
-                Either::First(c) => code = c,
-                Either::Second(_) => unreachable!(),
+                Either3::First(c) => next = Some(c),
+                Either3::Second(_) => next = None,
+                Either3::Third(()) => unreachable!(),
             };
         }
     }
 }
 
-impl From<PlugLedMode> for Level {
+impl From<PlugLedMode> for PinState {
     fn from(value: PlugLedMode) -> Self {
         match value {
-            PlugLedMode::On => Level::Low,
-            PlugLedMode::Off => Level::High,
+            PlugLedMode::On => board::PLUG_LED_ACTIVE,
+            PlugLedMode::Off => !board::PLUG_LED_ACTIVE,
         }
     }
 }
 
-impl From<Level> for PlugLedMode {
-    fn from(value: Level) -> Self {
-        match value {
-            Level::Low => PlugLedMode::On,
-            Level::High => PlugLedMode::Off,
+impl From<PinState> for PlugLedMode {
+    fn from(value: PinState) -> Self {
+        if value == board::PLUG_LED_ACTIVE {
+            PlugLedMode::On
+        } else {
+            PlugLedMode::Off
         }
     }
 }
 
-impl From<OnboardLedMode> for Level {
+impl From<OnboardLedMode> for PinState {
     fn from(value: OnboardLedMode) -> Self {
         match value {
-            OnboardLedMode::On => Level::Low,
-            OnboardLedMode::Off => Level::High,
+            OnboardLedMode::On => board::ONBOARD_LED_ACTIVE,
+            OnboardLedMode::Off => !board::ONBOARD_LED_ACTIVE,
         }
     }
 }
 
-impl From<Level> for OnboardLedMode {
-    fn from(value: Level) -> Self {
-        match value {
-            Level::Low => OnboardLedMode::On,
-            Level::High => OnboardLedMode::Off,
+impl From<PinState> for OnboardLedMode {
+    fn from(value: PinState) -> Self {
+        if value == board::ONBOARD_LED_ACTIVE {
+            OnboardLedMode::On
+        } else {
+            OnboardLedMode::Off
         }
     }
 }
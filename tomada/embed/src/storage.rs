@@ -0,0 +1,302 @@
+//! Flash-backed persistent records
+//!
+//! Every [Record] owns one sector of the `nvs` data partition. New values are
+//! appended after the previous ones and the sector is only erased once it
+//! fills up, so frequently written records don't wear out the same cells.
+//!
+//! Entry layout (all fields little endian, entries aligned to 4 bytes):
+//!
+//! | len: u16 | checksum: u16 | postcard payload | 0xFF padding |
+
+use common::{PlugConfig, PowerOnBehavior};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
+};
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{error, warn};
+
+const SECTOR_SIZE: u32 = FlashStorage::ERASE_SIZE as u32;
+const HEADER_SIZE: u32 = 4;
+/// Largest payload a single record can hold
+pub const MAX_RECORD_SIZE: usize = 512;
+
+/// Persistent records, each one lives in its own sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Record {
+    /// Credentials received through the provisioning portal
+    WifiCredentials = 0,
+    /// [Settings] configured by the broker
+    Settings = 1,
+    /// Last relay states as a channel mask, written with wear limiting
+    RelayState = 2,
+    /// Image id of an update waiting for confirmation
+    OtaPending = 3,
+    /// [Schedule](common::Schedule) pushed by the broker
+    Schedule = 4,
+    /// Random UUID the plug connects to the broker with, made on first boot
+    Identity = 5,
+}
+
+impl Record {
+    pub const ALL: [Record; 6] = [
+        Record::WifiCredentials,
+        Record::Settings,
+        Record::RelayState,
+        Record::OtaPending,
+        Record::Schedule,
+        Record::Identity,
+    ];
+}
+
+/// What the broker configures, sharing one record because OTA never rewrites
+/// the partition table and plugs in the field have no sector to spare
+///
+/// Before [PlugConfig] existed the record held a bare [PowerOnBehavior],
+/// which [decode](Settings::decode) still reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    pub power_on: Option<PowerOnBehavior>,
+    pub config: Option<PlugConfig>,
+}
+
+impl Settings {
+    fn decode(bytes: &[u8]) -> Result<Self, StorageError> {
+        // an old entry is a single byte, too short to be a Settings
+        postcard::from_bytes(bytes)
+            .or_else(|_| {
+                postcard::from_bytes(bytes).map(|power_on| Self {
+                    power_on: Some(power_on),
+                    config: None,
+                })
+            })
+            .map_err(|_| StorageError::Postcard)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageError {
+    NoPartition,
+    PartitionTooSmall,
+    Flash,
+    TooLarge,
+    Postcard,
+}
+
+struct Storage {
+    flash: FlashStorage,
+    /// Offset of the `nvs` partition
+    base: u32,
+    /// Size of the `nvs` partition
+    len: u32,
+}
+
+static STORAGE: Mutex<CriticalSectionRawMutex, Option<Storage>> = Mutex::new(None);
+
+impl Storage {
+    fn new() -> Result<Self, StorageError> {
+        let mut flash = FlashStorage::new();
+        let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+        let (base, len) = {
+            let pt = partitions::read_partition_table(&mut flash, &mut pt_mem)
+                .map_err(|_| StorageError::NoPartition)?;
+            let nvs = pt
+                .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+                .map_err(|_| StorageError::NoPartition)?
+                .ok_or(StorageError::NoPartition)?;
+            (nvs.offset(), nvs.len())
+        };
+
+        Ok(Self { flash, base, len })
+    }
+
+    fn sector(&self, record: Record) -> Result<u32, StorageError> {
+        let start = record as u32 * SECTOR_SIZE;
+        if start + SECTOR_SIZE > self.len {
+            return Err(StorageError::PartitionTooSmall);
+        }
+        Ok(self.base + start)
+    }
+
+    fn read_header(&mut self, addr: u32) -> Result<Option<(u16, u16)>, StorageError> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        self.flash
+            .read(addr, &mut header)
+            .map_err(|_| StorageError::Flash)?;
+        if header == [0xFF; HEADER_SIZE as usize] {
+            return Ok(None);
+        }
+        Ok(Some((
+            u16::from_le_bytes([header[0], header[1]]),
+            u16::from_le_bytes([header[2], header[3]]),
+        )))
+    }
+
+    /// Walks the sector and returns the last valid entry and where free space
+    /// starts
+    fn scan(
+        &mut self,
+        record: Record,
+        buf: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<(Option<usize>, u32), StorageError> {
+        let sector = self.sector(record)?;
+        let mut offset = 0;
+        let mut latest = None;
+
+        while offset + HEADER_SIZE <= SECTOR_SIZE {
+            let Some((len, checksum)) = self.read_header(sector + offset)? else {
+                break;
+            };
+            let padded = padded_len(len as usize) as u32;
+            if len as usize > MAX_RECORD_SIZE || offset + HEADER_SIZE + padded > SECTOR_SIZE {
+                // garbage, treat the rest of the sector as used
+                offset = SECTOR_SIZE;
+                break;
+            }
+
+            let mut entry = [0u8; MAX_RECORD_SIZE];
+            self.flash
+                .read(sector + offset + HEADER_SIZE, &mut entry[..padded as usize])
+                .map_err(|_| StorageError::Flash)?;
+            if fletcher16(&entry[..len as usize]) == checksum {
+                buf[..len as usize].copy_from_slice(&entry[..len as usize]);
+                latest = Some(len as usize);
+            } else {
+                warn!("[storage] Skipping corrupted entry for {}", record);
+            }
+
+            offset += HEADER_SIZE + padded;
+        }
+
+        Ok((latest, offset))
+    }
+
+    fn load(
+        &mut self,
+        record: Record,
+        buf: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Option<usize>, StorageError> {
+        Ok(self.scan(record, buf)?.0)
+    }
+
+    fn store(&mut self, record: Record, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(StorageError::TooLarge);
+        }
+
+        let mut current = [0u8; MAX_RECORD_SIZE];
+        let (latest, mut offset) = self.scan(record, &mut current)?;
+        if latest.is_some_and(|len| &current[..len] == data) {
+            return Ok(());
+        }
+
+        let sector = self.sector(record)?;
+        let padded = padded_len(data.len()) as u32;
+        if offset + HEADER_SIZE + padded > SECTOR_SIZE {
+            self.flash
+                .erase(sector, sector + SECTOR_SIZE)
+                .map_err(|_| StorageError::Flash)?;
+            offset = 0;
+        }
+
+        let mut entry = [0xFFu8; HEADER_SIZE as usize + MAX_RECORD_SIZE];
+        entry[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        entry[2..4].copy_from_slice(&fletcher16(data).to_le_bytes());
+        entry[4..4 + data.len()].copy_from_slice(data);
+
+        self.flash
+            .write(sector + offset, &entry[..(HEADER_SIZE + padded) as usize])
+            .map_err(|_| StorageError::Flash)
+    }
+
+    fn erase(&mut self, record: Record) -> Result<(), StorageError> {
+        let sector = self.sector(record)?;
+        self.flash
+            .erase(sector, sector + SECTOR_SIZE)
+            .map_err(|_| StorageError::Flash)
+    }
+}
+
+async fn with_storage<T>(
+    f: impl FnOnce(&mut Storage) -> Result<T, StorageError>,
+) -> Result<T, StorageError> {
+    let mut storage = STORAGE.lock().await;
+    if storage.is_none() {
+        *storage = Some(Storage::new().inspect_err(|e| {
+            error!("[storage] Failed to open nvs partition: {}", e);
+        })?);
+    }
+    f(storage.as_mut().unwrap())
+}
+
+/// Reads the latest value stored for `record`
+pub async fn load<T: DeserializeOwned>(record: Record) -> Result<Option<T>, StorageError> {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let Some(len) = with_storage(|s| s.load(record, &mut buf)).await? else {
+        return Ok(None);
+    };
+    postcard::from_bytes(&buf[..len])
+        .map(Some)
+        .map_err(|_| StorageError::Postcard)
+}
+
+/// Stores a new value for `record`, does nothing if it didn't change
+pub async fn store<T: Serialize>(record: Record, value: &T) -> Result<(), StorageError> {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let data = postcard::to_slice(value, &mut buf).map_err(|_| StorageError::TooLarge)?;
+    with_storage(|s| s.store(record, data)).await
+}
+
+/// Reads the stored [Settings]
+pub async fn load_settings() -> Result<Settings, StorageError> {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    match with_storage(|s| s.load(Record::Settings, &mut buf)).await? {
+        Some(len) => Settings::decode(&buf[..len]),
+        None => Ok(Settings::default()),
+    }
+}
+
+/// Changes the stored [Settings] with `f`, holding the flash throughout so
+/// concurrent updates of different fields don't undo each other
+pub async fn update_settings(f: impl FnOnce(&mut Settings)) -> Result<(), StorageError> {
+    with_storage(|s| {
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let mut settings = match s.load(Record::Settings, &mut buf)? {
+            // whatever can't be read gets replaced
+            Some(len) => Settings::decode(&buf[..len]).unwrap_or_default(),
+            None => Settings::default(),
+        };
+        f(&mut settings);
+        let data = postcard::to_slice(&settings, &mut buf).map_err(|_| StorageError::TooLarge)?;
+        s.store(Record::Settings, data)
+    })
+    .await
+}
+
+/// Forgets the value stored for `record`
+pub async fn erase(record: Record) -> Result<(), StorageError> {
+    with_storage(|s| s.erase(record)).await
+}
+
+/// Forgets every record, used for factory resets
+pub async fn wipe() -> Result<(), StorageError> {
+    with_storage(|s| Record::ALL.into_iter().try_for_each(|r| s.erase(r))).await
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(HEADER_SIZE as usize) * HEADER_SIZE as usize
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (a, b) = data.iter().fold((0u16, 0u16), |(a, b), d| {
+        let a = (a + *d as u16) % 255;
+        (a, (b + a) % 255)
+    });
+    (b << 8) | a
+}
@@ -6,29 +6,53 @@ use core::{
 };
 
 use crate::{debug, error, info, warn};
-use alloc::string::String;
-use common::{DisconnectReason, MessagePayload, PlugMessage};
+use alloc::{string::String, vec::Vec};
+use common::{ChannelMask, DisconnectReason, MessagePayload, OtaStatus, PlugMessage};
 use dotenvy_macro::{dotenv, option_dotenv};
-use embassy_futures::select::{Either, select, select3};
+use embassy_futures::{
+    join::{join, join4},
+    select::{Either, Either3, select, select3},
+};
 use embassy_net::{
-    Config, DhcpConfig, IpListenEndpoint, Runner, Stack, StackResources,
-    udp::{PacketMetadata, RecvError, SendError, UdpSocket},
+    Config, DhcpConfig, IpListenEndpoint, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
 };
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
-    lazy_lock::LazyLock,
+    channel::Channel,
     mutex::Mutex,
-    watch::{Receiver, Watch},
+    watch::Receiver,
 };
-use embassy_time::{Duration, TimeoutError, Timer, WithTimeout};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+#[cfg(feature = "tls")]
+use embedded_tls::{SplitConnectionState, TlsConnection};
 use esp_wifi::wifi::{
-    ClientConfiguration, ScanConfig, WifiController, WifiDevice, WifiError, WifiEvent,
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, ScanConfig, WifiController,
+    WifiDevice, WifiError, WifiEvent,
 };
-use futures::FutureExt;
+use portable_atomic::Ordering;
 
+#[cfg(feature = "lan-api")]
+use crate::lan_api;
 use crate::{
-    RELAY_SIGNAL, RELAY_STATUS, RelayMode,
+    RELAY_STATUS, RelayMode, RelayRequest, board,
+    clock::{self, TimeSource},
+    config, crash, health, identity,
+    link::{
+        self, ConnError, Datagram, Fallback, FrameReader, Incoming, Link, StreamRx, StreamTx,
+        Transport,
+    },
+    netlog,
+    ota::{self, OTA_BUSY, OTA_CONFIRM, OTA_SIGNAL, OtaRequest},
+    power_on,
+    provisioning::{self, WifiCredentials},
+    relay,
+    restart::{self, Challenge, Restart},
+    roaming::{self, Candidate, Choice, Roamer, Security},
+    schedule, sntp,
     status_led::{LED_STATUS, LedStatusCode},
+    storage::{self, Record},
 };
 
 extern crate alloc;
@@ -37,12 +61,31 @@ pub struct WifiHandler<'a> {
     controller: Mutex<NoopRawMutex, WifiController<'a>>,
     stack: Stack<'a>,
     runner: Mutex<NoopRawMutex, Runner<'a, WifiDevice<'a>>>,
+    /// Stack for the provisioning access point
+    ap_stack: Stack<'a>,
+    ap_runner: Mutex<NoopRawMutex, Runner<'a, WifiDevice<'a>>>,
+    mac_address: [u8; 6],
 }
 
-const BROKER_IP: &str = dotenv!("BROKER_IP");
+pub(crate) const BROKER_IP: &str = dotenv!("BROKER_IP");
 const BROKER_PORT: &str = dotenv!("BROKER_PORT");
 
-const SSID_PASSWORD: (&str, &str) = (dotenv!("SSID"), dotenv!("PASSWORD"));
+/// How often the RSSI is checked for roaming
+const ROAM_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Consecutive failed rounds through every known network before falling back
+/// to the provisioning access point
+const PROVISIONING_AFTER_FAILURES: u32 = 5;
+/// How long the portal stays up before the known networks are tried again,
+/// after a power cut the router usually comes back later than the plugs
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const SSID_PASSWORD: Option<(&str, &str)> =
+    match (option_dotenv!("SSID"), option_dotenv!("PASSWORD")) {
+        (Some(s), Some(p)) => Some((s, p)),
+        (None, None) => None,
+        _ => panic!(),
+    };
 
 const SSID_PASSWORD2: Option<(&str, &str)> =
     match (option_dotenv!("SSID2"), option_dotenv!("PASSWORD2")) {
@@ -62,28 +105,42 @@ impl<'a> WifiHandler<'a> {
     pub fn new(
         controller: WifiController<'a>,
         device: WifiDevice<'a>,
-        stack_resources: &'a mut StackResources<5>,
+        stack_resources: &'a mut StackResources<8>,
+        ap_device: WifiDevice<'a>,
+        ap_stack_resources: &'a mut StackResources<4>,
         seed: u64,
     ) -> Self {
+        let mac_address = device.mac_address();
         let (stack, runner) = embassy_net::new(
             device,
             Config::dhcpv4(DhcpConfig::default()),
             stack_resources,
             seed,
         );
+        let (ap_stack, ap_runner) = embassy_net::new(
+            ap_device,
+            Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(provisioning::AP_ADDR, 24),
+                gateway: Some(provisioning::AP_ADDR),
+                dns_servers: Default::default(),
+            }),
+            ap_stack_resources,
+            seed.rotate_left(32),
+        );
 
         Self {
             controller: Mutex::new(controller),
             stack,
             runner: Mutex::new(runner),
+            ap_stack,
+            ap_runner: Mutex::new(ap_runner),
+            mac_address,
         }
     }
 
-    pub async fn connect(
-        &self,
-        ssid: impl Into<String> + Deref<Target = str>,
-        passwd: Option<impl Into<String> + Deref<Target = str>>,
-    ) -> Result<(), WifiError> {
+    /// Scans once and joins the best AP of any of `networks`, as ranked by
+    /// [roaming::rank], returning the AP it joined
+    pub async fn connect(&self, networks: &[(&str, &str)]) -> Result<Choice, WifiError> {
         let mut controller = self.controller.lock().await;
 
         controller.set_mode(esp_wifi::wifi::WifiMode::Sta)?;
@@ -100,89 +157,220 @@ impl<'a> WifiHandler<'a> {
         controller.start_async().await?;
         info!("[wifi] Starting controller");
 
-        // Safety: safe to call with valid value 8
-        // idk where i saw this but thank you to whoever suggested lowering tx power
-        unsafe {
-            esp_wifi_sys::include::esp_wifi_set_max_tx_power(8);
-        }
+        set_tx_power(config::get().tx_power);
 
         controller.is_started()?;
 
-        for ap in controller
-            .scan_with_config_async(ScanConfig {
-                channel: None,
-                ..Default::default()
-            })
-            .await?
-        {
-            debug!("[wifi] Found AP ({}dBm): {}", ap.signal_strength, &*ap.ssid);
+        let names = networks.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        let choices = scan(&mut controller, &names).await?;
+        if choices.is_empty() {
+            warn!("[wifi] None of the known networks is in range");
         }
 
-        info!("[wifi] Connecting to {}", &*ssid);
+        for choice in choices {
+            let (ssid, passwd) = networks[choice.network];
+            info!(
+                "[wifi] Connecting to {} via {:02X} ({}dBm)",
+                ssid, choice.bssid, choice.rssi
+            );
 
-        controller.set_configuration(&esp_wifi::wifi::Configuration::Client(
-            ClientConfiguration {
-                ssid: ssid.into(),
-                password: passwd.map(|i| i.into()).unwrap_or_default(),
-                ..Default::default()
-            },
-        ))?;
+            controller.set_configuration(&esp_wifi::wifi::Configuration::Client(
+                ClientConfiguration {
+                    ssid: ssid.into(),
+                    bssid: Some(choice.bssid),
+                    channel: Some(choice.channel),
+                    password: passwd.into(),
+                    ..Default::default()
+                },
+            ))?;
 
-        controller.connect_async().await?;
+            match controller.connect_async().await {
+                Ok(()) => {}
+                Err(WifiError::Disconnected) => continue,
+                Err(e) => return Err(e),
+            }
 
-        debug!("[wifi] Waiting for connection up");
-        loop {
-            if controller.is_connected()? {
-                return Ok(());
-            } else {
-                let _ = controller
-                    .wait_for_events(
-                        [WifiEvent::StaConnected, WifiEvent::StaDisconnected].into(),
-                        false,
-                    )
-                    .with_timeout(Duration::from_secs(1))
-                    .await;
+            debug!("[wifi] Waiting for connection up");
+            loop {
+                if controller.is_connected()? {
+                    return Ok(choice);
+                } else {
+                    let _ = controller
+                        .wait_for_events(
+                            [WifiEvent::StaConnected, WifiEvent::StaDisconnected].into(),
+                            false,
+                        )
+                        .with_timeout(Duration::from_secs(1))
+                        .await;
+                }
             }
         }
+        Err(WifiError::Disconnected)
     }
 
-    pub async fn connect_many<S, P>(
-        &self,
-        pairs: impl IntoIterator<Item = (S, P)>,
-    ) -> Result<(), WifiError>
-    where
-        S: Into<String> + Deref<Target = str>,
-        P: Into<String> + Deref<Target = str>,
-    {
-        for (ssid, passwd) in pairs {
-            if let Err(e) = self.connect(ssid, Some(passwd)).await {
-                if !matches!(e, WifiError::Disconnected) {
-                    return Err(e);
+    /// Returns once the AP drops the plug, or after leaving it for a
+    /// noticeably stronger one
+    async fn supervise(&self, current: Choice, networks: &[(&str, &str)]) {
+        let mut controller = self.controller.lock().await;
+        let names = networks.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        let mut roamer = Roamer::new();
+
+        loop {
+            if let Either::First(_) = select(
+                controller.wait_for_events([WifiEvent::StaDisconnected].into(), false),
+                Timer::after(ROAM_CHECK_INTERVAL),
+            )
+            .await
+            {
+                return;
+            }
+
+            let rssi = match controller.rssi() {
+                Ok(rssi) => rssi.clamp(i8::MIN as i32, 0) as i8,
+                Err(e) => {
+                    debug!("[wifi] Failed to read RSSI: {}", e);
+                    continue;
                 }
-            } else {
-                return Ok(());
+            };
+            health::set_link(rssi, current.channel);
+            if !roamer.poll(Instant::now(), rssi) {
+                continue;
+            }
+
+            info!("[wifi] Weak signal ({}dBm), looking for a better AP", rssi);
+            let choices = match scan(&mut controller, &names).await {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("[wifi] Roaming scan failed: {}", e);
+                    continue;
+                }
+            };
+            if let Some(best) = choices.first()
+                && Roamer::should_roam(&current, rssi, best)
+            {
+                info!("[wifi] Roaming to {:02X} ({}dBm)", best.bssid, best.rssi);
+                let _ = controller.disconnect_async().await;
+                return;
             }
         }
-        Err(WifiError::Disconnected)
+    }
+
+    /// Runs the provisioning portal on a `Tomada Goodwe-XXXX` access point,
+    /// only returns by rebooting once new credentials are saved
+    pub async fn provision(&self) -> ! {
+        LED_STATUS.signal(LedStatusCode::Provisioning);
+        self.portal().await
+    }
+
+    /// The [provisioning](WifiHandler::provision) portal, given up on after
+    /// [PORTAL_TIMEOUT] so the known networks get another try
+    async fn provision_for_a_while(&self) {
+        LED_STATUS.signal(LedStatusCode::Provisioning);
+        let _timeout = self.portal().with_timeout(PORTAL_TIMEOUT).await;
+        info!("[wifi] Nothing provisioned, trying the known networks again");
+        LED_STATUS.signal(LedStatusCode::Connecting);
+    }
+
+    async fn portal(&self) -> ! {
+        let mut ssid = String::from("Tomada Goodwe-");
+        for b in &self.mac_address[4..] {
+            let _ = core::fmt::write(&mut ssid, format_args!("{b:02X}"));
+        }
+        info!(
+            "[wifi] Starting provisioning access point {}",
+            ssid.as_str()
+        );
+
+        loop {
+            if let Err(e) = self.start_ap(ssid.clone()).await {
+                error!("[wifi] Failed to start access point: {}", e);
+                Timer::after_secs(1).await;
+                continue;
+            }
+
+            select(
+                self.ap_runner.lock().await.run(),
+                provisioning::run(self.ap_stack),
+            )
+            .await;
+        }
+    }
+
+    async fn start_ap(&self, ssid: String) -> Result<(), WifiError> {
+        let mut controller = self.controller.lock().await;
+
+        if controller.is_started()? {
+            controller.stop_async().await?;
+        }
+
+        controller.set_mode(esp_wifi::wifi::WifiMode::Ap)?;
+        controller.set_configuration(&esp_wifi::wifi::Configuration::AccessPoint(
+            AccessPointConfiguration {
+                ssid,
+                auth_method: AuthMethod::None,
+                max_connections: 4,
+                ..Default::default()
+            },
+        ))?;
+        controller.start_async().await
     }
 
     pub async fn run(&self) {
+        select(self.run_station(), provisioning::PROVISIONING_SIGNAL.wait()).await;
+        info!("[wifi] Provisioning requested");
+        self.provision().await;
+    }
+
+    async fn run_station(&self) {
         LED_STATUS.signal(LedStatusCode::Connecting);
+        let stored = match storage::load::<WifiCredentials>(Record::WifiCredentials).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("[wifi] Failed to read stored credentials: {}", e);
+                None
+            }
+        };
+
+        let networks = stored
+            .iter()
+            .map(|c| (c.ssid.as_str(), c.password.as_str()))
+            .chain(
+                [SSID_PASSWORD, SSID_PASSWORD2, SSID_PASSWORD3]
+                    .into_iter()
+                    .flatten(),
+            )
+            .collect::<Vec<_>>();
+
         loop {
             let mut delay = 1000;
+            let mut failures = 0;
 
-            while let Err(e) = self
-                .connect_many(
-                    [Some(SSID_PASSWORD), SSID_PASSWORD2, SSID_PASSWORD3]
-                        .into_iter()
-                        .flatten(),
-                )
-                .await
-            {
-                error!("[wifi] Connection failed, retrying in {}ms: {}", delay, e);
-                Timer::after_millis(delay).await;
-                delay = core::cmp::min(delay * 2, 10000);
-            }
+            let current = loop {
+                match self.connect(&networks).await {
+                    Ok(choice) => {
+                        health::connected();
+                        health::set_link(choice.rssi, choice.channel);
+                        break choice;
+                    }
+                    Err(_) if networks.is_empty() => {
+                        warn!("[wifi] No network configured, starting provisioning");
+                        self.provision().await;
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        if failures >= PROVISIONING_AFTER_FAILURES {
+                            warn!("[wifi] No known network reachable, starting provisioning");
+                            self.provision_for_a_while().await;
+                            failures = 0;
+                            delay = 1000;
+                            continue;
+                        }
+                        error!("[wifi] Connection failed, retrying in {}ms: {}", delay, e);
+                        Timer::after_millis(delay).await;
+                        delay = core::cmp::min(delay * 2, 10000);
+                    }
+                }
+            };
             info!("[wifi] Connected");
 
             select(self.runner.lock().await.run(), async {
@@ -215,13 +403,19 @@ impl<'a> WifiHandler<'a> {
                     }
                 }
 
-                select3(
+                let services = join4(
                     broker_task(self.stack),
+                    ota::update_task(self.stack),
+                    sntp::run(self.stack),
+                    health::run(),
+                );
+                #[cfg(feature = "lan-api")]
+                let services = join(services, lan_api::run(self.stack));
+
+                select3(
+                    services,
                     self.stack.wait_link_down(),
-                    self.controller.lock().then(async |mut c| {
-                        c.wait_for_events([WifiEvent::StaDisconnected].into(), false)
-                            .await
-                    }),
+                    self.supervise(current, &networks),
                 )
                 .await;
                 warn!("[wifi] Connection down. Restarting...");
@@ -232,122 +426,151 @@ impl<'a> WifiHandler<'a> {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-enum ConnState {
-    Working,
-    Connecting,
-    Disconnected,
-    Pinging([u8; 16]),
-}
-
-const SEND_TIMEOUT: Duration = Duration::from_secs(5);
-
-#[derive(Debug, Clone)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-enum ConnError {
-    NoRoute,
-    PacketTooLarge,
-    SocketNotBound,
-    Postcard(postcard::Error),
-    SendTimeout,
-    RecvBufferTooSmall,
-}
-
-impl From<postcard::Error> for ConnError {
-    fn from(value: postcard::Error) -> Self {
-        Self::Postcard(value)
+/// Caps the transmit power, in 0.25dBm steps, only takes effect once the
+/// controller is started
+fn set_tx_power(power: u8) {
+    // Safety: clamped to the range the radio accepts by PlugConfig
+    let res = unsafe { esp_wifi_sys::include::esp_wifi_set_max_tx_power(power as i8) };
+    if res != 0 {
+        warn!("[wifi] Failed to set TX power to {}: {}", power, res);
     }
 }
 
-impl From<SendError> for ConnError {
-    fn from(value: SendError) -> Self {
-        match value {
-            SendError::NoRoute => Self::NoRoute,
-            SendError::SocketNotBound => Self::SocketNotBound,
-            SendError::PacketTooLarge => Self::PacketTooLarge,
-        }
-    }
+/// Scans every channel and ranks the APs of the `known` networks
+async fn scan(
+    controller: &mut WifiController<'_>,
+    known: &[&str],
+) -> Result<heapless::Vec<Choice, { roaming::MAX_CHOICES }>, WifiError> {
+    let aps = controller
+        .scan_with_config_async(ScanConfig {
+            channel: None,
+            ..Default::default()
+        })
+        .await?;
+    let candidates = aps
+        .iter()
+        .map(|ap| {
+            debug!(
+                "[wifi] Found AP ({}dBm, ch {}): {}",
+                ap.signal_strength, ap.channel, &*ap.ssid
+            );
+            Candidate {
+                ssid: &ap.ssid,
+                bssid: ap.bssid,
+                channel: ap.channel,
+                rssi: ap.signal_strength,
+                security: security(ap.auth_method),
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(roaming::rank(&candidates, known))
 }
 
-impl From<RecvError> for ConnError {
-    fn from(_value: RecvError) -> Self {
-        Self::RecvBufferTooSmall
+fn security(auth: Option<AuthMethod>) -> Security {
+    match auth {
+        None | Some(AuthMethod::None) => Security::Open,
+        Some(AuthMethod::WEP) => Security::Wep,
+        // mixed modes are as good as their weakest half
+        Some(AuthMethod::WPA | AuthMethod::WPAWPA2Personal) => Security::Wpa,
+        Some(AuthMethod::WPA3Personal) => Security::Wpa3,
+        Some(_) => Security::Wpa2,
     }
 }
 
-impl From<TimeoutError> for ConnError {
-    fn from(_value: TimeoutError) -> Self {
-        Self::SendTimeout
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum ConnState {
+    Working,
+    Connecting,
+    Disconnected,
+    Pinging([u8; 16]),
 }
 
-struct Client<'a> {
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
     seq: Wrapping<u32>,
     server_seq: Wrapping<u32>,
     state: ConnState,
     addr: SocketAddrV4,
-    socket: UdpSocket<'a>,
-    relay_state: Receiver<'static, CriticalSectionRawMutex, RelayMode, 4>,
+    fallback: Fallback,
+    relay_state: Receiver<'static, CriticalSectionRawMutex, ChannelMask, 4>,
+    /// Factory reset token handed out on this connection
+    challenge: Option<Challenge>,
+    /// Identity sent in the handshake
+    id: uuid::Uuid,
 }
 
-pub static WIFI_MSG_CHANNEL: Watch<CriticalSectionRawMutex, MessagePayload, 1> = Watch::new();
+/// Messages for the broker that don't answer a request, queued so
+/// notifications for several channels aren't lost
+pub static WIFI_MSG_CHANNEL: Channel<CriticalSectionRawMutex, MessagePayload, 8> = Channel::new();
 
-impl<'a> Client<'a> {
-    fn new(addr: SocketAddrV4, socket: UdpSocket<'a>) -> Self {
+/// Whatever the [Incoming] half of a session read, waiting to be handled
+type Inbox = Channel<NoopRawMutex, Result<PlugMessage, ConnError>, 1>;
+
+impl Client {
+    fn new(addr: SocketAddrV4) -> Self {
         Self {
             state: ConnState::Disconnected,
             addr,
-            socket,
+            fallback: Fallback::new(),
             seq: Default::default(),
             server_seq: Default::default(),
             relay_state: RELAY_STATUS.receiver().unwrap(),
+            challenge: None,
+            id: uuid::Uuid::nil(),
         }
     }
 
-    pub async fn send(&mut self, msg: MessagePayload) -> Result<(), ConnError> {
-        let mut buf = [0u8; 256];
+    pub async fn send(
+        &mut self,
+        link: &mut impl Link,
+        msg: MessagePayload,
+    ) -> Result<(), ConnError> {
         self.seq += 1;
-        debug!("[broker] Sending message: {}", msg);
-        self.socket
-            .send_to(
-                postcard::to_slice(&PlugMessage::new(self.seq.0, msg), &mut buf).unwrap(),
-                (*self.addr.ip(), self.addr.port()),
-            )
+        // logging every log line would feed itself
+        if !matches!(msg, MessagePayload::Log { .. }) {
+            debug!("[broker] Sending message: {}", msg);
+        }
+        link.send(&PlugMessage::new(self.seq.0, msg))
             .with_timeout(SEND_TIMEOUT)
-            .await??;
-        Ok(())
+            .await?
     }
 
-    pub async fn connect(&mut self) -> Result<(), ConnError> {
-        // TODO: random UUID
-        static UUID: LazyLock<uuid::Uuid> =
-            LazyLock::new(|| "338c1c8a-c3a2-4715-be92-8911248bbb8c".parse().unwrap());
+    pub async fn connect(&mut self, link: &mut impl Link) -> Result<(), ConnError> {
         self.state = ConnState::Connecting;
-        self.send(MessagePayload::Conn { id: *UUID.get() }).await
+        self.challenge = None;
+        self.id = identity::load().await;
+        self.send(link, MessagePayload::Conn { id: self.id }).await
     }
 
-    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<(), ConnError> {
+    pub async fn disconnect(
+        &mut self,
+        link: &mut impl Link,
+        reason: DisconnectReason,
+    ) -> Result<(), ConnError> {
         self.state = ConnState::Disconnected;
-        self.send(MessagePayload::Disconnect { reason }).await
+        self.send(link, MessagePayload::Disconnect { reason }).await
     }
 
-    pub async fn recv(&mut self) -> ControlFlow<DisconnectReason, Option<MessagePayload>> {
-        let mut buf = [0u8; 512];
-        let rcv = self
-            .socket
-            .recv_from(&mut buf)
-            .with_timeout(Duration::from_secs(30))
+    pub async fn recv(
+        &mut self,
+        inbox: &Inbox,
+    ) -> ControlFlow<DisconnectReason, Option<MessagePayload>> {
+        let rcv = inbox
+            .receive()
+            .with_timeout(Duration::from_secs(config::get().heartbeat_secs.into()))
             .await;
-        let rcv = rcv.map(|r| r.map(|m| postcard::from_bytes::<PlugMessage>(&buf[..m.0])));
         match rcv {
-            Ok(Ok(Ok(msg))) => {
+            Ok(Ok(msg)) => {
                 debug!("[broker] Received message: {}", msg);
                 self.feed_msg(Some(msg))
             }
-            Ok(Ok(Err(_pe))) => ControlFlow::Break(DisconnectReason::ProtocolError),
-            Ok(Err(_se)) => {
-                error!("[broker] Rx buffer too small");
+            Ok(Err(ConnError::Postcard(_pe))) => {
+                ControlFlow::Break(DisconnectReason::ProtocolError)
+            }
+            Ok(Err(e)) => {
+                error!("[broker] Receiving failed: {}", e);
                 ControlFlow::Break(DisconnectReason::Closed)
             }
             Err(_timeout) => self.feed_msg(None),
@@ -356,18 +579,19 @@ impl<'a> Client<'a> {
 
     pub async fn send_response(
         &mut self,
+        link: &mut impl Link,
         msg: ControlFlow<DisconnectReason, Option<MessagePayload>>,
     ) {
         match msg {
             ControlFlow::Continue(Some(msg)) => {
-                if let Err(e) = self.send(msg).await {
+                if let Err(e) = self.send(link, msg).await {
                     error!("[broker] Sending to socket failed: {}", e);
                 }
             }
             ControlFlow::Continue(None) => (),
             ControlFlow::Break(reason) => {
                 warn!("[broker] Requested disconnect: {}", reason);
-                if let Err(e) = self.disconnect(reason).await {
+                if let Err(e) = self.disconnect(link, reason).await {
                     warn!("[broker] Sending Disconnect to socket failed: {}", e);
                 }
             }
@@ -421,12 +645,37 @@ impl<'a> Client<'a> {
             }
         }
 
+        let seq = msg.as_ref().map(|m| m.seq);
         match (msg.map(|m| m.payload), self.state) {
-            (Some(Mp::ConnAck), S::Connecting) => {
-                self.server_seq = Wrapping(msg.unwrap().seq);
-                ok!(, S::Working)
+            (Some(Mp::ConnAck { time }), S::Connecting) => {
+                self.server_seq = Wrapping(seq.unwrap());
+                clock::sync(time * 1000, TimeSource::Broker);
+                OTA_CONFIRM.signal(());
+                if let Some(report) = crash::pending()
+                    && WIFI_MSG_CHANNEL
+                        .try_send(Mp::CrashReport { report })
+                        .is_err()
+                {
+                    warn!("[broker] Broker queue full, crash report waits for the next connection");
+                }
+                if WIFI_MSG_CHANNEL
+                    .try_send(Mp::ConfigReport {
+                        config: config::get(),
+                    })
+                    .is_err()
+                {
+                    warn!("[broker] Broker queue full, dropped config report");
+                }
+                ok!(self.power_on_report(), S::Working)
+            }
+            // the broker only sets up the session once we echo its cookie
+            (Some(Mp::ConnCookie { cookie }), S::Connecting) => {
+                ok!(Mp::ConnConfirm {
+                    id: self.id,
+                    cookie
+                })
             }
-            (Some(Mp::ConnAck), _) => dc!(Dr::Closed),
+            (Some(Mp::ConnAck { .. }), _) => dc!(Dr::Closed),
             (Some(Mp::Disconnect { reason }), _) => {
                 warn!("[broker] Server requested disconnect: {:?}", reason);
                 dc!(Dr::Closed)
@@ -440,23 +689,138 @@ impl<'a> Client<'a> {
                 }
             }
             (Some(Mp::Pong { data: _ }), _) => dc!(Dr::ProtocolError),
-            (Some(Mp::TurnOff), _) => {
-                info!("[broker] Broker requested TurnOff");
-                RELAY_SIGNAL.signal(RelayMode::Open);
-                ok!(Mp::TurnOffAck)
-            }
-            (Some(Mp::TurnOn), _) => {
-                info!("[broker] Broker requested TurnOn");
-                RELAY_SIGNAL.signal(RelayMode::Closed);
-                ok!(Mp::TurnOnAck)
-            }
-            (Some(Mp::QueryStatus), _) => {
-                let is_on = self
-                    .relay_state
-                    .try_get()
-                    .is_some_and(|l| l == RelayMode::Closed);
-                ok!(Mp::StatusResp { is_on })
+            (Some(Mp::TurnOff { channel }), _) => {
+                info!("[broker] Broker requested TurnOff of channel {}", channel);
+                ok!(self.switch(channel, RelayMode::Open.into()))
+            }
+            (Some(Mp::TurnOn { channel }), _) => {
+                info!("[broker] Broker requested TurnOn of channel {}", channel);
+                ok!(self.switch(channel, RelayMode::Closed.into()))
+            }
+            (Some(Mp::TurnOnFor { channel, seconds }), _) => {
+                info!(
+                    "[broker] Broker requested TurnOn of channel {} for {}s",
+                    channel, seconds
+                );
+                let request = RelayRequest {
+                    mode: RelayMode::Closed,
+                    revert_after: Some(Duration::from_secs(seconds.into())),
+                };
+                ok!(self.switch(channel, request))
+            }
+            (Some(Mp::TurnOffFor { channel, seconds }), _) => {
+                info!(
+                    "[broker] Broker requested TurnOff of channel {} for {}s",
+                    channel, seconds
+                );
+                let request = RelayRequest {
+                    mode: RelayMode::Open,
+                    revert_after: Some(Duration::from_secs(seconds.into())),
+                };
+                ok!(self.switch(channel, request))
+            }
+            (Some(Mp::Pulse { channel, millis }), _) => {
+                info!(
+                    "[broker] Broker requested {}ms pulse on channel {}",
+                    millis, channel
+                );
+                let request = RelayRequest {
+                    mode: RelayMode::Closed,
+                    revert_after: Some(Duration::from_millis(millis.into())),
+                };
+                ok!(self.switch(channel, request))
+            }
+            (Some(Mp::SetPowerOnBehavior { behavior }), _) => {
+                info!("[broker] Broker set power-on behavior to {}", behavior);
+                power_on::set_behavior(behavior);
+                ok!(self.power_on_report())
+            }
+            (
+                Some(Mp::OtaBegin {
+                    image_id,
+                    size,
+                    sha256,
+                    http_port,
+                    signature,
+                }),
+                _,
+            ) => {
+                let status = if OTA_BUSY.load(Ordering::Relaxed) || OTA_SIGNAL.signaled() {
+                    OtaStatus::Busy
+                } else {
+                    info!("[broker] Broker requested update to image {}", image_id);
+                    OTA_SIGNAL.signal(OtaRequest {
+                        image_id,
+                        size,
+                        sha256,
+                        http_port,
+                        signature,
+                    });
+                    OtaStatus::Accepted
+                };
+                ok!(Mp::OtaStatus { image_id, status })
+            }
+            (Some(Mp::QueryStatus), _) => ok!(self.status()),
+            (Some(Mp::SetSchedule { schedule }), _) => ok!(Mp::ScheduleReport {
+                schedule: schedule::set(schedule)
+            }),
+            (Some(Mp::TimeSync { time }), _) => {
+                clock::sync(time * 1000, TimeSource::Broker);
+                ok!()
+            }
+            (Some(Mp::SetLogLevel { level }), _) => {
+                info!("[broker] Broker set log level to {}", level);
+                netlog::set_level(level);
+                ok!(Mp::LogLevelReport {
+                    level: netlog::level()
+                })
+            }
+            (Some(Mp::Identify { seconds }), _) => {
+                info!("[broker] Identifying for {}s", seconds);
+                LED_STATUS.signal(LedStatusCode::Identify { seconds });
+                ok!(Mp::IdentifyAck { seconds })
             }
+            (Some(Mp::SetConfig { config }), _) => {
+                let config = config::set(config);
+                info!("[broker] Broker set config to {}", config);
+                set_tx_power(config.tx_power);
+                ok!(Mp::ConfigReport { config })
+            }
+            (Some(Mp::GetConfig), _) => ok!(Mp::ConfigReport {
+                config: config::get()
+            }),
+            (Some(Mp::Reboot), _) => {
+                restart::RESTART_SIGNAL.signal(Restart::Reboot);
+                ok!(Mp::RebootAck)
+            }
+            (Some(Mp::FactoryResetRequest), _) => {
+                let challenge = Challenge::new(identity::random(), Instant::now());
+                self.challenge = Some(challenge);
+                ok!(Mp::FactoryResetChallenge {
+                    token: challenge.token()
+                })
+            }
+            (Some(Mp::FactoryReset { token }), _) => {
+                // a token is only good for one try
+                match self.challenge.take() {
+                    Some(c) if c.confirms(token, Instant::now()) => {
+                        let id = identity::generate();
+                        restart::RESTART_SIGNAL.signal(Restart::FactoryReset { id });
+                        ok!(Mp::FactoryResetAck { id })
+                    }
+                    _ => {
+                        warn!("[broker] Refused factory reset with a wrong or expired token");
+                        ok!(Mp::FactoryResetRefused)
+                    }
+                }
+            }
+            (Some(Mp::CrashAck), _) => {
+                crash::acked();
+                ok!()
+            }
+            (Some(Mp::GetSchedule), _) => ok!(Mp::ScheduleReport {
+                schedule: schedule::get()
+            }),
             (Some(m), S::Working) => {
                 info!("[broker] Unhandled message: {:?}", m);
                 ok!()
@@ -476,21 +840,168 @@ impl<'a> Client<'a> {
         }
     }
 
-    pub async fn run(&mut self) -> ! {
-        let mut receiver = WIFI_MSG_CHANNEL.receiver().unwrap();
+    fn power_on_report(&self) -> MessagePayload {
+        MessagePayload::PowerOnReport {
+            behavior: power_on::behavior(),
+            channels: board::RELAY_COUNT as u8,
+            on: self.relay_state.try_get().unwrap_or_default(),
+        }
+    }
+
+    /// Passes a request on to the relays, answering with the ack for its mode
+    /// or a status report if the channel doesn't exist
+    fn switch(&self, channel: u8, request: RelayRequest) -> MessagePayload {
+        if !relay::request(channel, request) {
+            warn!("[broker] No relay channel {}", channel);
+            return self.status();
+        }
+        match request.mode {
+            RelayMode::Open => MessagePayload::TurnOffAck { channel },
+            RelayMode::Closed => MessagePayload::TurnOnAck { channel },
+        }
+    }
+
+    fn status(&self) -> MessagePayload {
+        MessagePayload::StatusResp {
+            channels: board::RELAY_COUNT as u8,
+            on: self.relay_state.try_get().unwrap_or_default(),
+            remaining: relay::remaining_secs(),
+        }
+    }
+
+    /// Keeps a session with the broker, over TCP when UDP goes unanswered
+    pub async fn run(&mut self, udp: &UdpSocket<'_>, tcp: &mut TcpSocket<'_>) -> ! {
+        let mut reader = FrameReader::new();
         loop {
-            if self.state == ConnState::Disconnected
-                && let Err(e) = self.connect().await
-            {
-                warn!("[broker] Failed to send connection request: {}", e);
+            let transport = self.fallback.transport();
+            let connected = match transport {
+                Transport::Datagram => {
+                    let (mut tx, mut rx) = Datagram::pair(udp, self.addr);
+                    self.session(&mut tx, &mut rx).await
+                }
+                Transport::Stream => self.stream_session(tcp, &mut reader).await,
+            };
+            if connected {
+                self.fallback.connected();
+            } else if self.fallback.failed() != transport {
+                warn!(
+                    "[broker] No answer over {}, trying {}",
+                    transport,
+                    self.fallback.transport()
+                );
             }
-            match select(self.recv(), receiver.changed()).await {
-                Either::First(f) => self.send_response(f).await,
-                Either::Second(s) => {
-                    let _ = self.send(s).await;
+        }
+    }
+
+    /// Connects over `link` and serves the broker until disconnected,
+    /// returning whether the handshake went through
+    async fn session(&mut self, link: &mut impl Link, incoming: &mut impl Incoming) -> bool {
+        let inbox = Inbox::new();
+        match select(Self::read_into(incoming, &inbox), self.serve(link, &inbox)).await {
+            Either::First(never) => never,
+            Either::Second(connected) => connected,
+        }
+    }
+
+    /// Hands everything `incoming` reads to `inbox`
+    ///
+    /// This is the only place a session reads from, and it runs until the
+    /// session is over, so no read is ever dropped halfway through.
+    async fn read_into(incoming: &mut impl Incoming, inbox: &Inbox) -> ! {
+        loop {
+            inbox.send(incoming.recv().await).await;
+        }
+    }
+
+    /// The [session](Client::session) itself, with reads coming from `inbox`
+    async fn serve(&mut self, link: &mut impl Link, inbox: &Inbox) -> bool {
+        if let Err(e) = self.connect(link).await {
+            warn!("[broker] Failed to send connection request: {}", e);
+        }
+        let mut connected = false;
+        while self.state != ConnState::Disconnected {
+            let working = self.state == ConnState::Working;
+            let established = working || matches!(self.state, ConnState::Pinging(_));
+            connected |= working;
+            // queued messages wait for the handshake, and log lines only go
+            // out once everything else has
+            let queued = async {
+                if established {
+                    WIFI_MSG_CHANNEL.receive().await
+                } else {
+                    core::future::pending().await
+                }
+            };
+            let log = async {
+                if working {
+                    netlog::next().await
+                } else {
+                    core::future::pending().await
+                }
+            };
+            match select3(self.recv(inbox), queued, log).await {
+                Either3::First(f) => self.send_response(link, f).await,
+                Either3::Second(s) => {
+                    let _ = self.send(link, s).await;
+                }
+                Either3::Third((line, dropped)) => {
+                    let _ = self.send(link, MessagePayload::Log { line, dropped }).await;
                 }
             }
         }
+        connected
+    }
+
+    /// A [session](Client::session) over TCP, wrapped in TLS with the `tls`
+    /// feature
+    async fn stream_session(&mut self, tcp: &mut TcpSocket<'_>, reader: &mut FrameReader) -> bool {
+        reader.clear();
+        match tcp.connect(self.addr).with_timeout(SEND_TIMEOUT).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("[broker] TCP connection failed: {}", e);
+                tcp.abort();
+                return false;
+            }
+            Err(_timeout) => {
+                warn!("[broker] TCP connection timed out");
+                tcp.abort();
+                return false;
+            }
+        }
+
+        #[cfg(not(feature = "tls"))]
+        let connected = {
+            let (rx, tx) = tcp.split();
+            self.session(&mut StreamTx { io: tx }, &mut StreamRx { io: rx, reader })
+                .await
+        };
+        #[cfg(feature = "tls")]
+        let connected = {
+            // only needed while on the fallback, so they come from the heap
+            let mut read_buf = alloc::vec![0u8; link::tls::RECORD_SIZE];
+            let mut write_buf = alloc::vec![0u8; link::tls::RECORD_SIZE];
+            let (rx, tx) = tcp.split();
+            let (rx, tx) = (Mutex::new(rx), Mutex::new(tx));
+            let shared = link::tls::SharedTcp { rx: &rx, tx: &tx };
+            let mut tls = TlsConnection::new(shared, &mut read_buf, &mut write_buf);
+            match link::tls::open(&mut tls).await {
+                Ok(()) => {
+                    let mut state = SplitConnectionState::default();
+                    let (rx, tx) = tls.split_with(&mut state);
+                    self.session(&mut StreamTx { io: tx }, &mut StreamRx { io: rx, reader })
+                        .await
+                }
+                Err(e) => {
+                    warn!("[broker] TLS handshake failed: {}", e);
+                    false
+                }
+            }
+        };
+
+        tcp.abort();
+        let _ = tcp.flush().await;
+        connected
     }
 }
 
@@ -522,6 +1033,10 @@ async fn broker_task(stack: Stack<'_>) -> ! {
         broker_ip, broker_port
     );
 
-    let mut client = Client::new(SocketAddrV4::new(broker_ip, broker_port), sock);
-    client.run().await;
+    let mut tcp_rx_buf = [0u8; 1024];
+    let mut tcp_tx_buf = [0u8; 1024];
+    let mut tcp = TcpSocket::new(stack, &mut tcp_rx_buf, &mut tcp_tx_buf);
+
+    let mut client = Client::new(SocketAddrV4::new(broker_ip, broker_port));
+    client.run(&sock, &mut tcp).await;
 }
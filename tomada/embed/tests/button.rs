@@ -0,0 +1,111 @@
+use common::ChannelMask;
+use embassy_futures::join::join;
+use embassy_time::{Duration, Timer, WithTimeout};
+use goodwe_plug::{
+    BUTTON_ACTION, BUTTON_STATUS, ButtonEvent, RELAY_STATUS,
+    board::{BUTTON_ACTIVE, RELAY_ACTIVE},
+    button_task,
+    config::{self, PlugConfig},
+    gesture::{ButtonAction, MULTI_CLICK_WINDOW},
+    relay_task,
+};
+
+use crate::mock::{MockButton, MockOutput, run_with, serial};
+
+mod mock;
+
+const CLICK: Duration = Duration::from_millis(100);
+
+/// Runs the button and relay tasks next to `test`
+fn run_plug<T>(button: &MockButton, test: impl Future<Output = T>) -> T {
+    let mut relays = [MockOutput::new(!RELAY_ACTIVE)];
+    let mut button = button.clone();
+    run_with(
+        join(
+            relay_task(&mut relays, ChannelMask(0), |_, _| {}),
+            button_task(&mut button),
+        ),
+        test,
+    )
+}
+
+#[test]
+fn click_toggles_relay() {
+    let _guard = serial();
+    let button = MockButton::new();
+    run_plug(&button, async {
+        Timer::after_millis(10).await;
+        assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+
+        button.click(CLICK).await;
+        // a single click only counts once the multi-click window is over
+        assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+        Timer::after(MULTI_CLICK_WINDOW).await;
+        assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(1)));
+
+        button.click(CLICK).await;
+        Timer::after(MULTI_CLICK_WINDOW).await;
+        assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+    });
+}
+
+#[test]
+fn reports_debounced_events() {
+    let _guard = serial();
+    let button = MockButton::new();
+    let mut events = BUTTON_STATUS.receiver().unwrap();
+    run_plug(&button, async {
+        Timer::after_millis(10).await;
+        // left over from other tests
+        events.try_changed();
+
+        // bounces shorter than the debounce time are ignored
+        button.set(BUTTON_ACTIVE);
+        Timer::after_millis(10).await;
+        button.set(!BUTTON_ACTIVE);
+        Timer::after_millis(100).await;
+        assert_eq!(events.try_changed(), None);
+
+        button.click(CLICK).await;
+        Timer::after(MULTI_CLICK_WINDOW).await;
+    });
+    // the watch only keeps the latest event
+    assert_eq!(events.try_changed(), Some(ButtonEvent::Release));
+}
+
+#[test]
+fn triple_click_starts_provisioning() {
+    let _guard = serial();
+    let button = MockButton::new();
+    BUTTON_ACTION.reset();
+    let action = run_plug(&button, async {
+        Timer::after_millis(10).await;
+        for _ in 0..3 {
+            button.click(CLICK).await;
+        }
+        BUTTON_ACTION
+            .wait()
+            .with_timeout(Duration::from_secs(1))
+            .await
+    });
+    assert_eq!(action, Ok(ButtonAction::StartProvisioning));
+    // handled by the rest of the firmware, the relay isn't touched
+    assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+}
+
+#[test]
+fn disabled_button_keeps_the_relay() {
+    let _guard = serial();
+    let button = MockButton::new();
+    config::set(PlugConfig {
+        button_enabled: false,
+        ..Default::default()
+    });
+    run_plug(&button, async {
+        Timer::after_millis(10).await;
+        button.click(CLICK).await;
+        Timer::after(MULTI_CLICK_WINDOW).await;
+        assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+    });
+    config::set(PlugConfig::default());
+}
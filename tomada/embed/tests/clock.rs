@@ -0,0 +1,64 @@
+use embassy_time::{Duration, Instant};
+use goodwe_plug::clock::{Clock, MIN_DRIFT_INTERVAL, SNTP_PRIORITY, TimeSource};
+
+/// 2024-01-01 00:00 UTC
+const T: u64 = 1_704_067_200_000;
+
+fn secs(s: u64) -> Instant {
+    Instant::from_secs(s)
+}
+
+#[test]
+fn unset_until_synced() {
+    let mut clock = Clock::new();
+    assert_eq!(clock.now_millis(secs(5)), None);
+    assert!(clock.sync(secs(5), T, TimeSource::Broker));
+    assert_eq!(clock.now_millis(secs(5)), Some(T));
+    assert_eq!(clock.now_millis(secs(65)), Some(T + 60_000));
+    assert_eq!(clock.source(), Some(TimeSource::Broker));
+}
+
+#[test]
+fn sntp_wins_over_broker_for_a_while() {
+    let mut clock = Clock::new();
+    clock.sync(secs(0), T, TimeSource::Sntp);
+    assert!(!clock.sync(secs(10), T + 5_000, TimeSource::Broker));
+    assert_eq!(clock.now_millis(secs(10)), Some(T + 10_000));
+
+    // SNTP has been unreachable for too long
+    let later = secs(0) + SNTP_PRIORITY;
+    assert!(clock.sync(later, T, TimeSource::Broker));
+    assert_eq!(clock.now_millis(later), Some(T));
+    assert_eq!(clock.source(), Some(TimeSource::Broker));
+}
+
+#[test]
+fn tracks_drift_between_sntp_syncs() {
+    let mut clock = Clock::new();
+    clock.sync(secs(0), T, TimeSource::Sntp);
+    // real time ran 100ms ahead over 1000s, 100ppm
+    clock.sync(secs(1000), T + 1_000_100, TimeSource::Sntp);
+    assert_eq!(clock.drift_ppm(), 50);
+    assert_eq!(clock.now_millis(secs(2000)), Some(T + 2_000_150));
+
+    clock.sync(secs(2000), T + 2_000_200, TimeSource::Sntp);
+    assert_eq!(clock.drift_ppm(), 75);
+}
+
+#[test]
+fn ignores_drift_over_short_or_bad_intervals() {
+    let mut clock = Clock::new();
+    clock.sync(secs(0), T, TimeSource::Sntp);
+    let short = MIN_DRIFT_INTERVAL - Duration::from_secs(1);
+    clock.sync(
+        secs(0) + short,
+        T + short.as_millis() + 100,
+        TimeSource::Sntp,
+    );
+    assert_eq!(clock.drift_ppm(), 0);
+
+    // a whole second off after 1000s is a bad reply, not drift
+    clock.sync(secs(2000), T + 2_001_000, TimeSource::Sntp);
+    assert_eq!(clock.drift_ppm(), 0);
+    assert_eq!(clock.now_millis(secs(2000)), Some(T + 2_001_000));
+}
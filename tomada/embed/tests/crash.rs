@@ -0,0 +1,55 @@
+use core::mem::MaybeUninit;
+
+use common::{MAX_BACKTRACE, MAX_CRASH_FILE, MAX_CRASH_MESSAGE};
+use goodwe_plug::crash::CrashRecord;
+
+#[test]
+fn record_round_trips() {
+    let record = CrashRecord::new(
+        format_args!("index out of bounds: {}", 7),
+        "src/relay.rs",
+        42,
+        3600,
+        &[0x4200_1234, 0x4200_5678],
+    );
+    let report = record.report().unwrap();
+    assert_eq!(report.message, "index out of bounds: 7");
+    assert_eq!(report.file, "src/relay.rs");
+    assert_eq!(report.line, 42);
+    assert_eq!(report.uptime, 3600);
+    assert_eq!(&report.backtrace[..], &[0x4200_1234, 0x4200_5678]);
+}
+
+#[test]
+fn long_fields_are_cut() {
+    // multibyte characters straddle both limits
+    let message = "é".repeat(MAX_CRASH_MESSAGE);
+    let file = format!("/home/ci/{}/src/wifi.rs", "ã".repeat(MAX_CRASH_FILE));
+    let frames = [1u32; MAX_BACKTRACE + 3];
+    let report = CrashRecord::new(&message, &file, 1, 0, &frames)
+        .report()
+        .unwrap();
+
+    assert!(message.starts_with(report.message.as_str()));
+    assert!(report.message.len() > MAX_CRASH_MESSAGE - 2);
+    assert!(file.ends_with(report.file.as_str()));
+    assert!(report.file.ends_with("/src/wifi.rs"));
+    assert_eq!(report.backtrace.len(), MAX_BACKTRACE);
+}
+
+#[test]
+fn garbage_and_cleared_records_are_ignored() {
+    // what RTC memory may hold after a power cut
+    let garbage: CrashRecord = unsafe {
+        let mut m = MaybeUninit::<CrashRecord>::uninit();
+        m.as_mut_ptr()
+            .cast::<u8>()
+            .write_bytes(0xa5, size_of::<CrashRecord>());
+        m.assume_init()
+    };
+    assert_eq!(garbage.report(), None);
+
+    let mut record = CrashRecord::new("boom", "src/lib.rs", 1, 0, &[]);
+    record.clear();
+    assert_eq!(record.report(), None);
+}
@@ -0,0 +1,120 @@
+use embassy_time::{Duration, Instant};
+use goodwe_plug::{
+    ButtonEvent,
+    gesture::{
+        ButtonAction, Gesture, GestureRecognizer, LONG_PRESS, MULTI_CLICK_WINDOW, VERY_LONG_PRESS,
+    },
+};
+
+fn ms(millis: u64) -> Instant {
+    Instant::from_millis(millis)
+}
+
+/// Feeds (press, release) pairs, returning every gesture produced
+fn clicks(rec: &mut GestureRecognizer, presses: &[(u64, u64)]) -> Vec<Gesture> {
+    let mut out = Vec::new();
+    for &(down, up) in presses {
+        out.extend(rec.feed(ButtonEvent::Press, ms(down)));
+        out.extend(rec.feed(ButtonEvent::Release, ms(up)));
+    }
+    out
+}
+
+#[test]
+fn single_click_waits_for_window() {
+    let mut rec = GestureRecognizer::new();
+    assert!(clicks(&mut rec, &[(0, 100)]).is_empty());
+
+    let deadline = rec.deadline().unwrap();
+    assert_eq!(deadline, ms(100) + MULTI_CLICK_WINDOW);
+    assert_eq!(rec.poll(deadline - Duration::from_millis(1)), None);
+    assert_eq!(rec.poll(deadline), Some(Gesture::SingleClick));
+    assert_eq!(rec.deadline(), None);
+}
+
+#[test]
+fn double_click() {
+    let mut rec = GestureRecognizer::new();
+    assert!(clicks(&mut rec, &[(0, 100), (300, 400)]).is_empty());
+    assert_eq!(rec.poll(ms(1000)), Some(Gesture::DoubleClick));
+}
+
+#[test]
+fn triple_click_is_immediate() {
+    let mut rec = GestureRecognizer::new();
+    assert_eq!(
+        clicks(&mut rec, &[(0, 100), (300, 400), (600, 700)]),
+        [Gesture::TripleClick]
+    );
+    assert_eq!(rec.deadline(), None);
+}
+
+#[test]
+fn no_deadline_while_held() {
+    let mut rec = GestureRecognizer::new();
+    clicks(&mut rec, &[(0, 100)]);
+    rec.feed(ButtonEvent::Press, ms(300));
+    assert_eq!(rec.deadline(), None);
+}
+
+#[test]
+fn slow_clicks_are_separate() {
+    let mut rec = GestureRecognizer::new();
+    clicks(&mut rec, &[(0, 100)]);
+    // poll wasn't called, the next press completes the previous click
+    assert_eq!(
+        rec.feed(ButtonEvent::Press, ms(2000)),
+        Some(Gesture::SingleClick)
+    );
+    assert_eq!(rec.feed(ButtonEvent::Release, ms(2100)), None);
+    assert_eq!(rec.poll(ms(3000)), Some(Gesture::SingleClick));
+}
+
+#[test]
+fn long_presses() {
+    let mut rec = GestureRecognizer::new();
+    let long = LONG_PRESS.as_millis();
+    let very_long = VERY_LONG_PRESS.as_millis();
+    assert_eq!(clicks(&mut rec, &[(0, long)]), [Gesture::LongPress]);
+    assert_eq!(
+        clicks(&mut rec, &[(20_000, 20_000 + very_long)]),
+        [Gesture::VeryLongPress]
+    );
+    assert_eq!(rec.deadline(), None);
+}
+
+#[test]
+fn long_press_discards_pending_clicks() {
+    let mut rec = GestureRecognizer::new();
+    let long = LONG_PRESS.as_millis();
+    assert_eq!(
+        clicks(&mut rec, &[(0, 100), (300, 300 + long)]),
+        [Gesture::LongPress]
+    );
+    assert_eq!(rec.poll(ms(60_000)), None);
+}
+
+#[test]
+fn release_without_press() {
+    let mut rec = GestureRecognizer::new();
+    assert_eq!(rec.feed(ButtonEvent::Release, ms(0)), None);
+    assert_eq!(rec.deadline(), None);
+}
+
+#[test]
+fn actions() {
+    assert_eq!(
+        Gesture::SingleClick.action(),
+        Some(ButtonAction::ToggleRelay)
+    );
+    assert_eq!(Gesture::DoubleClick.action(), None);
+    assert_eq!(
+        Gesture::TripleClick.action(),
+        Some(ButtonAction::StartProvisioning)
+    );
+    assert_eq!(Gesture::LongPress.action(), Some(ButtonAction::BlePairing));
+    assert_eq!(
+        Gesture::VeryLongPress.action(),
+        Some(ButtonAction::FactoryReset)
+    );
+}
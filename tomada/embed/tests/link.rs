@@ -0,0 +1,127 @@
+use core::{convert::Infallible, future::poll_fn, task::Poll};
+
+use common::{MessagePayload, PlugMessage};
+use embassy_futures::poll_once;
+use futures::executor::block_on;
+use goodwe_plug::link::{
+    FALLBACK_AFTER, Fallback, FrameError, FrameReader, MAX_FRAME, Transport, encode,
+};
+
+/// Hands out `data` a few bytes per read, pending before each chunk
+struct Chunked<'d> {
+    data: &'d [u8],
+    chunk: usize,
+    ready: bool,
+}
+
+impl<'d> Chunked<'d> {
+    fn new(data: &'d [u8], chunk: usize) -> Self {
+        Self {
+            data,
+            chunk,
+            ready: false,
+        }
+    }
+}
+
+impl embedded_io_async::ErrorType for Chunked<'_> {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for Chunked<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        poll_fn(|cx| {
+            if self.ready {
+                Poll::Ready(())
+            } else {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await;
+        self.ready = false;
+        let n = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fn frame(msg: &PlugMessage) -> Vec<u8> {
+    let mut buf = [0; MAX_FRAME + 2];
+    encode(msg, &mut buf).unwrap().to_vec()
+}
+
+#[test]
+fn falls_back_after_failed_handshakes() {
+    let mut fallback = Fallback::new();
+    for _ in 1..FALLBACK_AFTER {
+        assert_eq!(fallback.failed(), Transport::Datagram);
+    }
+    assert_eq!(fallback.failed(), Transport::Stream);
+
+    // a session that got through resets the count
+    fallback.failed();
+    fallback.connected();
+    for _ in 1..FALLBACK_AFTER {
+        assert_eq!(fallback.failed(), Transport::Stream);
+    }
+    assert_eq!(fallback.failed(), Transport::Datagram);
+}
+
+#[test]
+fn frames_round_trip() {
+    let first = PlugMessage::new(1, MessagePayload::QueryStatus);
+    let second = PlugMessage::new(2, MessagePayload::Ping { data: [7; 16] });
+    let mut data = frame(&first);
+    assert_eq!(
+        u16::from_be_bytes([data[0], data[1]]) as usize,
+        data.len() - 2
+    );
+    data.extend(frame(&second));
+
+    // both in one read, and a byte at a time
+    for chunk in [data.len(), 1] {
+        let mut reader = FrameReader::new();
+        let mut io = Chunked::new(&data, chunk);
+        assert_eq!(block_on(reader.read(&mut io)), Ok(first.clone()));
+        assert_eq!(block_on(reader.read(&mut io)), Ok(second.clone()));
+        assert_eq!(block_on(reader.read(&mut io)), Err(FrameError::Closed));
+    }
+}
+
+#[test]
+fn cancelled_reads_keep_partial_frames() {
+    let msg = PlugMessage::new(1, MessagePayload::QueryStatus);
+    let data = frame(&msg);
+    let mut reader = FrameReader::new();
+    let mut io = Chunked::new(&data, 1);
+
+    // every attempt reads a byte and gets dropped waiting for the next one
+    for _ in 0..data.len() {
+        assert!(poll_once(reader.read(&mut io)).is_pending());
+    }
+    assert_eq!(block_on(reader.read(&mut io)), Ok(msg));
+}
+
+#[test]
+fn oversized_frames_are_refused() {
+    let mut data = ((MAX_FRAME + 1) as u16).to_be_bytes().to_vec();
+    data.extend([0; 8]);
+    let mut io = Chunked::new(&data, data.len());
+    assert_eq!(
+        block_on(FrameReader::new().read(&mut io)),
+        Err(FrameError::TooLarge(MAX_FRAME + 1))
+    );
+}
+
+#[test]
+fn garbage_is_a_decode_error() {
+    let data = [0, 2, 0xff, 0xff];
+    let mut io = Chunked::new(&data, data.len());
+    assert!(matches!(
+        block_on(FrameReader::new().read(&mut io)),
+        Err(FrameError::Decode(_))
+    ));
+}
@@ -0,0 +1,156 @@
+//! Fake pins and helpers shared by the host tests
+
+#![allow(dead_code)]
+
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    rc::Rc,
+    sync::{Mutex, MutexGuard},
+};
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin, PinState, StatefulOutputPin};
+use embedded_hal_async::digital::Wait;
+use goodwe_plug::board::BUTTON_ACTIVE;
+
+/// The firmware state lives in statics, so tests touching it can't overlap
+pub fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs `test` next to a task that never returns
+pub fn run_with<T>(task: impl Future, test: impl Future<Output = T>) -> T {
+    futures::executor::block_on(async {
+        match select(task, test).await {
+            Either::First(_) => unreachable!("task returned"),
+            Either::Second(t) => t,
+        }
+    })
+}
+
+/// Output pin that remembers its state, clones share it
+#[derive(Clone)]
+pub struct MockOutput(Rc<Cell<PinState>>);
+
+impl MockOutput {
+    pub fn new(state: PinState) -> Self {
+        Self(Rc::new(Cell::new(state)))
+    }
+
+    pub fn state(&self) -> PinState {
+        self.0.get()
+    }
+}
+
+impl ErrorType for MockOutput {
+    type Error = Infallible;
+}
+
+impl OutputPin for MockOutput {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set(PinState::Low);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set(PinState::High);
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for MockOutput {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.get() == PinState::High)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.get() == PinState::Low)
+    }
+}
+
+/// Button with the board's polarity, clones share it
+#[derive(Clone)]
+pub struct MockButton {
+    level: Rc<Cell<PinState>>,
+    edge: Rc<Signal<CriticalSectionRawMutex, ()>>,
+}
+
+impl MockButton {
+    pub fn new() -> Self {
+        Self {
+            level: Rc::new(Cell::new(!BUTTON_ACTIVE)),
+            edge: Rc::new(Signal::new()),
+        }
+    }
+
+    pub fn set(&self, level: PinState) {
+        if self.level.replace(level) != level {
+            self.edge.signal(());
+        }
+    }
+
+    /// Holds the button down for `held`, then releases it and waits until
+    /// the release is past the debounce
+    pub async fn click(&self, held: Duration) {
+        self.set(BUTTON_ACTIVE);
+        Timer::after(held).await;
+        self.set(!BUTTON_ACTIVE);
+        Timer::after_millis(100).await;
+    }
+}
+
+impl ErrorType for MockButton {
+    type Error = Infallible;
+}
+
+impl InputPin for MockButton {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level.get() == PinState::High)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level.get() == PinState::Low)
+    }
+}
+
+impl MockButton {
+    async fn wait_for(&mut self, level: PinState) {
+        while self.level.get() != level {
+            self.edge.wait().await;
+        }
+    }
+}
+
+impl Wait for MockButton {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::Low).await;
+        self.wait_for(PinState::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(PinState::High).await;
+        self.wait_for(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.edge.reset();
+        self.edge.wait().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use common::{LogLevel, MAX_LOG_MESSAGE};
+use goodwe_plug::{
+    info,
+    netlog::{self, BUFFER_LINES, render},
+    warn,
+};
+
+use crate::mock::serial;
+
+mod mock;
+
+fn drain() {
+    while netlog::take().is_some() {}
+}
+
+#[test]
+fn renders_placeholders_with_debug() {
+    let line = render::<64>("[wifi] {} via {:02X}, {{literal}}", &[&"home", &[1u8, 2]]);
+    assert_eq!(line, "[wifi] \"home\" via [1, 2], {literal}");
+    assert_eq!(render::<64>("missing {}", &[]), "missing {?}");
+}
+
+#[test]
+fn long_lines_are_cut_between_characters() {
+    let line = render::<8>("{}", &[&"ééééé"]);
+    assert_eq!(line, "\"ééé");
+}
+
+#[test]
+fn only_lines_at_the_level_are_kept() {
+    let _guard = serial();
+    drain();
+    netlog::set_level(LogLevel::Warn);
+    info!("[test] not kept {}", 1);
+    warn!("[test] kept {}", 2);
+
+    let (line, dropped) = netlog::take().unwrap();
+    assert_eq!(line.level, LogLevel::Warn);
+    assert_eq!(line.message, "[test] kept 2");
+    assert_eq!(dropped, 0);
+    assert!(netlog::take().is_none());
+
+    netlog::set_level(LogLevel::Info);
+    info!("[test] now kept");
+    assert_eq!(netlog::take().unwrap().0.level, LogLevel::Info);
+    netlog::set_level(netlog::DEFAULT_LEVEL);
+}
+
+#[test]
+fn full_buffer_drops_the_oldest() {
+    let _guard = serial();
+    drain();
+    for i in 0..BUFFER_LINES + 3 {
+        warn!("[test] line {}", i);
+    }
+    let (line, dropped) = netlog::take().unwrap();
+    assert_eq!(line.message, "[test] line 3");
+    assert_eq!(dropped, 3);
+    assert_eq!(netlog::take().unwrap().1, 0);
+    drain();
+}
+
+#[test]
+fn arguments_are_evaluated_once() {
+    let _guard = serial();
+    let mut calls = 0;
+    let mut count = || {
+        calls += 1;
+        calls
+    };
+    warn!("[test] {}", count());
+    assert_eq!(calls, 1);
+    let long = "x".repeat(MAX_LOG_MESSAGE * 2);
+    warn!("{}", long);
+    drain();
+}
@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+
+use common::ChannelMask;
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Timer};
+use goodwe_plug::{
+    RELAY_SIGNAL, RELAY_STATUS, RelayMode, RelayRequest, board::RELAY_ACTIVE, relay_task,
+    remaining_secs, settled,
+};
+
+use crate::mock::{MockOutput, run_with, serial};
+
+mod mock;
+
+#[test]
+fn starts_in_initial_mode() {
+    let _guard = serial();
+    for (initial, level) in [
+        (ChannelMask(1), RELAY_ACTIVE),
+        (ChannelMask(0), !RELAY_ACTIVE),
+    ] {
+        let pin = MockOutput::new(!level);
+        run_with(relay_task(&mut [pin.clone()], initial, |_, _| {}), async {
+            yield_now().await;
+            assert_eq!(pin.state(), level);
+            assert_eq!(RELAY_STATUS.try_get(), Some(initial));
+        });
+    }
+}
+
+#[test]
+fn follows_requests() {
+    let _guard = serial();
+    let pin = MockOutput::new(!RELAY_ACTIVE);
+    let changes = RefCell::new(Vec::new());
+    let on_change = |c, m| changes.borrow_mut().push((c, m));
+
+    run_with(
+        relay_task(&mut [pin.clone()], ChannelMask(0), on_change),
+        async {
+            yield_now().await;
+
+            RELAY_SIGNAL[0].signal(RelayMode::Closed.into());
+            yield_now().await;
+            assert_eq!(pin.state(), RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(1)));
+
+            // already closed, nothing to report
+            RELAY_SIGNAL[0].signal(RelayMode::Closed.into());
+            yield_now().await;
+
+            RELAY_SIGNAL[0].signal(RelayMode::Open.into());
+            yield_now().await;
+            assert_eq!(pin.state(), !RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+        },
+    );
+
+    assert_eq!(
+        *changes.borrow(),
+        [(0, RelayMode::Closed), (0, RelayMode::Open)]
+    );
+}
+
+#[test]
+fn channels_switch_independently() {
+    let _guard = serial();
+    let pins = [
+        MockOutput::new(!RELAY_ACTIVE),
+        MockOutput::new(!RELAY_ACTIVE),
+        MockOutput::new(!RELAY_ACTIVE),
+    ];
+    let changes = RefCell::new(Vec::new());
+    let on_change = |c, m| changes.borrow_mut().push((c, m));
+
+    run_with(
+        relay_task(&mut pins.clone(), ChannelMask(0b100), on_change),
+        async {
+            yield_now().await;
+            assert_eq!(pins[2].state(), RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0b100)));
+
+            RELAY_SIGNAL[1].signal(RelayMode::Closed.into());
+            yield_now().await;
+            assert_eq!(pins[0].state(), !RELAY_ACTIVE);
+            assert_eq!(pins[1].state(), RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0b110)));
+
+            RELAY_SIGNAL[2].signal(RelayMode::Open.into());
+            yield_now().await;
+            assert_eq!(pins[2].state(), !RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0b010)));
+
+            // there's no relay on channel 5, nothing happens
+            RELAY_SIGNAL[5].signal(RelayMode::Closed.into());
+            yield_now().await;
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0b010)));
+        },
+    );
+
+    assert_eq!(
+        *changes.borrow(),
+        [(1, RelayMode::Closed), (2, RelayMode::Open)]
+    );
+}
+
+#[test]
+fn timed_request_reverts() {
+    let _guard = serial();
+    let pin = MockOutput::new(!RELAY_ACTIVE);
+    let changes = RefCell::new(Vec::new());
+    let on_change = |c, m| changes.borrow_mut().push((c, m));
+
+    run_with(
+        relay_task(&mut [pin.clone()], ChannelMask(0), on_change),
+        async {
+            yield_now().await;
+
+            RELAY_SIGNAL[0].signal(RelayRequest {
+                mode: RelayMode::Closed,
+                revert_after: Some(Duration::from_millis(100)),
+            });
+            Timer::after_millis(50).await;
+            assert_eq!(pin.state(), RELAY_ACTIVE);
+            assert_eq!(remaining_secs()[0], 1);
+            // stored for a reboot as it will be once the timer runs out
+            assert_eq!(settled(ChannelMask(1)), ChannelMask(0));
+
+            Timer::after_millis(100).await;
+            assert_eq!(remaining_secs()[0], 0);
+            assert_eq!(pin.state(), !RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(0)));
+        },
+    );
+
+    assert_eq!(
+        *changes.borrow(),
+        [(0, RelayMode::Closed), (0, RelayMode::Open)]
+    );
+}
+
+#[test]
+fn plain_request_cancels_timer() {
+    let _guard = serial();
+    let pin = MockOutput::new(!RELAY_ACTIVE);
+
+    run_with(
+        relay_task(&mut [pin.clone()], ChannelMask(1), |_, _| {}),
+        async {
+            yield_now().await;
+
+            // already closed, only the timer starts
+            RELAY_SIGNAL[0].signal(RelayRequest {
+                mode: RelayMode::Closed,
+                revert_after: Some(Duration::from_millis(100)),
+            });
+            Timer::after_millis(50).await;
+            RELAY_SIGNAL[0].signal(RelayMode::Closed.into());
+
+            Timer::after_millis(100).await;
+            assert_eq!(pin.state(), RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(1)));
+        },
+    );
+}
@@ -0,0 +1,25 @@
+use embassy_time::{Duration, Instant};
+use goodwe_plug::restart::{Challenge, TOKEN_LIFETIME};
+
+#[test]
+fn token_confirms_until_it_expires() {
+    let issued = Instant::from_secs(100);
+    let challenge = Challenge::new(0xdead_beef, issued);
+
+    assert!(challenge.confirms(0xdead_beef, issued));
+    assert!(challenge.confirms(
+        0xdead_beef,
+        issued + TOKEN_LIFETIME - Duration::from_millis(1)
+    ));
+    assert!(!challenge.confirms(0xdead_beef, issued + TOKEN_LIFETIME));
+}
+
+#[test]
+fn wrong_token_is_refused() {
+    let issued = Instant::from_secs(100);
+    let challenge = Challenge::new(0xdead_beef, issued);
+
+    assert!(!challenge.confirms(0xdead_bee0, issued));
+    // a clock that went backwards doesn't make it valid forever
+    assert!(!challenge.confirms(0xdead_beef, Instant::from_secs(99)));
+}
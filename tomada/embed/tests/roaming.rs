@@ -0,0 +1,105 @@
+use embassy_time::{Duration, Instant};
+use goodwe_plug::roaming::{
+    Candidate, MAX_CHOICES, ROAM_AFTER, ROAM_COOLDOWN, ROAM_THRESHOLD, Roamer, Security, rank,
+};
+
+fn ap(ssid: &str, id: u8, rssi: i8, security: Security) -> Candidate<'_> {
+    Candidate {
+        ssid,
+        bssid: [0, 0, 0, 0, 0, id],
+        channel: 1,
+        rssi,
+        security,
+    }
+}
+
+#[test]
+fn ranks_known_networks_by_signal() {
+    let scan = [
+        ap("neighbor", 1, -40, Security::Wpa2),
+        ap("home", 2, -70, Security::Wpa2),
+        ap("home", 3, -55, Security::Wpa2),
+        ap("office", 4, -60, Security::Wpa2),
+    ];
+    let choices = rank(&scan, &["home", "office"]);
+    let order: Vec<_> = choices.iter().map(|c| c.bssid[5]).collect();
+    assert_eq!(order, [3, 4, 2]);
+    assert_eq!(choices[0].network, 0);
+    assert_eq!(choices[1].network, 1);
+}
+
+#[test]
+fn security_breaks_near_ties_only() {
+    let scan = [
+        ap("open", 1, -60, Security::Open),
+        ap("safe", 2, -62, Security::Wpa3),
+    ];
+    assert_eq!(rank(&scan, &["open", "safe"])[0].bssid[5], 2);
+
+    let scan = [
+        ap("open", 1, -50, Security::Open),
+        ap("safe", 2, -62, Security::Wpa3),
+    ];
+    assert_eq!(rank(&scan, &["open", "safe"])[0].bssid[5], 1);
+}
+
+#[test]
+fn equal_scores_keep_configured_order() {
+    let scan = [
+        ap("builtin", 1, -60, Security::Wpa2),
+        ap("stored", 2, -60, Security::Wpa2),
+    ];
+    let choices = rank(&scan, &["stored", "builtin"]);
+    assert_eq!(choices[0].network, 0);
+}
+
+#[test]
+fn drops_weak_duplicate_and_excess_aps() {
+    let mut scan = vec![
+        ap("home", 1, -95, Security::Wpa2),
+        ap("home", 2, -60, Security::Wpa2),
+        ap("home", 2, -61, Security::Wpa2),
+    ];
+    assert_eq!(rank(&scan, &["home"]).len(), 1);
+
+    scan.clear();
+    for id in 0..MAX_CHOICES as u8 + 4 {
+        scan.push(ap("home", id, -80 + id as i8, Security::Wpa2));
+    }
+    let choices = rank(&scan, &["home"]);
+    assert_eq!(choices.len(), MAX_CHOICES);
+    assert_eq!(choices[0].bssid[5], MAX_CHOICES as u8 + 3);
+    assert_eq!(choices[MAX_CHOICES - 1].bssid[5], 4);
+}
+
+#[test]
+fn rescans_after_staying_weak() {
+    let weak = ROAM_THRESHOLD - 5;
+    let mut roamer = Roamer::new();
+    let t0 = Instant::from_secs(100);
+    assert!(!roamer.poll(t0, weak));
+    assert!(!roamer.poll(t0 + ROAM_AFTER - Duration::from_secs(1), weak));
+    assert!(roamer.poll(t0 + ROAM_AFTER, weak));
+
+    // the signal recovering resets the wait, the cooldown holds either way
+    let t1 = t0 + ROAM_AFTER + Duration::from_secs(1);
+    assert!(!roamer.poll(t1, ROAM_THRESHOLD));
+    assert!(!roamer.poll(t1, weak));
+    assert!(!roamer.poll(t1 + ROAM_AFTER, weak));
+    assert!(roamer.poll(t0 + ROAM_AFTER + ROAM_COOLDOWN, weak));
+}
+
+#[test]
+fn roams_only_to_a_clearly_stronger_ap() {
+    let scan = [
+        ap("home", 1, -78, Security::Wpa2),
+        ap("home", 2, -72, Security::Wpa2),
+        ap("home", 3, -65, Security::Wpa2),
+    ];
+    let choices = rank(&scan, &["home"]);
+    let current = choices.iter().find(|c| c.bssid[5] == 1).unwrap();
+
+    assert!(Roamer::should_roam(current, -78, &choices[0]));
+    assert!(!Roamer::should_roam(current, -70, &choices[0]));
+    assert!(!Roamer::should_roam(&choices[0], -65, &choices[0]));
+}
@@ -0,0 +1,80 @@
+use common::{ChannelMask, Schedule, ScheduleEntry, heapless::Vec};
+use embassy_futures::join::join;
+use embassy_time::Timer;
+use goodwe_plug::{
+    RELAY_STATUS,
+    board::RELAY_ACTIVE,
+    clock::{self, TimeSource},
+    relay_task, schedule,
+};
+
+use crate::mock::{MockOutput, run_with, serial};
+
+mod mock;
+
+/// Monday 2024-01-01 10:30 UTC, 07:30 at UTC-3
+const MONDAY_0730: u64 = 1_704_105_000;
+const MONDAY: u8 = 1;
+
+fn schedule(entries: &[ScheduleEntry]) -> Schedule {
+    Schedule {
+        utc_offset: -180,
+        entries: Vec::from_slice(entries).unwrap(),
+    }
+}
+
+fn entry(days: u8, minute: u16, channel: u8) -> ScheduleEntry {
+    ScheduleEntry {
+        days,
+        minute,
+        channel,
+        on: true,
+    }
+}
+
+#[test]
+fn due_in_local_time() {
+    let s = schedule(&[entry(MONDAY, 7 * 60 + 30, 0)]);
+    assert_eq!(s.due(MONDAY_0730).count(), 1);
+    assert_eq!(s.due(MONDAY_0730 + 59).count(), 1);
+    assert_eq!(s.due(MONDAY_0730 + 60).count(), 0);
+    // same time on Tuesday
+    assert_eq!(s.due(MONDAY_0730 + 24 * 3600).count(), 0);
+    // same time on the next Monday
+    assert_eq!(s.due(MONDAY_0730 + 7 * 24 * 3600).count(), 1);
+}
+
+#[test]
+fn drops_entries_the_board_cant_run() {
+    let _guard = serial();
+    let kept = schedule::set(schedule(&[
+        entry(MONDAY, 60, 0),
+        entry(MONDAY, 60, 7),
+        entry(MONDAY, 24 * 60, 0),
+        entry(0, 60, 0),
+    ]));
+    assert_eq!(kept.entries, [entry(MONDAY, 60, 0)]);
+    assert_eq!(schedule::get(), kept);
+}
+
+#[test]
+fn switches_relay_on_time() {
+    let _guard = serial();
+    let pin = MockOutput::new(!RELAY_ACTIVE);
+    schedule::set(schedule(&[entry(MONDAY, 7 * 60 + 30, 0)]));
+    clock::sync((MONDAY_0730 - 1) * 1000, TimeSource::Broker);
+
+    run_with(
+        join(
+            relay_task(&mut [pin.clone()], ChannelMask(0), |_, _| {}),
+            schedule::run(),
+        ),
+        async {
+            Timer::after_millis(500).await;
+            assert_eq!(pin.state(), !RELAY_ACTIVE);
+            Timer::after_millis(1000).await;
+            assert_eq!(pin.state(), RELAY_ACTIVE);
+            assert_eq!(RELAY_STATUS.try_get(), Some(ChannelMask(1)));
+        },
+    );
+}
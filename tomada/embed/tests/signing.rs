@@ -0,0 +1,61 @@
+use goodwe_plug::signing::{SignatureError, verify};
+use p256::ecdsa::{Signature, SigningKey, signature::hazmat::PrehashSigner};
+use sha2::{Digest, Sha256};
+
+fn key() -> SigningKey {
+    SigningKey::from_slice(&[7; 32]).unwrap()
+}
+
+fn public_hex(key: &SigningKey) -> String {
+    let point = key.verifying_key().to_encoded_point(false);
+    point
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn sign(key: &SigningKey, sha256: &[u8; 32]) -> Vec<u8> {
+    let signature: Signature = key.sign_prehash(sha256).unwrap();
+    signature.to_der().as_bytes().to_vec()
+}
+
+#[test]
+fn signed_images_verify() {
+    let sha256: [u8; 32] = Sha256::digest(b"firmware").into();
+    let key = key();
+    assert_eq!(
+        verify(&public_hex(&key), &sha256, &sign(&key, &sha256)),
+        Ok(())
+    );
+}
+
+#[test]
+fn other_images_and_keys_are_refused() {
+    let sha256: [u8; 32] = Sha256::digest(b"firmware").into();
+    let key = key();
+    let signature = sign(&key, &sha256);
+
+    let other: [u8; 32] = Sha256::digest(b"malware").into();
+    assert_eq!(
+        verify(&public_hex(&key), &other, &signature),
+        Err(SignatureError::Invalid)
+    );
+    let stranger = SigningKey::from_slice(&[9; 32]).unwrap();
+    assert_eq!(
+        verify(&public_hex(&stranger), &sha256, &signature),
+        Err(SignatureError::Invalid)
+    );
+}
+
+#[test]
+fn garbage_is_malformed() {
+    let sha256 = [0; 32];
+    let key = public_hex(&key());
+    assert_eq!(
+        verify(&key, &sha256, &[0x30, 0x02, 0xff]),
+        Err(SignatureError::Malformed)
+    );
+    assert_eq!(verify(&key, &sha256, &[]), Err(SignatureError::Malformed));
+    assert_eq!(verify("zz", &sha256, &[]), Err(SignatureError::Key));
+}
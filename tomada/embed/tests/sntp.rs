@@ -0,0 +1,60 @@
+use goodwe_plug::sntp::{PACKET_LEN, SntpError, parse_reply, request};
+
+const NONCE: u64 = 0x0123_4567_89ab_cdef;
+/// 2024-01-01 00:00 UTC in NTP seconds
+const NTP_2024: u32 = 3_913_056_000;
+const UNIX_2024_MS: u64 = 1_704_067_200_000;
+
+fn reply(seconds: u32, fraction: u32) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    // version 4, server mode
+    packet[0] = (4 << 3) | 4;
+    packet[1] = 2;
+    packet[24..32].copy_from_slice(&NONCE.to_be_bytes());
+    packet[40..44].copy_from_slice(&seconds.to_be_bytes());
+    packet[44..48].copy_from_slice(&fraction.to_be_bytes());
+    packet
+}
+
+#[test]
+fn request_is_client_mode() {
+    let packet = request(NONCE);
+    assert_eq!(packet[0], 0b0010_0011);
+    assert_eq!(packet[40..48], NONCE.to_be_bytes());
+}
+
+#[test]
+fn reads_transmit_time() {
+    assert_eq!(parse_reply(&reply(NTP_2024, 0), NONCE), Ok(UNIX_2024_MS));
+    // half a second
+    assert_eq!(
+        parse_reply(&reply(NTP_2024, 1 << 31), NONCE),
+        Ok(UNIX_2024_MS + 500)
+    );
+}
+
+#[test]
+fn handles_era_rollover() {
+    // 2036-02-07 06:28:16 UTC, the NTP seconds wrap to 0
+    assert_eq!(parse_reply(&reply(0, 0), NONCE), Ok(2_085_978_496_000));
+}
+
+#[test]
+fn rejects_bad_replies() {
+    assert_eq!(
+        parse_reply(&reply(NTP_2024, 0)[..40], NONCE),
+        Err(SntpError::BadReply)
+    );
+    assert_eq!(
+        parse_reply(&reply(NTP_2024, 0), NONCE + 1),
+        Err(SntpError::BadReply)
+    );
+
+    let mut kiss = reply(NTP_2024, 0);
+    kiss[1] = 0;
+    assert_eq!(parse_reply(&kiss, NONCE), Err(SntpError::KissOfDeath));
+
+    let mut client = reply(NTP_2024, 0);
+    client[0] = (4 << 3) | 3;
+    assert_eq!(parse_reply(&client, NONCE), Err(SntpError::BadReply));
+}
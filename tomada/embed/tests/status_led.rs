@@ -0,0 +1,126 @@
+use common::ChannelMask;
+use embassy_futures::yield_now;
+use embassy_time::Timer;
+use goodwe_plug::{
+    RELAY_STATUS, StatusLed,
+    board::{ONBOARD_LED_ACTIVE as ON, PLUG_LED_ACTIVE},
+    config::{self, LedBehavior, PlugConfig},
+    status_led::{LED_STATUS, LedStatusCode},
+};
+
+use crate::mock::{MockOutput, run_with, serial};
+
+mod mock;
+
+#[test]
+fn working_mirrors_relay() {
+    let _guard = serial();
+    let onboard = MockOutput::new(!ON);
+    let plug = MockOutput::new(!ON);
+    let led = StatusLed::new(onboard.clone(), Some(plug.clone()));
+    let relay = RELAY_STATUS.sender();
+    relay.send(ChannelMask(0b10));
+    LED_STATUS.signal(LedStatusCode::Working);
+
+    run_with(led.run(), async {
+        yield_now().await;
+        assert_eq!(onboard.state(), ON);
+        assert_eq!(plug.state(), PLUG_LED_ACTIVE);
+
+        relay.send(ChannelMask(0));
+        yield_now().await;
+        assert_eq!(onboard.state(), !ON);
+        assert_eq!(plug.state(), !PLUG_LED_ACTIVE);
+    });
+}
+
+#[test]
+fn idle_blinks_slowly() {
+    let _guard = serial();
+    let onboard = MockOutput::new(!ON);
+    let plug = MockOutput::new(!ON);
+    let led = StatusLed::new(onboard.clone(), Some(plug.clone()));
+    LED_STATUS.signal(LedStatusCode::Idle);
+
+    run_with(led.run(), async {
+        Timer::after_millis(250).await;
+        assert_eq!(onboard.state(), ON);
+        assert_eq!(plug.state(), PLUG_LED_ACTIVE);
+        Timer::after_millis(500).await;
+        assert_eq!(onboard.state(), !ON);
+        assert_eq!(plug.state(), !PLUG_LED_ACTIVE);
+    });
+}
+
+#[test]
+fn works_without_plug_led() {
+    let _guard = serial();
+    let onboard = MockOutput::new(!ON);
+    let led = StatusLed::new(onboard.clone(), None);
+    LED_STATUS.signal(LedStatusCode::Idle);
+
+    run_with(led.run(), async {
+        Timer::after_millis(250).await;
+        assert_eq!(onboard.state(), ON);
+    });
+}
+
+#[test]
+fn identify_blinks_then_restores() {
+    let _guard = serial();
+    let onboard = MockOutput::new(!ON);
+    let led = StatusLed::new(onboard.clone(), None);
+    let relay = RELAY_STATUS.sender();
+    relay.send(ChannelMask(0));
+    LED_STATUS.signal(LedStatusCode::Working);
+
+    run_with(led.run(), async {
+        yield_now().await;
+        assert_eq!(onboard.state(), !ON);
+
+        LED_STATUS.signal(LedStatusCode::Identify { seconds: 1 });
+        Timer::after_millis(50).await;
+        assert_eq!(onboard.state(), ON);
+        Timer::after_millis(100).await;
+        assert_eq!(onboard.state(), !ON);
+
+        // back to mirroring the relay
+        Timer::after_millis(1500).await;
+        assert_eq!(onboard.state(), !ON);
+        relay.send(ChannelMask(1));
+        yield_now().await;
+        assert_eq!(onboard.state(), ON);
+    });
+}
+
+#[test]
+fn config_can_turn_the_led_off() {
+    let _guard = serial();
+    let onboard = MockOutput::new(!ON);
+    let led = StatusLed::new(onboard.clone(), None);
+    RELAY_STATUS.sender().send(ChannelMask(1));
+    LED_STATUS.signal(LedStatusCode::Working);
+
+    run_with(led.run(), async {
+        yield_now().await;
+        assert_eq!(onboard.state(), ON);
+
+        config::set(PlugConfig {
+            led: LedBehavior::Off,
+            ..Default::default()
+        });
+        yield_now().await;
+        assert_eq!(onboard.state(), !ON);
+
+        // identifying still works
+        LED_STATUS.signal(LedStatusCode::Identify { seconds: 1 });
+        Timer::after_millis(50).await;
+        assert_eq!(onboard.state(), ON);
+        Timer::after_millis(1500).await;
+        assert_eq!(onboard.state(), !ON);
+
+        config::set(PlugConfig::default());
+        yield_now().await;
+        assert_eq!(onboard.state(), ON);
+    });
+}